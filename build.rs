@@ -3,6 +3,10 @@ use std::io::Error;
 use clap::CommandFactory;
 use clap_complete::{generate_to, Shell};
 
+mod transitions {
+    include!("utils/src/transitions.rs");
+}
+
 include!("src/cli.rs");
 
 const COMPLETION_DIR: &str = "completions";
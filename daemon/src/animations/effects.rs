@@ -0,0 +1,633 @@
+//! The transition effects.
+//!
+//! An effect only decides what happens to each pixel at some point of the transition. Everything
+//! else (easing the progress with the bezier curve, pacing the frames, drawing and damaging the
+//! wallpapers, and finishing off whatever pixels are left) is up to the driver, `Transition`.
+//!
+//! To add an effect, implement `TransitionEffect` for it, add it to `EFFECTS`, and add its name and
+//! description to `utils::transitions::TRANSITIONS`, which is what `swww img` parses.
+
+use keyframe::num_traits::Pow;
+use utils::ipc::Transition;
+
+/// What happens to a pixel in some frame
+#[derive(Clone, Copy)]
+pub(super) enum Change {
+    /// It stays as it is
+    Keep,
+    /// Its bytes move this much closer to the new image's
+    Step(u8),
+    /// It blends with the new image: 0.0 keeps it as it is, 1.0 replaces it
+    Mix(f64),
+    /// It becomes the old image's pixel at (x, y), as it was when the transition started
+    OldAt(usize, usize),
+    /// It becomes the new image's pixel at (x, y)
+    NewAt(usize, usize),
+    /// It becomes the old image's pixel at (x, y) blended with the new image's there: 0.0 takes
+    /// the old one, 1.0 the new one
+    MixAt(usize, usize, f64),
+}
+
+/// Where the pixels of a row come from in some frame, for effects that move whole rows around.
+/// The rest of the row stays as it is
+#[derive(Clone, Copy, Default)]
+pub(super) struct RowSource {
+    /// Taken from the old image as it was when the transition started
+    pub(super) old: Option<Span>,
+    /// Taken from the new image. Copied last, so it wins where it overlaps `old`
+    pub(super) new: Option<Span>,
+}
+
+/// Some columns of a row, copied from a row of one of the images
+#[derive(Clone, Copy)]
+pub(super) struct Span {
+    /// The first column it covers, and the one past its last
+    pub(super) columns: (usize, usize),
+    /// The column and row of the image the first column is copied from
+    pub(super) from: (usize, usize),
+}
+
+pub(super) trait TransitionEffect: Send + Sync {
+    /// Whether the effect lasts `--transition-duration`. Untimed effects instead run until the
+    /// new image has fully replaced the old one
+    fn timed(&self) -> bool {
+        true
+    }
+
+    /// Whether the effect uses `Change::OldAt`, `Change::MixAt` or `RowSource::old`. We only keep a copy of the old image around for
+    /// those that do
+    fn samples_old_image(&self) -> bool {
+        false
+    }
+
+    /// What happens to the pixel at (`x`, `y`), counting from the top left corner, `progress`
+    /// into the transition. `progress` goes from 0 to 1, eased by the bezier curve
+    fn pixel(&self, x: usize, y: usize, progress: f32) -> Change;
+
+    /// Where row `y` comes from, `progress` into the transition, for effects that only move
+    /// pixels around. Returning `Some` means `pixel` is not called for that row
+    fn row(&self, _y: usize, _progress: f32) -> Option<RowSource> {
+        None
+    }
+}
+
+type Constructor = fn(&Transition, (u32, u32)) -> Box<dyn TransitionEffect>;
+
+/// Every effect, by the name `utils::transitions::TRANSITIONS` gives it
+const EFFECTS: &[(&str, Constructor)] = &[
+    ("simple", |t, _| Box::new(Simple { step: t.step })),
+    ("fade", |_, _| Box::new(Fade)),
+    ("wipe", |t, dim| Box::new(Wipe::new(t, dim))),
+    ("wave", |t, dim| Box::new(Wave::new(t, dim))),
+    ("grow", |t, dim| {
+        Box::new(Iris::new(t, dim, Shape::Circle, false))
+    }),
+    ("outer", |t, dim| {
+        Box::new(Iris::new(t, dim, Shape::Circle, true))
+    }),
+    ("horizontal-blinds", |t, dim| {
+        Box::new(Blinds::new(t, dim, false))
+    }),
+    ("vertical-blinds", |t, dim| {
+        Box::new(Blinds::new(t, dim, true))
+    }),
+    ("dissolve", |t, _| Box::new(Dissolve::new(t))),
+    ("pixelate-out", |_, dim| Box::new(Pixelate::new(dim, true))),
+    ("pixelate-in", |_, dim| Box::new(Pixelate::new(dim, false))),
+    ("iris-rectangle", |t, dim| {
+        Box::new(Iris::new(t, dim, Shape::Rectangle, false))
+    }),
+    ("iris-diamond", |t, dim| {
+        Box::new(Iris::new(t, dim, Shape::Diamond, false))
+    }),
+    ("iris-star", |t, dim| {
+        Box::new(Iris::new(t, dim, Shape::Star, false))
+    }),
+    ("slide", |t, dim| Box::new(Slide::new(t, dim, false))),
+    ("push", |t, dim| Box::new(Slide::new(t, dim, true))),
+];
+
+/// Fails if we do not implement the effect `transition` asks for, which happens when the client
+/// is newer than us
+pub(super) fn check(transition: &Transition) -> Result<(), String> {
+    constructor(transition).map(|_| ())
+}
+
+/// Builds the effect `transition` asks for, for a screen with `dimensions`
+pub(super) fn new(
+    transition: &Transition,
+    dimensions: (u32, u32),
+) -> Result<Box<dyn TransitionEffect>, String> {
+    constructor(transition).map(|constructor| constructor(transition, dimensions))
+}
+
+fn constructor(transition: &Transition) -> Result<Constructor, String> {
+    let name = transition.transition_type.name();
+    match EFFECTS.iter().find(|(effect, _)| *effect == name) {
+        Some((_, constructor)) => Ok(*constructor),
+        None => Err(format!(
+            "this swww-daemon does not implement the '{name}' transition. Make sure swww and \
+             swww-daemon are the same version"
+        )),
+    }
+}
+
+/// Moves every pixel `step` closer to the new image each frame
+pub(super) struct Simple {
+    pub(super) step: u8,
+}
+
+impl TransitionEffect for Simple {
+    fn timed(&self) -> bool {
+        false
+    }
+
+    fn pixel(&self, _: usize, _: usize, _: f32) -> Change {
+        Change::Step(self.step)
+    }
+}
+
+struct Fade;
+
+impl TransitionEffect for Fade {
+    fn pixel(&self, _: usize, _: usize, progress: f32) -> Change {
+        Change::Mix(progress as f64)
+    }
+}
+
+struct Wipe {
+    step: u8,
+    height: usize,
+    center: (f64, f64),
+    circle_radius: f64,
+    max_offset: f64,
+    /// The line's normal, scaled by `circle_radius`
+    normal: (f64, f64),
+}
+
+impl Wipe {
+    fn new(transition: &Transition, (width, height): (u32, u32)) -> Self {
+        let screen_diag = ((width.pow(2) + height.pow(2)) as f64).sqrt();
+        let circle_radius = screen_diag / 2.0;
+        let angle = transition.angle.to_radians();
+        Self {
+            step: transition.step,
+            height: height as usize,
+            center: ((width / 2) as f64, (height / 2) as f64),
+            circle_radius,
+            max_offset: circle_radius.pow(2) * 2.0,
+            normal: (circle_radius * angle.cos(), circle_radius * angle.sin()),
+        }
+    }
+}
+
+impl TransitionEffect for Wipe {
+    // line formula: (x-h)*a + (y-k)*b + C = r^2
+    // https://www.desmos.com/calculator/vpvzk12yar
+    //
+    // checks if a pixel is to the left or right of the line
+    fn pixel(&self, x: usize, y: usize, progress: f32) -> Change {
+        let offset = progress as f64 * self.max_offset;
+        let x = x as f64 - self.center.0;
+        let y = (self.height - y) as f64 - self.center.1;
+        if x * self.normal.0 + y * self.normal.1 + offset >= self.circle_radius.pow(2) {
+            Change::Step(self.step)
+        } else {
+            Change::Keep
+        }
+    }
+}
+
+struct Wave {
+    step: u8,
+    height: usize,
+    center: (f64, f64),
+    circle_radius: f64,
+    angle: f64,
+    scale: (f64, f64),
+    /// Where the line starts and ends its sweep
+    offsets: (f64, f64),
+}
+
+impl Wave {
+    fn new(transition: &Transition, (width, height): (u32, u32)) -> Self {
+        let screen_diag = ((width.pow(2) + height.pow(2)) as f64).sqrt();
+        let circle_radius = screen_diag / 2.0;
+        let mut wave = Self {
+            step: transition.step,
+            height: height as usize,
+            center: ((width / 2) as f64, (height / 2) as f64),
+            circle_radius,
+            angle: transition.angle.to_radians(),
+            scale: (transition.wave.0 as f64, transition.wave.1 as f64),
+            offsets: (0.0, 0.0),
+        };
+
+        // find the offset to start the transition at
+        let mut offset = 0.0;
+        for x in 0..width {
+            for y in 0..height {
+                if wave.is_low(x as f64, y as f64, offset) {
+                    offset += 1.0;
+                    break;
+                }
+            }
+        }
+        wave.offsets = (offset, 2.0 * circle_radius - offset);
+        wave
+    }
+
+    // graph: https://www.desmos.com/calculator/wunde042es
+    //
+    // checks if a pixel is to the left or right of the line
+    fn is_low(&self, x: f64, y: f64, offset: f64) -> bool {
+        let f = |x: f64| (x / self.scale.0).sin() * self.scale.1;
+        let x = x - self.center.0;
+        let y = y - self.center.1;
+
+        let lhs = y * self.angle.cos() - x * self.angle.sin();
+        let rhs = f(x * self.angle.cos() + y * self.angle.sin()) + self.circle_radius - offset;
+        lhs >= rhs
+    }
+}
+
+impl TransitionEffect for Wave {
+    fn pixel(&self, x: usize, y: usize, progress: f32) -> Change {
+        let (start, end) = self.offsets;
+        let offset = start + progress as f64 * (end - start);
+        if self.is_low(x as f64, (self.height - y) as f64, offset) {
+            Change::Step(self.step)
+        } else {
+            Change::Keep
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Shape {
+    Circle,
+    /// With the screen's aspect ratio
+    Rectangle,
+    /// With the screen's aspect ratio
+    Diamond,
+    /// Five pointed, with a point facing up
+    Star,
+}
+
+/// How far the star's inner corners are from its center, relative to its points
+const STAR_INNER: f32 = 0.45;
+
+impl Shape {
+    /// How far (`dx`, `dy`) is from the shape's center, such that its edge is where every point is
+    /// as far as the shape is big. `aspect` is the screen's width over its height
+    fn distance(self, dx: f32, dy: f32, aspect: f32) -> f32 {
+        match self {
+            Self::Circle => f32::sqrt(dx.pow(2) + dy.pow(2)),
+            Self::Rectangle => dx.abs().max(dy.abs() * aspect),
+            Self::Diamond => dx.abs() + dy.abs() * aspect,
+            Self::Star => {
+                // 0 at the points, 0.5 at the inner corners
+                let turn = (dx.atan2(dy) * 5.0 / std::f32::consts::TAU).rem_euclid(1.0);
+                let edge = STAR_INNER + (1.0 - STAR_INNER) * (2.0 * turn - 1.0).abs();
+                f32::sqrt(dx.pow(2) + dy.pow(2)) / edge
+            }
+        }
+    }
+}
+
+/// A shape around `--transition-pos`, growing out of it, or shrinking into it when `outer`
+struct Iris {
+    step: u8,
+    height: usize,
+    center: (f32, f32),
+    shape: Shape,
+    aspect: f32,
+    /// How big the shape must get to cover the whole screen
+    size: f32,
+    outer: bool,
+}
+
+impl Iris {
+    fn new(transition: &Transition, dimensions: (u32, u32), shape: Shape, outer: bool) -> Self {
+        let (width, height) = (dimensions.0 as f32, dimensions.1 as f32);
+        let center = transition.pos.to_pixel(dimensions, transition.invert_y);
+        let aspect = width / height;
+        let distance = |x: f32, y: f32| shape.distance(x - center.0, y - center.1, aspect);
+        // the pixels' extremes, in the coordinates `pixel` measures from. `--transition-pos` may be
+        // anywhere, even off the screen
+        let (left, right, bottom, top) = (0.0, width - 1.0, 1.0, height);
+        let mut size = [(left, bottom), (left, top), (right, bottom), (right, top)]
+            .into_iter()
+            .map(|(x, y)| distance(x, y))
+            .fold(0.0, f32::max);
+        if let Shape::Star = shape {
+            // the other shapes are convex, so the farthest pixel is a corner. The star is not, but
+            // the distance still grows along every ray out of the center, so the farthest pixel
+            // is on the screen's border
+            for x in 0..dimensions.0 {
+                size = size
+                    .max(distance(x as f32, bottom))
+                    .max(distance(x as f32, top));
+            }
+            for y in 1..=dimensions.1 {
+                size = size
+                    .max(distance(left, y as f32))
+                    .max(distance(right, y as f32));
+            }
+        }
+        Self {
+            step: transition.step,
+            height: dimensions.1 as usize,
+            center,
+            shape,
+            aspect,
+            size,
+            outer,
+        }
+    }
+}
+
+impl TransitionEffect for Iris {
+    fn pixel(&self, x: usize, y: usize, progress: f32) -> Change {
+        let dx = x as f32 - self.center.0;
+        let dy = (self.height - y) as f32 - self.center.1;
+        let distance = self.shape.distance(dx, dy, self.aspect);
+        // how far past the shape's edge the pixel is, in the direction it moves
+        let inside = if self.outer {
+            distance - (1.0 - progress) * self.size
+        } else {
+            progress * self.size - distance
+        };
+        if inside >= 0.0 {
+            Change::Step(self.step.saturating_add(inside.log2() as u8))
+        } else {
+            Change::Keep
+        }
+    }
+}
+
+/// `--transition-slats` slats, all opening at once, from top to bottom or from left to right
+struct Blinds {
+    step: u8,
+    /// How many pixels wide (or tall) each slat is
+    slat: f32,
+    vertical: bool,
+}
+
+impl Blinds {
+    fn new(transition: &Transition, (width, height): (u32, u32), vertical: bool) -> Self {
+        let len = if vertical { width } else { height };
+        Self {
+            step: transition.step,
+            slat: len as f32 / transition.slats.max(1) as f32,
+            vertical,
+        }
+    }
+}
+
+impl TransitionEffect for Blinds {
+    fn pixel(&self, x: usize, y: usize, progress: f32) -> Change {
+        let pos = if self.vertical { x } else { y } as f32;
+        if pos.rem_euclid(self.slat) < progress * self.slat {
+            Change::Step(self.step)
+        } else {
+            Change::Keep
+        }
+    }
+}
+
+/// Every pixel switches to the new image at its own random time, given by a noise mask
+struct Dissolve {
+    step: u8,
+    seed: u32,
+}
+
+impl Dissolve {
+    fn new(transition: &Transition) -> Self {
+        // the mask is random on purpose, so that no two dissolves look the same. The clock makes
+        // a good enough seed for that
+        let seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |time| time.subsec_nanos());
+        Self {
+            step: transition.step,
+            seed,
+        }
+    }
+
+    /// A number in [0, 1], the same for each pixel and seed
+    fn noise(&self, x: usize, y: usize) -> f32 {
+        let mut hash =
+            (x as u32).wrapping_mul(0x9E37_79B9) ^ (y as u32).wrapping_mul(0x85EB_CA6B) ^ self.seed;
+        hash ^= hash >> 16;
+        hash = hash.wrapping_mul(0x7FEB_352D);
+        hash ^= hash >> 15;
+        hash = hash.wrapping_mul(0x846C_A68B);
+        hash ^= hash >> 16;
+        hash as f32 / u32::MAX as f32
+    }
+}
+
+impl TransitionEffect for Dissolve {
+    fn pixel(&self, x: usize, y: usize, progress: f32) -> Change {
+        if self.noise(x, y) < progress {
+            Change::Step(self.step)
+        } else {
+            Change::Keep
+        }
+    }
+}
+
+/// The biggest blocks are this many times smaller than the screen's larger side
+const PIXELATE_BLOCKS: u32 = 16;
+
+/// The old image breaking into ever bigger blocks until halfway through, which then fade into the
+/// new one as they shrink back (`out`), or the new image coming in as big blocks that get smaller
+/// and smaller until it is sharp
+struct Pixelate {
+    dimensions: (usize, usize),
+    max_block: f32,
+    out: bool,
+}
+
+impl Pixelate {
+    fn new((width, height): (u32, u32), out: bool) -> Self {
+        Self {
+            dimensions: (width as usize, height as usize),
+            max_block: (width.max(height) / PIXELATE_BLOCKS).max(1) as f32,
+            out,
+        }
+    }
+}
+
+impl TransitionEffect for Pixelate {
+    fn samples_old_image(&self) -> bool {
+        self.out
+    }
+
+    fn pixel(&self, x: usize, y: usize, progress: f32) -> Change {
+        let coarseness = if !self.out {
+            1.0 - progress
+        } else if progress < 0.5 {
+            progress * 2.0
+        } else {
+            2.0 - progress * 2.0
+        };
+        let block = (1.0 + coarseness * (self.max_block - 1.0)) as usize;
+        // every pixel in a block takes the color of the block's center
+        let x = (x / block * block + block / 2).min(self.dimensions.0 - 1);
+        let y = (y / block * block + block / 2).min(self.dimensions.1 - 1);
+        if !self.out {
+            Change::NewAt(x, y)
+        } else if progress < 0.5 {
+            Change::OldAt(x, y)
+        } else {
+            Change::MixAt(x, y, (progress as f64 - 0.5) * 2.0)
+        }
+    }
+}
+
+/// The new image sliding in along `--transition-angle`, over the old one, or pushing it out of the
+/// screen when `push`
+struct Slide {
+    dimensions: (usize, usize),
+    /// How far the images move over the whole transition, in pixels
+    travel: (f32, f32),
+    push: bool,
+}
+
+impl Slide {
+    fn new(transition: &Transition, (width, height): (u32, u32), push: bool) -> Self {
+        let angle = transition.angle.to_radians() as f32;
+        // like in 'wipe', 0 degrees moves right to left and 90 top to bottom
+        let (dir_x, dir_y) = (-angle.cos(), angle.sin());
+        // just enough for the new image to start fully out of the screen
+        let distance = (width as f32 / dir_x.abs()).min(height as f32 / dir_y.abs());
+        Self {
+            dimensions: (width as usize, height as usize),
+            travel: (dir_x * distance, dir_y * distance),
+            push,
+        }
+    }
+
+    /// The part of row `y` that shows an image moved by `by` times `travel`, if any
+    fn moved(&self, y: usize, by: f32) -> Option<Span> {
+        let (width, height) = (self.dimensions.0 as isize, self.dimensions.1 as isize);
+        let from_y = y as isize - (by * self.travel.1).round() as isize;
+        let shift = (by * self.travel.0).round() as isize;
+        let start = shift.clamp(0, width);
+        let end = (width + shift).clamp(0, width);
+        if !(0..height).contains(&from_y) || start >= end {
+            return None;
+        }
+        Some(Span {
+            columns: (start as usize, end as usize),
+            from: ((start - shift) as usize, from_y as usize),
+        })
+    }
+}
+
+impl TransitionEffect for Slide {
+    fn samples_old_image(&self) -> bool {
+        self.push
+    }
+
+    /// Never called, since `row` always answers
+    fn pixel(&self, _: usize, _: usize, _: f32) -> Change {
+        Change::Keep
+    }
+
+    fn row(&self, y: usize, progress: f32) -> Option<RowSource> {
+        // beziers may overshoot, but moving past the end would uncover columns neither image
+        // covers
+        let progress = progress.clamp(0.0, 1.0);
+        // the new image is still `1 - progress` of the way from where it ends, and the old one
+        // has moved `progress` of the way
+        Some(RowSource {
+            old: if self.push {
+                self.moved(y, progress)
+            } else {
+                None
+            },
+            new: self.moved(y, progress - 1.0),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::ipc::{Coord, Position};
+
+    #[test]
+    fn every_transition_should_have_an_effect() {
+        for t in utils::transitions::TRANSITIONS {
+            assert!(
+                EFFECTS.iter().any(|(name, _)| *name == t.name),
+                "'{}' has no effect",
+                t.name
+            );
+        }
+        for (name, _) in EFFECTS {
+            assert!(
+                utils::transitions::find(name).is_some(),
+                "'{name}' is not in utils::transitions::TRANSITIONS"
+            );
+        }
+    }
+
+    #[test]
+    fn iris_should_cover_the_screen_from_anywhere() {
+        let dimensions = (64, 48);
+        let shapes = [Shape::Circle, Shape::Rectangle, Shape::Diamond, Shape::Star];
+        for pos in [
+            (0.0, 0.0),
+            (32.0, 24.0),
+            (-100.0, 20.0),
+            (200.0, -50.0),
+            (500.0, 700.0),
+        ] {
+            let mut transition = Transition::default();
+            transition.pos = Position::new(Coord::Pixel(pos.0), Coord::Pixel(pos.1));
+            for (shape, outer) in shapes.into_iter().flat_map(|s| [(s, false), (s, true)]) {
+                let iris = Iris::new(&transition, dimensions, shape, outer);
+                let mut covered = true;
+                let mut farthest: f32 = 0.0;
+                for y in 0..dimensions.1 as usize {
+                    for x in 0..dimensions.0 as usize {
+                        covered &= !matches!(iris.pixel(x, y, 1.0), Change::Keep);
+                        let dx = x as f32 - iris.center.0;
+                        let dy = (iris.height - y) as f32 - iris.center.1;
+                        farthest = farthest.max(shape.distance(dx, dy, iris.aspect));
+                    }
+                }
+                assert!(covered, "iris doesn't cover the screen from {pos:?}");
+                assert!(
+                    (iris.size - farthest).abs() <= farthest * 1e-4,
+                    "iris from {pos:?} grows to {} instead of {farthest}",
+                    iris.size
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn slide_should_not_overshoot() {
+        let transition = Transition::default();
+        for push in [false, true] {
+            let slide = Slide::new(&transition, (64, 48), push);
+            for progress in [1.0, 1.3] {
+                let row = slide.row(10, progress).unwrap();
+                let new = row.new.unwrap();
+                assert_eq!((new.columns, new.from), ((0, 64), (0, 10)));
+                assert!(row.old.is_none());
+            }
+            let row = slide.row(10, -0.3).unwrap();
+            assert!(row.new.is_none());
+            if push {
+                let old = row.old.unwrap();
+                assert_eq!((old.columns, old.from), ((0, 64), (0, 10)));
+            }
+        }
+    }
+}
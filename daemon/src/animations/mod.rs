@@ -8,20 +8,29 @@ use std::{
 
 use utils::{
     compression::Decompressor,
-    ipc::{self, Animation, Answer, BgImg, Img},
+    ipc::{self, Animation, Answer, BgImg, Event, Img},
 };
 
-use crate::wallpaper::{AnimationToken, Wallpaper};
+use crate::{
+    events,
+    wallpaper::{AnimationToken, Damage, Wallpaper},
+};
 
 mod anim_barrier;
+mod effects;
 mod transitions;
-use transitions::Transition;
+use transitions::{FrameClock, Transition};
 
 use self::anim_barrier::ArcAnimBarrier;
 
 ///The default thread stack size of 2MiB is way too overkill for our purposes
 const STACK_SIZE: usize = 1 << 17; //128KiB
 
+/// Fails if this daemon has no effect for `transition`'s type
+pub(super) fn check_transition(transition: &ipc::Transition) -> Result<(), String> {
+    effects::check(transition)
+}
+
 pub(super) struct Animator {
     anim_barrier: ArcAnimBarrier,
 }
@@ -58,7 +67,10 @@ impl Animator {
                     * crate::pixel_format().channels() as usize;
 
                 if img.len() == expected_len {
-                    Transition::new(wallpapers, dimensions, transition).execute(img);
+                    match Transition::new(wallpapers, dimensions, transition) {
+                        Ok(transition) => transition.execute(img),
+                        Err(e) => error!("{e}"),
+                    }
                 } else {
                     error!(
                         "image is of wrong size! Image len: {}, expected len: {expected_len}",
@@ -82,8 +94,14 @@ impl Animator {
             .name("transition spawner".to_string())
             .spawn(move || {
                 thread::scope(|s| {
-                    for ((Img { img, path }, _), wallpapers) in imgs.iter().zip(wallpapers) {
-                        Self::spawn_transition_thread(s, &transition, img, path, wallpapers);
+                    for ((Img { img, path, .. }, _), wallpapers) in imgs.iter().zip(wallpapers) {
+                        Self::spawn_transition_thread(
+                            s,
+                            &transition,
+                            img.slice(),
+                            path,
+                            wallpapers,
+                        );
                     }
                 });
             }) {
@@ -125,44 +143,110 @@ impl Animator {
                     }
                 }
 
-                let mut now = std::time::Instant::now();
+                for (wallpaper, token) in wallpapers.iter().zip(&tokens) {
+                    wallpaper.set_animating(token, true);
+                }
+
+                // frames are picked by the compositor's frame callbacks, like transitions, so that
+                // we keep in step with the screen's refresh instead of drifting from it
+                let mut clock = FrameClock::new();
+                let frames = &animation.animation;
+                let total: Duration = frames.iter().map(|(_, duration)| *duration).sum();
+                // the frame on screen (the transition drew the first one), and when it ends
+                let mut shown = 0;
+                let mut shown_until = frames[0].1;
 
                 let mut decompressor = Decompressor::new();
-                for (frame, duration) in animation.animation.iter().cycle() {
-                    barrier.wait(duration.div_f32(2.0));
+                loop {
+                    // there is no point in waking up for a callback before the next frame is due
+                    std::thread::sleep(shown_until.saturating_sub(clock.estimate()));
+                    barrier.wait(frames[shown].1.div_f32(2.0));
 
                     let mut i = 0;
                     while i < wallpapers.len() {
-                        let token = &tokens[i];
-                        if !wallpapers[i].has_animation_id(token) {
-                            wallpapers.swap_remove(i);
+                        if !wallpapers[i].has_animation_id(&tokens[i]) {
+                            let wallpaper = wallpapers.swap_remove(i);
                             tokens.swap_remove(i);
+                            events::publish(Event::AnimationStopped(
+                                wallpaper.output_name().to_string(),
+                            ));
                             continue;
                         }
+                        i += 1;
+                    }
+                    if wallpapers.is_empty() {
+                        return;
+                    }
+
+                    let mut callback = None;
+                    for wallpaper in wallpapers.iter() {
+                        callback = callback.or(wallpaper.wait_frame());
+                    }
+                    let elapsed = clock.elapsed(callback);
+
+                    // whole loops we missed (e.g. while suspended) would bring us back here anyway
+                    if !total.is_zero() && elapsed > shown_until + total {
+                        let loops =
+                            ((elapsed - shown_until).as_secs_f64() / total.as_secs_f64()).floor();
+                        shown_until += total.mul_f64(loops);
+                    }
+                    // we always show at least one new frame, since we only get here when the next
+                    // one is about due. If we fell behind, we also go through the ones we missed,
+                    // since every frame is compressed against the previous one
+                    let mut due = Vec::new();
+                    loop {
+                        shown = (shown + 1) % frames.len();
+                        shown_until += frames[shown].1;
+                        due.push(shown);
+                        if shown_until > elapsed {
+                            break;
+                        }
+                    }
 
-                        let result = wallpapers[i].canvas_change(|canvas| {
-                            decompressor.decompress(frame, canvas, crate::pixel_format())
-                        });
+                    let mut i = 0;
+                    while i < wallpapers.len() {
+                        let mut damage = Vec::new();
+                        let mut result = Ok(());
+                        for frame in &due {
+                            let changed = wallpapers[i].canvas_change(|canvas| {
+                                decompressor.decompress(
+                                    &frames[*frame].0,
+                                    canvas,
+                                    crate::pixel_format(),
+                                )
+                            });
+                            match changed {
+                                Ok(changed) => {
+                                    let width = wallpapers[i].get_dimensions().0;
+                                    damage.extend(
+                                        changed.map(|pixels| Damage::from_pixels(pixels, width)),
+                                    );
+                                }
+                                Err(e) => {
+                                    result = Err(e);
+                                    break;
+                                }
+                            }
+                        }
 
                         if let Err(e) = result {
                             error!("failed to unpack frame: {e}");
-                            wallpapers.swap_remove(i);
-                            tokens.swap_remove(i);
+                            let wallpaper = wallpapers.swap_remove(i);
+                            let token = tokens.swap_remove(i);
+                            wallpaper.set_animating(&token, false);
+                            events::publish(Event::AnimationStopped(
+                                wallpaper.output_name().to_string(),
+                            ));
                             continue;
                         }
-
-                        wallpapers[i].draw();
+                        wallpapers[i].draw_damage(&damage);
                         i += 1;
                     }
 
                     if wallpapers.is_empty() {
                         return;
                     }
-
-                    let timeout = duration.saturating_sub(now.elapsed());
-                    spin_sleep::sleep(timeout);
                     crate::wake_poll();
-                    now = std::time::Instant::now();
                 }
             })
         {
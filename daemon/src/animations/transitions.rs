@@ -6,27 +6,72 @@ use std::{
 use rayon::prelude::*;
 
 use log::debug;
-use utils::ipc::{Position, TransitionType};
 
-use crate::wallpaper::{AnimationToken, Wallpaper};
+use crate::wallpaper::{AnimationToken, Damage, Wallpaper};
 
-use keyframe::{
-    functions::BezierCurve, keyframes, mint::Vector2, num_traits::Pow, AnimationSequence,
-};
+use keyframe::{functions::BezierCurve, keyframes, mint::Vector2, AnimationSequence};
+
+use super::effects::{self, Change, RowSource, Simple, Span, TransitionEffect};
+
+/// Measures how long a transition or animation has been running by the compositor's frame
+/// callbacks, when it sends them, since they tell us when our frames actually reach the screen
+pub(super) struct FrameClock {
+    start: Instant,
+    /// The first frame callback's time, and how long into the transition it came
+    first_callback: Option<(u32, Duration)>,
+    last_frame: Instant,
+    /// What `elapsed` returned last
+    last_elapsed: Duration,
+}
+
+impl FrameClock {
+    pub(super) fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            start: now,
+            first_callback: None,
+            last_frame: now,
+            last_elapsed: Duration::ZERO,
+        }
+    }
+
+    /// How long the transition has been running, given the time of the frame callback we just
+    /// received, if any
+    pub(super) fn elapsed(&mut self, callback: Option<u32>) -> Duration {
+        self.last_frame = Instant::now();
+        self.last_elapsed = match (callback, self.first_callback) {
+            (None, _) => self.start.elapsed(),
+            (Some(time), Some((first, at))) => {
+                at + Duration::from_millis(time.wrapping_sub(first).into())
+            }
+            (Some(time), None) => {
+                let at = self.start.elapsed();
+                self.first_callback = Some((time, at));
+                at
+            }
+        };
+        self.last_elapsed
+    }
+
+    /// Our best guess of what `elapsed` would return right now, without waiting for a callback
+    pub(super) fn estimate(&self) -> Duration {
+        self.last_elapsed + self.last_frame.elapsed()
+    }
+}
 
 pub(super) struct Transition {
     animation_tokens: Vec<AnimationToken>,
     wallpapers: Vec<Arc<Wallpaper>>,
     dimensions: (u32, u32),
-    transition_type: TransitionType,
+    effect: Box<dyn TransitionEffect>,
+    /// What each wallpaper showed when the transition started, for effects that sample it
+    old_imgs: Vec<Box<[u8]>>,
     duration: f32,
     step: u8,
-    fps: Duration,
-    angle: f64,
-    pos: Position,
+    /// We draw a frame whenever the compositor asks for one, but at most once every `fps_cap`
+    fps_cap: Option<Duration>,
+    clock: FrameClock,
     bezier: BezierCurve,
-    wave: (f32, f32),
-    invert_y: bool,
 }
 
 /// All transitions return whether or not they completed
@@ -35,20 +80,23 @@ impl Transition {
         wallpapers: Vec<Arc<Wallpaper>>,
         dimensions: (u32, u32),
         transition: &utils::ipc::Transition,
-    ) -> Self {
-        Transition {
+    ) -> Result<Self, String> {
+        Ok(Transition {
             animation_tokens: wallpapers
                 .iter()
                 .map(|w| w.create_animation_token())
                 .collect(),
             wallpapers,
             dimensions,
-            transition_type: transition.transition_type,
+            effect: effects::new(transition, dimensions)?,
+            old_imgs: Vec::new(),
             duration: transition.duration,
             step: transition.step,
-            fps: Duration::from_nanos(1_000_000_000 / transition.fps as u64),
-            angle: transition.angle,
-            pos: transition.pos.clone(),
+            fps_cap: match transition.fps {
+                0 => None,
+                fps => Some(Duration::from_nanos(1_000_000_000 / fps as u64)),
+            },
+            clock: FrameClock::new(),
             bezier: BezierCurve::from(
                 Vector2 {
                     x: transition.bezier.0,
@@ -59,309 +107,212 @@ impl Transition {
                     y: transition.bezier.3,
                 },
             ),
-            wave: transition.wave,
-            invert_y: transition.invert_y,
-        }
+        })
     }
 
     pub(super) fn execute(mut self, new_img: &[u8]) {
         debug!("Starting transitions");
-        match self.transition_type {
-            TransitionType::Simple => self.simple(new_img),
-            TransitionType::Wipe => self.wipe(new_img),
-            TransitionType::Grow => self.grow(new_img),
-            TransitionType::Outer => self.outer(new_img),
-            TransitionType::Wave => self.wave(new_img),
-            TransitionType::Fade => self.fade(new_img),
-        };
+        self.clock = FrameClock::new();
+        for (wallpaper, token) in self.wallpapers.iter().zip(&self.animation_tokens) {
+            wallpaper.transition_started(token);
+        }
+        let effect = std::mem::replace(&mut self.effect, Box::new(Simple { step: 0 }));
+        if effect.samples_old_image() {
+            self.old_imgs = self
+                .wallpapers
+                .iter()
+                .map(|wallpaper| wallpaper.canvas_change(|canvas| Box::from(&canvas[..])))
+                .collect();
+        }
+        if effect.timed() {
+            self.timed(new_img, effect.as_ref());
+            // finish off whatever the effect left halfway
+            let step = 4 + self.step / 4;
+            self.untimed(new_img, &Simple { step });
+        } else {
+            self.untimed(new_img, effect.as_ref());
+        }
         debug!("Transitions finished");
         for (wallpaper, token) in self.wallpapers.iter().zip(self.animation_tokens) {
             token.set_transition_done(wallpaper);
         }
     }
 
-    fn send_frame(&mut self, now: &mut Instant) {
-        let fps = self.fps;
+    /// Waits until every wallpaper can take a new frame (and for the fps cap, if any), and returns
+    /// how long the transition has been running
+    fn next_frame(&mut self) -> Duration {
         let mut i = 0;
         while i < self.wallpapers.len() {
             let token = &self.animation_tokens[i];
             if !self.wallpapers[i].has_animation_id(token) {
                 self.wallpapers.swap_remove(i);
                 self.animation_tokens.swap_remove(i);
+                if i < self.old_imgs.len() {
+                    self.old_imgs.swap_remove(i);
+                }
                 continue;
             }
             i += 1;
         }
-        let timeout = fps.saturating_sub(now.elapsed());
-        spin_sleep::sleep(timeout);
-        crate::wake_poll();
-        *now = Instant::now();
-    }
-
-    fn bezier_seq(&self, start: f32, end: f32) -> (AnimationSequence<f32>, Instant) {
-        (
-            keyframes![(start, 0.0, self.bezier), (end, self.duration, self.bezier)],
-            Instant::now(),
-        )
+        if let Some(cap) = self.fps_cap {
+            spin_sleep::sleep(cap.saturating_sub(self.clock.last_frame.elapsed()));
+        }
+        let mut callback = None;
+        for wallpaper in self.wallpapers.iter() {
+            callback = callback.or(wallpaper.wait_frame());
+        }
+        self.clock.elapsed(callback)
     }
 
-    fn simple(&mut self, new_img: &[u8]) {
-        let step = self.step;
-        let mut now = Instant::now();
-        let done = std::sync::atomic::AtomicBool::new(false);
-        while !done.load(std::sync::atomic::Ordering::Relaxed) {
-            done.store(true, std::sync::atomic::Ordering::Relaxed);
-            self.draw_all(new_img, |_, old, new| {
-                if old.abs_diff(*new) < step {
-                    *old = *new;
-                } else if *old > *new {
-                    *old -= step;
-                    done.store(false, std::sync::atomic::Ordering::Relaxed);
-                } else {
-                    *old += step;
-                    done.store(false, std::sync::atomic::Ordering::Relaxed);
-                }
-            });
-            self.send_frame(&mut now);
+    /// Waits for the next frame, and returns the value `seq` has by then, or `None` once it is over
+    fn advance(&mut self, seq: &mut AnimationSequence<f32>) -> Option<f32> {
+        let elapsed = self.next_frame().as_secs_f64();
+        if elapsed >= seq.duration() || self.wallpapers.is_empty() {
+            return None;
         }
+        seq.advance_to(elapsed);
+        Some(seq.now())
     }
 
-    fn fade(&mut self, new_img: &[u8]) {
-        let mut step = 0.0;
-        let (mut seq, start) = self.bezier_seq(0.0, 1.0);
-
-        let mut now = Instant::now();
-        while start.elapsed().as_secs_f64() < seq.duration() {
-            self.draw_all(new_img, |_, old_col, new_col| {
-                *old_col = (*old_col as f64 * (1.0 - step) + *new_col as f64 * step) as u8;
-            });
-            self.send_frame(&mut now);
-            step = seq.now() as f64;
-            seq.advance_to(start.elapsed().as_secs_f64());
+    /// Runs `effect` for `--transition-duration`, easing its progress with the bezier curve
+    fn timed(&mut self, new_img: &[u8], effect: &dyn TransitionEffect) {
+        let mut seq: AnimationSequence<f32> =
+            keyframes![(0.0, 0.0, self.bezier), (1.0, self.duration, self.bezier)];
+        while let Some(progress) = self.advance(&mut seq) {
+            self.draw_all(new_img, effect, progress);
         }
-        self.step = 4 + self.step / 4;
-        self.simple(new_img)
     }
 
-    fn wave(&mut self, new_img: &[u8]) {
-        let width = self.dimensions.0;
-        let height = self.dimensions.1;
-        let mut now = Instant::now();
-        let center = (width / 2, height / 2);
-        let screen_diag = ((width.pow(2) + height.pow(2)) as f64).sqrt();
-
-        let angle = self.angle.to_radians();
-        let (scale_x, scale_y) = (self.wave.0 as f64, self.wave.1 as f64);
-
-        let circle_radius = screen_diag / 2.0;
-
-        let f = |x: f64| (x / scale_x).sin() * scale_y;
-
-        // graph: https://www.desmos.com/calculator/wunde042es
-        //
-        // checks if a pixel is to the left or right of the line
-        let is_low = |x: f64, y: f64, offset: f64| {
-            let x = x - center.0 as f64;
-            let y = y - center.1 as f64;
-
-            let lhs = y * angle.cos() - x * angle.sin();
-            let rhs = f(x * angle.cos() + y * angle.sin()) + circle_radius - offset;
-            lhs >= rhs
-        };
-
-        // find the offset to start the transition at
-        let mut offset = {
-            let mut offset = 0.0;
-            for x in 0..width {
-                for y in 0..height {
-                    if is_low(x as f64, y as f64, offset) {
-                        offset += 1.0;
-                        break;
-                    }
-                }
+    /// Runs `effect` until the new image has fully replaced the old one
+    fn untimed(&mut self, new_img: &[u8], effect: &dyn TransitionEffect) {
+        loop {
+            self.next_frame();
+            if self.wallpapers.is_empty() || !self.draw_all(new_img, effect, 1.0) {
+                return;
             }
-            offset
-        };
-        let max_offset = 2.0 * circle_radius - offset;
-        let (width, height) = (width as usize, height as usize);
-
-        let (mut seq, start) = self.bezier_seq(offset as f32, max_offset as f32);
-
-        let step = self.step;
-
-        while start.elapsed().as_secs_f64() < seq.duration() {
-            self.draw_all(new_img, |i, old, new| {
-                let pix_x = i % width;
-                let pix_y = height - i / width;
-                if is_low(pix_x as f64, pix_y as f64, offset) {
-                    change_byte(step, old, new);
-                }
-            });
-            self.send_frame(&mut now);
-
-            offset = seq.now() as f64;
-            seq.advance_to(start.elapsed().as_secs_f64());
         }
-        self.step = 4 + self.step / 4;
-        self.simple(new_img)
     }
 
-    fn wipe(&mut self, new_img: &[u8]) {
-        let width = self.dimensions.0;
-        let height = self.dimensions.1;
-        let mut now = Instant::now();
-        let center = (width / 2, height / 2);
-        let screen_diag = ((width.pow(2) + height.pow(2)) as f64).sqrt();
-
-        let circle_radius = screen_diag / 2.0;
-        let max_offset = circle_radius.pow(2) * 2.0;
-
-        let angle = self.angle.to_radians();
-
-        let mut offset = {
-            let (x, y) = angle.sin_cos();
-            (x.abs() * width as f64 / 2.0 + y.abs() * height as f64 / 2.0).abs()
-        };
-
-        // line formula: (x-h)*a + (y-k)*b + C = r^2
-        // https://www.desmos.com/calculator/vpvzk12yar
-        //
-        // checks if a pixel is to the left or right of the line
-        let is_low = |pix_x: f64, pix_y: f64, offset: f64, radius: f64| {
-            let a = radius * angle.cos();
-            let b = radius * angle.sin();
-            let x = pix_x - center.0 as f64;
-            let y = pix_y - center.1 as f64;
-            let res = x * a + y * b + offset;
-            res >= radius.pow(2)
-        };
-
-        let (width, height) = (width as usize, height as usize);
-        let (mut seq, start) = self.bezier_seq(0.0, max_offset as f32);
-
-        let step = self.step;
-
-        while start.elapsed().as_secs_f64() < seq.duration() {
-            self.draw_all(new_img, |i, old, new| {
-                let pix_x = i % width;
-                let pix_y = height - i / width;
-                if is_low(pix_x as f64, pix_y as f64, offset, circle_radius) {
-                    change_byte(step, old, new);
-                }
+    /// Applies `effect` to every pixel in the old img, and draws only the rows it changed.
+    /// Returns whether it changed anything
+    fn draw_all(&self, new_img: &[u8], effect: &dyn TransitionEffect, progress: f32) -> bool {
+        let channels = crate::pixel_format().channels() as usize;
+        let width = self.dimensions.0 as usize;
+        let stride = width * channels;
+        let mut changed = false;
+        for (i, wallpaper) in self.wallpapers.iter().enumerate() {
+            let imgs = Imgs {
+                old: self.old_imgs.get(i).map(|img| &img[..]),
+                new: new_img,
+                width,
+                channels,
+            };
+            let rows: Vec<Option<(u32, u32)>> = wallpaper.canvas_change(|canvas| {
+                canvas
+                    .par_chunks_mut(stride)
+                    .zip(new_img.par_chunks(stride))
+                    .enumerate()
+                    .map(|(y, (old_row, new_row))| {
+                        if let Some(source) = effect.row(y, progress) {
+                            return copy_row(source, old_row, &imgs);
+                        }
+                        let mut changed: Option<(u32, u32)> = None;
+                        let pixels = old_row
+                            .chunks_exact_mut(channels)
+                            .zip(new_row.chunks_exact(channels));
+                        for (x, (old, new)) in pixels.enumerate() {
+                            if change_pixel(effect.pixel(x, y, progress), old, new, &imgs) {
+                                let first = changed.map_or(x as u32, |(first, _)| first);
+                                changed = Some((first, x as u32));
+                            }
+                        }
+                        changed
+                    })
+                    .collect()
             });
-            self.send_frame(&mut now);
-
-            offset = seq.now() as f64;
-            seq.advance_to(start.elapsed().as_secs_f64());
+            changed |= rows.iter().any(Option::is_some);
+            wallpaper.draw_damage(&Damage::from_rows(&rows));
         }
-        self.step = 4 + self.step / 4;
-        self.simple(new_img)
+        // so that the main thread sends our commits right away
+        crate::wake_poll();
+        changed
     }
+}
 
-    fn grow(&mut self, new_img: &[u8]) {
-        let (width, height) = (self.dimensions.0 as f32, self.dimensions.1 as f32);
-        let (center_x, center_y) = self.pos.to_pixel(self.dimensions, self.invert_y);
-        let mut dist_center: f32 = 0.0;
-        let dist_end: f32 = {
-            let mut x = center_x;
-            let mut y = center_y;
-            if x < width / 2.0 {
-                x = width - 1.0 - x;
-            }
-            if y < height / 2.0 {
-                y = height - 1.0 - y;
-            }
-            f32::sqrt(x.pow(2) + y.pow(2))
-        };
-
-        let (width, height) = (width as usize, height as usize);
-        let (center_x, center_y) = (center_x as usize, center_y as usize);
-
-        let step = self.step;
-        let (mut seq, start) = self.bezier_seq(0.0, dist_end);
-        let mut now = Instant::now();
-        while start.elapsed().as_secs_f64() < seq.duration() {
-            self.draw_all(new_img, |i, old, new| {
-                let pix_x = i % width;
-                let pix_y = height - i / width;
-                let diff_x = pix_x.abs_diff(center_x);
-                let diff_y = pix_y.abs_diff(center_y);
-                let pix_center_dist = f32::sqrt((diff_x.pow(2) + diff_y.pow(2)) as f32);
-                if pix_center_dist <= dist_center {
-                    let step = step.saturating_add((dist_center - pix_center_dist).log2() as u8);
-                    change_byte(step, old, new);
-                }
-            });
-            self.send_frame(&mut now);
+/// The images a `Change` may copy pixels from
+struct Imgs<'a> {
+    /// Only there when the effect samples the old image
+    old: Option<&'a [u8]>,
+    new: &'a [u8],
+    width: usize,
+    channels: usize,
+}
 
-            dist_center = seq.now();
-            seq.advance_to(start.elapsed().as_secs_f64());
-        }
-        self.step = 4 + self.step / 4;
-        self.simple(new_img)
+impl<'a> Imgs<'a> {
+    #[inline(always)]
+    fn pixel(&self, img: &'a [u8], x: usize, y: usize) -> &'a [u8] {
+        let start = (y * self.width + x) * self.channels;
+        &img[start..start + self.channels]
     }
+}
 
-    fn outer(&mut self, new_img: &[u8]) {
-        let (width, height) = (self.dimensions.0 as f32, self.dimensions.1 as f32);
-        let (center_x, center_y) = self.pos.to_pixel(self.dimensions, self.invert_y);
-        let mut dist_center = {
-            let mut x = center_x;
-            let mut y = center_y;
-            if x < width / 2.0 {
-                x = width - 1.0 - x;
-            }
-            if y < height / 2.0 {
-                y = height - 1.0 - y;
-            }
-            f32::sqrt(x.pow(2) + y.pow(2))
+/// Copies the spans in `source` into `row`, returning the columns it changed, if any
+fn copy_row(source: RowSource, row: &mut [u8], imgs: &Imgs) -> Option<(u32, u32)> {
+    let mut changed: Option<(u32, u32)> = None;
+    let spans = [(source.old, imgs.old), (source.new, Some(imgs.new))];
+    for (span, img) in spans {
+        let (Some(Span { columns, from }), Some(img)) = (span, img) else {
+            continue;
         };
-        let (width, height) = (width as usize, height as usize);
-        let (center_x, center_y) = (center_x as usize, center_y as usize);
-
-        let step = self.step;
-        let (mut seq, start) = self.bezier_seq(dist_center, 0.0);
-        let mut now = Instant::now();
-        while start.elapsed().as_secs_f64() < seq.duration() {
-            self.draw_all(new_img, |i, old, new| {
-                let pix_x = i % width;
-                let pix_y = height - i / width;
-                let diff_x = pix_x.abs_diff(center_x);
-                let diff_y = pix_y.abs_diff(center_y);
-                let pix_center_dist = f32::sqrt((diff_x.pow(2) + diff_y.pow(2)) as f32);
-                if pix_center_dist >= dist_center {
-                    let step = step.saturating_add((pix_center_dist - dist_center).log2() as u8);
-                    change_byte(step, old, new);
-                }
-            });
-            self.send_frame(&mut now);
-
-            dist_center = seq.now();
-            seq.advance_to(start.elapsed().as_secs_f64());
+        let dst = &mut row[columns.0 * imgs.channels..columns.1 * imgs.channels];
+        let start = (from.1 * imgs.width + from.0) * imgs.channels;
+        let src = &img[start..start + dst.len()];
+        if dst != src {
+            dst.copy_from_slice(src);
+            let (first, last) = (columns.0 as u32, columns.1 as u32 - 1);
+            changed = Some(changed.map_or((first, last), |(f, l)| (f.min(first), l.max(last))));
         }
-        self.step = 4 + self.step / 4;
-        self.simple(new_img)
     }
+    changed
+}
 
-    /// Runs pixels_change_fn for every byte in the old img
-    #[inline(always)]
-    fn draw_all<F>(&self, new_img: &[u8], pixels_change_fn: F)
-    where
-        F: FnOnce(usize, &mut u8, &u8) + Copy + Send + Sync,
-    {
-        let channels = crate::pixel_format().channels() as usize;
-        for wallpaper in self.wallpapers.iter() {
-            wallpaper.canvas_change(|canvas| {
-                canvas
-                    .par_iter_mut()
-                    .zip(new_img.par_iter())
-                    .enumerate()
-                    .for_each(|(i, (old, new))| {
-                        pixels_change_fn(i / channels, old, new);
-                    });
-            });
-            wallpaper.draw();
+/// Applies `change` to the `old` pixel, returning whether it changed it
+#[inline(always)]
+fn change_pixel(change: Change, old: &mut [u8], new: &[u8], imgs: &Imgs) -> bool {
+    let src = match change {
+        Change::Keep => return false,
+        Change::MixAt(x, y, t) => {
+            let Some(img) = imgs.old else {
+                return false;
+            };
+            let from = imgs.pixel(img, x, y).iter();
+            let to = imgs.pixel(imgs.new, x, y);
+            let mut changed = false;
+            for (old, (from, to)) in old.iter_mut().zip(from.zip(to)) {
+                let before = *old;
+                *old = (*from as f64 * (1.0 - t) + *to as f64 * t) as u8;
+                changed |= *old != before;
+            }
+            return changed;
+        }
+        Change::OldAt(x, y) => match imgs.old {
+            Some(img) => imgs.pixel(img, x, y),
+            None => return false,
+        },
+        Change::NewAt(x, y) => imgs.pixel(imgs.new, x, y),
+        Change::Step(_) | Change::Mix(_) => new,
+    };
+    let mut changed = false;
+    for (old, src) in old.iter_mut().zip(src) {
+        let before = *old;
+        match change {
+            Change::Step(step) => change_byte(step, old, src),
+            Change::Mix(t) => *old = (*old as f64 * (1.0 - t) + *src as f64 * t) as u8,
+            _ => *old = *src,
         }
+        changed |= *old != before;
     }
+    changed
 }
 
 #[inline(always)]
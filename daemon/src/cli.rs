@@ -3,12 +3,16 @@ use utils::ipc::PixelFormat;
 pub struct Cli {
     pub format: Option<PixelFormat>,
     pub quiet: bool,
+    pub socket: Option<String>,
+    pub no_cache: bool,
 }
 
 impl Cli {
     pub fn new() -> Self {
         let mut quiet = false;
         let mut format = None;
+        let mut socket = None;
+        let mut no_cache = false;
         let mut args = std::env::args();
         args.next(); // skip the first argument
 
@@ -25,6 +29,14 @@ impl Cli {
                     }
                 },
                 "-q" | "--quiet" => quiet = true,
+                "--no-cache" => no_cache = true,
+                "-s" | "--socket" => match args.next() {
+                    Some(path) if !path.is_empty() => socket = Some(path),
+                    _ => {
+                        eprintln!("`--socket` command line option requires a path");
+                        std::process::exit(-2);
+                    }
+                },
                 "-h" | "--help" => {
                     println!("swww-daemon");
                     println!();
@@ -40,6 +52,14 @@ impl Cli {
                     println!("          Whatever you chose, make sure you compositor actually supports it!");
                     println!("          'xrgb' is the most compatible one.");
                     println!();
+                    println!("  -s|--socket <path>");
+                    println!("          listen at <path> instead of $XDG_RUNTIME_DIR/swww.socket.");
+                    println!("          Overrides the SWWW_SOCKET environment variable.");
+                    println!();
+                    println!("  --no-cache");
+                    println!("          do not restore the images outputs displayed last, from the cache.");
+                    println!("          Scheduled images and the configuration's default image still apply.");
+                    println!();
                     println!("  -q|--quiet    will only log errors");
                     println!("  -h|--help     print help");
                     println!("  -V|--version  print version");
@@ -57,6 +77,11 @@ impl Cli {
             }
         }
 
-        Self { format, quiet }
+        Self {
+            format,
+            quiet,
+            socket,
+            no_cache,
+        }
     }
 }
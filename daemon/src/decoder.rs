@@ -0,0 +1,115 @@
+//! Decoding and resizing images in the daemon itself, for requests that only send us a path.
+
+use std::{path::Path, sync::mpsc::Sender};
+
+use log::{error, warn};
+use utils::{
+    cache,
+    imgproc::{compress_frames, img_resize_mmap, make_filter, ImgBuf},
+    ipc::{self, Answer, ImgByPath, Request, ResizeStrategy},
+};
+
+/// Output names, grouped by their dimensions (already multiplied by their scale factor)
+pub(super) type OutputGroups = Vec<((u32, u32), Vec<String>)>;
+
+/// Decodes and resizes the image in a separate thread, so that we do not block the main loop.
+///
+/// The resulting requests are sent through `requests`, to be dispatched by the main thread like
+/// any other. `groups` must contain the output names to display the image at, grouped by their
+/// dimensions. Once we are done, we call `reply` with the result.
+pub(super) fn spawn<F>(img: ImgByPath, groups: OutputGroups, requests: Sender<Request>, reply: F)
+where
+    F: FnOnce(Answer) + Send + 'static,
+{
+    let spawned = std::thread::Builder::new()
+        .name("img decoder".to_string())
+        .spawn(move || {
+            let answer = match decode(img, groups, requests) {
+                Ok(()) => Answer::Ok,
+                Err(e) => Answer::Err(e),
+            };
+            reply(answer);
+        });
+    if let Err(e) = spawned {
+        error!("failed to spawn 'img decoder' thread: {e}");
+    }
+}
+
+fn decode(img: ImgByPath, groups: OutputGroups, requests: Sender<Request>) -> Result<(), String> {
+    let format = crate::pixel_format();
+    let filter = make_filter(&img.filter);
+    let imgbuf = ImgBuf::new(Path::new(&img.path))?;
+    let first_frame = imgbuf.decode(format)?;
+
+    let mut imgs = Vec::with_capacity(groups.len());
+    for (dim, outputs) in groups.iter() {
+        let img_mmap = img_resize_mmap(&first_frame, *dim, img.resize, filter, &img.fill_color)?;
+        imgs.push((
+            ipc::Img {
+                path: img.path.clone(),
+                img: img_mmap,
+                resize: img.resize,
+                filter: img.filter,
+                fill_color: img.fill_color,
+                span: false,
+            },
+            outputs.clone().into_boxed_slice(),
+        ));
+    }
+    drop(first_frame);
+
+    send_request(
+        &requests,
+        Request::Img((img.transition, imgs.into_boxed_slice())),
+    )?;
+
+    if imgbuf.is_animated() {
+        //TODO: make cache work for all resize strategies
+        let key =
+            (img.resize == ResizeStrategy::Crop).then(|| cache::AnimationKey::new(imgbuf.bytes()));
+        let mut animations = Vec::with_capacity(groups.len());
+        for (dim, outputs) in groups {
+            if let Some(key) = key {
+                match cache::load_animation_frames(key, dim, format) {
+                    Ok(Some(animation)) => {
+                        animations.push((animation, outputs.into_boxed_slice()));
+                        continue;
+                    }
+                    Ok(None) => (),
+                    Err(e) => warn!("failed to load animation frames from cache: {e}"),
+                }
+            }
+            let animation = compress_frames(
+                imgbuf.as_frames()?,
+                dim,
+                format,
+                filter,
+                img.resize,
+                &img.fill_color,
+            )?;
+            let animation = ipc::Animation {
+                animation: animation.into_boxed_slice(),
+                path: img.path.clone(),
+                dimensions: dim,
+                pixel_format: format,
+            };
+            if let Some(key) = key {
+                let max_size = crate::cache_max_size();
+                if let Err(e) = cache::store_animation_frames(key, &animation, max_size) {
+                    error!("failed to store animation frames in cache: {e}");
+                }
+            }
+            animations.push((animation, outputs.into_boxed_slice()));
+        }
+        send_request(&requests, Request::Animation(animations.into_boxed_slice()))?;
+    }
+    Ok(())
+}
+
+fn send_request(requests: &Sender<Request>, request: Request) -> Result<(), String> {
+    requests
+        .send(request)
+        .map_err(|_| "the daemon is exiting".to_string())?;
+    crate::wake_poll();
+    Ok(())
+}
@@ -0,0 +1,78 @@
+//! Pushes `Event`s to every client that subscribed to them.
+//!
+//! Events are published from many different threads (transitions and animations run in their own
+//! threads), so we keep the subscribers in a static, like we do with the other global state.
+//!
+//! Publishing never writes to the sockets itself: each subscriber gets a bounded queue, drained by
+//! its own thread. A subscriber that does not keep up with its events is dropped.
+
+use std::{
+    io::Write,
+    os::unix::net::UnixStream,
+    sync::{
+        mpsc::{self, Receiver, SyncSender},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use log::{debug, error};
+use utils::ipc::{Answer, Event};
+
+/// A subscriber that is not reading its events gets disconnected after this long
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many events may wait for a subscriber to read them
+const QUEUE_LEN: usize = 64;
+
+static SUBSCRIBERS: Mutex<Vec<SyncSender<Event>>> = Mutex::new(Vec::new());
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Encoding {
+    /// `Answer::Event`s, as sent through our binary protocol
+    Binary,
+    /// One json object per line
+    Json,
+}
+
+/// Writes the events in `queue` to `stream` until either the subscriber or we hang up
+fn write_events(stream: UnixStream, encoding: Encoding, queue: Receiver<Event>) {
+    for event in queue {
+        let sent = match encoding {
+            Encoding::Binary => Answer::Event(event).send(&stream).is_ok(),
+            Encoding::Json => writeln!(&stream, "{}", event.to_json()).is_ok(),
+        };
+        if !sent {
+            debug!("subscriber hung up");
+            return;
+        }
+    }
+}
+
+pub(super) fn subscribe(stream: UnixStream, encoding: Encoding) {
+    if let Err(e) = stream.set_write_timeout(Some(WRITE_TIMEOUT)) {
+        error!("failed to set write timeout for subscriber: {e}");
+        return;
+    }
+    let (sender, queue) = mpsc::sync_channel(QUEUE_LEN);
+    if let Err(e) = std::thread::Builder::new()
+        .name("subscriber".to_string())
+        .spawn(move || write_events(stream, encoding, queue))
+    {
+        error!("failed to spawn subscriber thread: {e}");
+        return;
+    }
+    let mut subscribers = SUBSCRIBERS.lock().unwrap();
+    subscribers.push(sender);
+    debug!("new subscriber. Subscriber count: {}", subscribers.len());
+}
+
+/// Queues `event` for all subscribers, forgetting the ones that disconnected or fell behind
+pub(super) fn publish(event: Event) {
+    let mut subscribers = SUBSCRIBERS.lock().unwrap();
+    if subscribers.is_empty() {
+        return;
+    }
+    debug!("publishing event: {event}");
+    subscribers.retain(|subscriber| subscriber.try_send(event.clone()).is_ok());
+}
@@ -0,0 +1,258 @@
+//! Newline-delimited json commands, so that scripts can talk to the daemon without spawning the
+//! `swww` binary.
+//!
+//! These arrive through the same socket as our binary requests. We can tell them apart because
+//! json commands always start with a '{', while our own messages start with `ipc::MAGIC`.
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    os::unix::net::UnixStream,
+    time::Instant,
+};
+
+use log::{debug, error};
+use serde_json::{json, Value};
+use utils::{
+    config,
+    ipc::{self, Answer, DeadlineReader, Request},
+};
+
+/// Json messages are small. We impose a limit to avoid reading garbage forever
+const MAX_LINE_LEN: u64 = 1 << 16;
+
+pub(super) enum JsonCommand {
+    /// Commands that map directly into one of our requests
+    Request(Request),
+    /// Keeps the connection open, so that we can write events as json lines into it
+    Subscribe,
+}
+
+/// Reads a command, failing if its whole line has not arrived by `deadline`
+pub(super) fn read_command(stream: &UnixStream, deadline: Instant) -> Result<JsonCommand, String> {
+    let mut line = String::new();
+    let mut reader = BufReader::new(DeadlineReader::new(stream, deadline)).take(MAX_LINE_LEN);
+    if let Err(e) = reader.read_line(&mut line) {
+        return Err(format!("failed to read json command: {e}"));
+    }
+    let value: Value =
+        serde_json::from_str(&line).map_err(|e| format!("malformed json command: {e}"))?;
+    debug!("received json command: {value}");
+    parse_command(&value)
+}
+
+pub(super) fn send_answer(stream: &UnixStream, answer: &Answer) {
+    let mut writer = stream;
+    if let Err(e) = writeln!(writer, "{}", answer_to_json(answer)) {
+        error!("error sending json answer to client: {e}");
+    }
+}
+
+fn answer_to_json(answer: &Answer) -> Value {
+    match answer {
+        Answer::Ok => json!({ "ok": true }),
+        Answer::Err(e) => json!({ "ok": false, "error": e }),
+        Answer::Info(infos) => {
+            let outputs: Vec<Value> = infos.iter().map(ipc::BgInfo::to_json).collect();
+            json!({ "ok": true, "outputs": outputs })
+        }
+        Answer::Ping(configured) => json!({ "ok": true, "configured": configured }),
+        Answer::Event(event) => event.to_json(),
+    }
+}
+
+fn parse_command(value: &Value) -> Result<JsonCommand, String> {
+    let command = match value.get("command").and_then(Value::as_str) {
+        Some(command) => command,
+        None => return Err("json command must have a \"command\" string field".to_string()),
+    };
+
+    match command {
+        "ping" => Ok(JsonCommand::Request(Request::Ping)),
+        "query" => Ok(JsonCommand::Request(Request::Query)),
+        "kill" => Ok(JsonCommand::Request(Request::Kill)),
+        "subscribe" => Ok(JsonCommand::Subscribe),
+        "clear" => {
+            let mut color = match value.get("color") {
+                Some(color) => parse_color(color)?,
+                None => [0, 0, 0],
+            };
+            if crate::pixel_format().must_swap_r_and_b_channels() {
+                color.swap(0, 2);
+            }
+            Ok(JsonCommand::Request(Request::Clear(ipc::Clear {
+                color,
+                outputs: parse_outputs(value)?,
+            })))
+        }
+        "img" => {
+            let path = match value.get("path").and_then(Value::as_str) {
+                Some(path) => std::fs::canonicalize(path)
+                    .map_err(|e| format!("failed to canonicalize image path '{path}': {e}"))?
+                    .to_string_lossy()
+                    .to_string(),
+                None => return Err("\"img\" command must have a \"path\" string".to_string()),
+            };
+            Ok(JsonCommand::Request(Request::ImgByPath(parse_img_by_path(
+                value, path,
+            )?)))
+        }
+        _ => Err(format!(
+            "unrecognized command '{command}'. Valid commands are: \
+             ping | query | clear | img | subscribe | kill"
+        )),
+    }
+}
+
+/// Reads the fields of an `ImgByPath` other than its path. These are the same fields the "img"
+/// command takes
+///
+/// Fields that are missing fall back to the configuration file's defaults.
+pub(super) fn parse_img_by_path(value: &Value, path: String) -> Result<ipc::ImgByPath, String> {
+    let value = &config::with_defaults(value, crate::config().defaults());
+    Ok(ipc::ImgByPath {
+        path,
+        outputs: parse_outputs(value)?,
+        resize: match value.get("resize") {
+            Some(v) => v.as_str().ok_or("\"resize\" must be a string")?.parse()?,
+            None => ipc::ResizeStrategy::default(),
+        },
+        filter: match value.get("filter") {
+            Some(v) => v.as_str().ok_or("\"filter\" must be a string")?.parse()?,
+            None => ipc::Filter::default(),
+        },
+        fill_color: match value.get("fill_color") {
+            Some(color) => parse_color(color)?,
+            None => [0, 0, 0],
+        },
+        transition: parse_transition(value)?,
+    })
+}
+
+fn parse_outputs(value: &Value) -> Result<Box<[String]>, String> {
+    match value.get("outputs") {
+        None => Ok(Box::new([])),
+        Some(Value::Array(outputs)) => outputs
+            .iter()
+            .map(|o| {
+                o.as_str()
+                    .map(str::to_string)
+                    .ok_or_else(|| "\"outputs\" must only contain strings".to_string())
+            })
+            .collect(),
+        Some(_) => Err("\"outputs\" must be an array of strings".to_string()),
+    }
+}
+
+fn parse_color(value: &Value) -> Result<[u8; 3], String> {
+    let hex = value
+        .as_str()
+        .ok_or_else(|| "colors must be given as \"rrggbb\" strings".to_string())?;
+    if hex.len() != 6 || !hex.is_ascii() {
+        return Err(format!("expected a color in rrggbb format, found '{hex}'"));
+    }
+    let mut color = [0; 3];
+    for (i, c) in color.iter_mut().enumerate() {
+        *c = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|e| format!("invalid color '{hex}': {e}"))?;
+    }
+    Ok(color)
+}
+
+fn parse_transition(value: &Value) -> Result<ipc::Transition, String> {
+    let mut transition = ipc::Transition::default();
+    // like in `swww img`, these aliases take precedence over "transition_angle" and
+    // "transition_step"
+    let mut forced_angle = None;
+    let mut forced_step = None;
+    if let Some(t) = value.get("transition_type") {
+        let effect = |name: &str| -> ipc::TransitionType {
+            name.parse()
+                .expect("aliases only name effects in the registry")
+        };
+        transition.transition_type = match t.as_str() {
+            Some("none") => {
+                forced_step = Some(u8::MAX);
+                effect("simple")
+            }
+            Some(side @ ("right" | "top" | "left" | "bottom")) => {
+                forced_angle = Some(match side {
+                    "right" => 0.0,
+                    "top" => 90.0,
+                    "left" => 180.0,
+                    _ => 270.0,
+                });
+                effect("wipe")
+            }
+            Some("center") => effect("grow"),
+            Some(name) if utils::transitions::find(name).is_some() => effect(name),
+            // schedule entries are only parsed once, so these could not be random every time
+            Some(alias @ ("any" | "random")) => {
+                return Err(format!(
+                    "\"transition_type\" cannot be '{alias}' here, only in `swww img`"
+                ))
+            }
+            _ => {
+                return Err(format!(
+                    "\"transition_type\" must be one of: none | {} | left | right | top | \
+                     bottom | center",
+                    utils::transitions::names()
+                ))
+            }
+        };
+    }
+    transition.step = transition.transition_type.default_step();
+
+    let number = |key: &str| -> Result<Option<f64>, String> {
+        match value.get(key) {
+            None => Ok(None),
+            Some(v) => v
+                .as_f64()
+                .map(Some)
+                .ok_or_else(|| format!("\"{key}\" must be a number")),
+        }
+    };
+    if let Some(step) = number("transition_step")? {
+        transition.step = step.clamp(1.0, u8::MAX as f64) as u8;
+    }
+    if let Some(duration) = number("transition_duration")? {
+        transition.duration = duration as f32;
+    }
+    if let Some(fps) = number("transition_fps")? {
+        transition.fps = fps.clamp(0.0, u16::MAX as f64) as u16;
+    }
+    if let Some(slats) = number("transition_slats")? {
+        transition.slats = slats.clamp(1.0, u16::MAX as f64) as u16;
+    }
+    if let Some(angle) = number("transition_angle")? {
+        transition.angle = angle;
+    }
+    if let Some(bezier) = value.get("transition_bezier") {
+        if let [a, b, c, d] = parse_floats(bezier, "transition_bezier", 4)?[..] {
+            transition.bezier = (a, b, c, d);
+        }
+    }
+    if let Some(wave) = value.get("transition_wave") {
+        if let [width, height] = parse_floats(wave, "transition_wave", 2)?[..] {
+            transition.wave = (width, height);
+        }
+    }
+    transition.angle = forced_angle.unwrap_or(transition.angle);
+    transition.step = forced_step.unwrap_or(transition.step);
+    Ok(transition)
+}
+
+/// Parses `len` comma separated floats, such as "0.54,0,0.34,0.99"
+fn parse_floats(value: &Value, key: &str, len: usize) -> Result<Vec<f32>, String> {
+    let err = || format!("\"{key}\" must be a string with {len} comma separated numbers");
+    let floats = value
+        .as_str()
+        .ok_or_else(err)?
+        .split(',')
+        .map(|f| f.trim().parse::<f32>().map_err(|_| err()))
+        .collect::<Result<Vec<_>, _>>()?;
+    if floats.len() == len {
+        Ok(floats)
+    } else {
+        Err(err())
+    }
+}
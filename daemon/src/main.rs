@@ -5,9 +5,17 @@
 mod animations;
 pub mod bump_pool;
 mod cli;
+mod decoder;
+mod events;
+mod json_ipc;
+mod playlist;
+mod scheduler;
 mod wallpaper;
 use log::{debug, error, info, warn, LevelFilter};
-use rustix::event::{poll, PollFd, PollFlags};
+use rustix::{
+    event::{poll, PollFd, PollFlags},
+    net::RecvFlags,
+};
 use simplelog::{ColorChoice, TermLogger, TerminalMode, ThreadLogMode};
 use wallpaper::Wallpaper;
 
@@ -15,20 +23,22 @@ use std::{
     fs,
     num::NonZeroI32,
     os::{
-        fd::OwnedFd,
+        fd::{FromRawFd, OwnedFd},
         unix::net::{UnixListener, UnixStream},
     },
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc, OnceLock,
+        mpsc::{self, Receiver, Sender},
+        Arc, OnceLock, RwLock, RwLockReadGuard,
     },
+    time::{Duration, Instant},
 };
 
 use smithay_client_toolkit::{
     compositor::{CompositorHandler, CompositorState, Region},
     delegate_compositor, delegate_layer, delegate_output, delegate_registry,
     globals::GlobalData,
-    output::{OutputHandler, OutputState},
+    output::{OutputHandler, OutputInfo, OutputState},
     registry::{ProvidesRegistryState, RegistryState},
     registry_handlers,
     shell::{
@@ -48,11 +58,32 @@ use wayland_client::{
     },
     Connection, Dispatch, QueueHandle,
 };
+use wayland_protocols::wp::{
+    fractional_scale::v1::client::{
+        wp_fractional_scale_manager_v1::WpFractionalScaleManagerV1,
+        wp_fractional_scale_v1::{self, WpFractionalScaleV1},
+    },
+    viewporter::client::{wp_viewport::WpViewport, wp_viewporter::WpViewporter},
+};
 
-use utils::ipc::{get_socket_path, Answer, BgInfo, PixelFormat, Request};
+use utils::{
+    cache::{self, CachedImg},
+    config::{self, Config},
+    ipc::{
+        self, get_socket_path, Answer, BgImg, BgInfo, Clear, Event, ImgByPath, PixelFormat,
+        Request, Scale, Transition,
+    },
+    selector::{OutputId, Selection},
+};
+
+use decoder::OutputGroups;
+use json_ipc::JsonCommand;
 
 use animations::Animator;
 
+/// How long a client has to send us its whole request before we give up on it
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
 // We need this because this might be set by signals, so we can't keep it in the daemon
 static EXIT: AtomicBool = AtomicBool::new(false);
 
@@ -68,6 +99,7 @@ fn should_daemon_exit() -> bool {
 
 static POLL_WAKER: OnceLock<OwnedFd> = OnceLock::new();
 static PIXEL_FORMAT: OnceLock<PixelFormat> = OnceLock::new();
+static CONFIG: RwLock<Config> = RwLock::new(Config::new());
 
 #[inline]
 pub fn wl_shm_format() -> wl_shm::Format {
@@ -85,6 +117,41 @@ pub fn pixel_format() -> PixelFormat {
     *PIXEL_FORMAT.get().unwrap_or(&PixelFormat::Xrgb)
 }
 
+/// The settings from the user's configuration file
+pub fn config() -> RwLockReadGuard<'static, Config> {
+    CONFIG.read().unwrap()
+}
+
+/// The cache's maximum size, in bytes. See `utils::cache`
+pub fn cache_max_size() -> u64 {
+    cache::max_size(&config()).unwrap_or_else(|e| {
+        warn!("{e}");
+        cache::DEFAULT_MAX_SIZE * 1024 * 1024
+    })
+}
+
+/// Reads the configuration file, and applies the daemon's own options. If it fails, we keep using
+/// the previous configuration
+fn load_config(quiet: bool) {
+    match Config::load() {
+        Ok(config) => *CONFIG.write().unwrap() = config,
+        Err(e) => error!("failed to load configuration file: {e}"),
+    }
+
+    let level = match config().daemon().get("log_level") {
+        _ if quiet => LevelFilter::Error,
+        None => LevelFilter::Debug,
+        Some(level) => match level.as_str().map(str::parse::<LevelFilter>) {
+            Some(Ok(level)) => level,
+            _ => {
+                error!("\"log_level\" must be one of: off | error | warn | info | debug");
+                LevelFilter::Debug
+            }
+        },
+    };
+    log::set_max_level(level);
+}
+
 #[inline]
 pub fn wake_poll() {
     debug_assert!(POLL_WAKER.get().is_some());
@@ -105,15 +172,44 @@ extern "C" fn signal_handler(_s: i32) {
     exit_daemon();
 }
 
+// Set by SIGHUP, and handled in the main loop
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+// Set whenever an output or its image changes, so that we only rebuild the status we send to
+// systemd when there is something new in it
+static STATUS_CHANGED: AtomicBool = AtomicBool::new(true);
+
+pub fn status_changed() {
+    STATUS_CHANGED.store(true, Ordering::Release);
+    wake_poll();
+}
+
+extern "C" fn reload_handler(_s: i32) {
+    RELOAD.store(true, Ordering::Release);
+    // we cannot log from here, so we do not use `wake_poll`
+    if let Some(fd) = POLL_WAKER.get() {
+        let _ = rustix::io::write(fd, &1u64.to_ne_bytes());
+    }
+}
+
 fn main() -> Result<(), String> {
     let cli = cli::Cli::new();
-    make_logger(cli.quiet);
+    if let Some(socket) = &cli.socket {
+        // so that the `swww` processes we spawn talk to us, too
+        std::env::set_var(ipc::SOCKET_ENV, socket);
+    }
+    make_logger();
 
     if let Some(format) = cli.format {
         PIXEL_FORMAT.set(format).unwrap();
         info!("Forced usage of wl_shm format: {:?}", wl_shm_format());
     }
 
+    load_config(cli.quiet);
+    if let Err(e) = cache::prune(cache_max_size()) {
+        warn!("failed to prune the cache: {e}");
+    }
+
     rayon::ThreadPoolBuilder::default()
         .thread_name(|i| format!("rayon thread {i}"))
         .stack_size(1 << 19) // 512KiB; we do not need a large stack
@@ -129,10 +225,15 @@ fn main() -> Result<(), String> {
         registry_queue_init(&conn).expect("failed to initialize the event queue");
     let qh = event_queue.handle();
 
-    let mut daemon = Daemon::new(&globals, &qh);
+    let mut daemon = Daemon::new(&globals, &qh, cli.no_cache);
+    playlist::restore(&daemon.deferred_requests.0, |selectors| {
+        daemon.selected_outputs(selectors)
+    });
+    scheduler::start(&daemon.deferred_requests.0);
 
+    // we keep NOTIFY_SOCKET around, to keep our status up to date
     if let Ok(true) = sd_notify::booted() {
-        if let Err(e) = sd_notify::notify(true, &[sd_notify::NotifyState::Ready]) {
+        if let Err(e) = sd_notify::notify(false, &[sd_notify::NotifyState::Ready]) {
             error!("Error sending status update to systemd: {}", e.to_string());
         }
     }
@@ -150,7 +251,7 @@ fn main() -> Result<(), String> {
         let events = {
             let connection_fd = read_guard.connection_fd();
             let mut fds = [
-                PollFd::new(&listener.0, PollFlags::IN),
+                PollFd::new(&listener.listener, PollFlags::IN),
                 PollFd::new(&connection_fd, PollFlags::IN | PollFlags::RDBAND),
                 PollFd::new(&wake, PollFlags::IN),
             ];
@@ -174,7 +275,7 @@ fn main() -> Result<(), String> {
         }
 
         if !events[0].is_empty() {
-            match listener.0.accept() {
+            match listener.listener.accept() {
                 Ok((stream, _adr)) => daemon.recv_socket_msg(stream),
                 Err(e) => match e.kind() {
                     std::io::ErrorKind::WouldBlock => (),
@@ -188,8 +289,24 @@ fn main() -> Result<(), String> {
                 error!("error reading pipe file descriptor: {e}");
             }
         }
+
+        daemon.handle_deferred_requests();
+
+        if RELOAD.swap(false, Ordering::AcqRel) {
+            info!("Reloading...");
+            load_config(cli.quiet);
+            scheduler::start(&daemon.deferred_requests.0);
+            daemon.reapply_all();
+        }
+
+        if STATUS_CHANGED.swap(false, Ordering::AcqRel) {
+            daemon.notify_status();
+        }
     }
 
+    if let Err(e) = sd_notify::notify(false, &[sd_notify::NotifyState::Stopping]) {
+        error!("Error sending status update to systemd: {e}");
+    }
     info!("Goodbye!");
     Ok(())
 }
@@ -198,16 +315,25 @@ fn main() -> Result<(), String> {
 fn setup_signals_and_eventfd() -> OwnedFd {
     let mut mask = std::mem::MaybeUninit::uninit();
     unsafe { libc::sigemptyset(mask.as_mut_ptr()) };
-    let sigaction = libc::sigaction {
+    let mask = unsafe { mask.assume_init() };
+    let exit = libc::sigaction {
         sa_sigaction: signal_handler as *const extern "C" fn(libc::c_int) as usize,
-        sa_mask: unsafe { mask.assume_init() },
+        sa_mask: mask,
         sa_flags: 0,
         sa_restorer: None,
     };
+    let reload = libc::sigaction {
+        sa_sigaction: reload_handler as *const extern "C" fn(libc::c_int) as usize,
+        ..exit
+    };
 
-    for signal in [libc::SIGINT, libc::SIGQUIT, libc::SIGTERM] {
-        let ret =
-            unsafe { libc::sigaction(signal, std::ptr::addr_of!(sigaction), std::ptr::null_mut()) };
+    for (signal, sigaction) in [
+        (libc::SIGINT, &exit),
+        (libc::SIGQUIT, &exit),
+        (libc::SIGTERM, &exit),
+        (libc::SIGHUP, &reload),
+    ] {
+        let ret = unsafe { libc::sigaction(signal, sigaction, std::ptr::null_mut()) };
         if ret != 0 {
             error!("Failed to install signal handler!")
         }
@@ -223,14 +349,38 @@ fn setup_signals_and_eventfd() -> OwnedFd {
 
 /// This is a wrapper that makes sure to delete the socket when it is dropped
 /// It also makes sure to set the listener to nonblocking mode
-struct SocketWrapper(UnixListener);
+struct SocketWrapper {
+    listener: UnixListener,
+    /// Sockets we inherited through socket activation belong to the service manager, so we must
+    /// leave their file alone
+    owns_file: bool,
+}
+
 impl SocketWrapper {
     fn new() -> Result<Self, String> {
-        if is_daemon_running()? {
-            return Err("There is an swww-daemon instance already running!".to_string());
+        if let Some(listener) = inherited_listener()? {
+            if let Err(e) = listener.set_nonblocking(true) {
+                return Err(format!("failed to set socket to nonblocking mode: {e}"));
+            }
+            let addr = listener.local_addr().ok();
+            debug!("Listening at inherited socket {addr:?}. Starting daemon...");
+            // so that the `swww` processes we spawn talk to us, too
+            if let Some(path) = addr.as_ref().and_then(|addr| addr.as_pathname()) {
+                std::env::set_var(ipc::SOCKET_ENV, path);
+            }
+            return Ok(Self {
+                listener,
+                owns_file: false,
+            });
         }
 
         let socket_addr = get_socket_path();
+        if ipc::is_daemon_listening(&socket_addr) {
+            return Err(format!(
+                "There is an swww-daemon instance already listening at {}!",
+                socket_addr.to_string_lossy()
+            ));
+        }
         if socket_addr.exists() {
             warn!(
                 "socket file {} was not deleted when the previous daemon exited",
@@ -267,12 +417,42 @@ impl SocketWrapper {
             return Err(format!("failed to set socket to nonblocking mode: {e}"));
         }
 
-        Ok(Self(listener))
+        Ok(Self {
+            listener,
+            owns_file: true,
+        })
+    }
+}
+
+/// The listening socket the service manager passed us, if we were socket activated
+fn inherited_listener() -> Result<Option<UnixListener>, String> {
+    let mut fds = match sd_notify::listen_fds() {
+        Ok(fds) => fds,
+        Err(e) => return Err(format!("failed to read LISTEN_FDS: {e}")),
+    };
+    let Some(fd) = fds.next() else {
+        return Ok(None);
+    };
+    if fds.next().is_some() {
+        warn!("got more than one socket through LISTEN_FDS. Only using the first one");
+    }
+    // SAFETY: the service manager gave us this file descriptor, and nothing else uses it
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    match rustix::net::sockopt::get_socket_type(&fd) {
+        Ok(rustix::net::SocketType::STREAM) => Ok(Some(UnixListener::from(fd))),
+        _ => Err(
+            "the socket passed through LISTEN_FDS must be a unix stream socket \
+            (ListenStream= in the .socket unit)"
+                .to_string(),
+        ),
     }
 }
 
 impl Drop for SocketWrapper {
     fn drop(&mut self) {
+        if !self.owns_file {
+            return;
+        }
         let socket_addr = get_socket_path();
         if let Err(e) = fs::remove_file(&socket_addr) {
             error!("Failed to remove socket at {socket_addr:?}: {e}");
@@ -290,14 +470,27 @@ struct Daemon {
     shm: Shm,
     pixel_format: PixelFormat,
     shm_format: wl_shm::Format,
+    /// Both are needed for fractional scaling, so we only keep them if the compositor supports both
+    fractional_scaling: Option<(WpViewporter, WpFractionalScaleManagerV1)>,
 
     // swww stuff
     wallpapers: Vec<Arc<Wallpaper>>,
     animator: Animator,
+
+    /// Requests created by other threads (for example, after decoding an image), that must be
+    /// handled in the main thread
+    deferred_requests: (Sender<Request>, Receiver<Request>),
+
+    /// The last status we sent to systemd
+    status: String,
+
+    /// The outputs whose last image we must not restore, because of `--no-cache`: the ones that
+    /// were there when we started, until they are first configured or we reload. See `utils::cache`
+    no_cache: Vec<u32>,
 }
 
 impl Daemon {
-    fn new(globals: &GlobalList, qh: &QueueHandle<Self>) -> Self {
+    fn new(globals: &GlobalList, qh: &QueueHandle<Self>, no_cache: bool) -> Self {
         // The compositor (not to be confused with the server which is commonly called the compositor) allows
         // configuring surfaces to be presented.
         let compositor_state =
@@ -310,6 +503,19 @@ impl Daemon {
         let pixel_format = PixelFormat::Xrgb;
         let shm_format = wl_shm::Format::Xrgb8888;
 
+        let fractional_scaling = match (
+            globals.bind::<WpViewporter, _, _>(qh, 1..=1, ()),
+            globals.bind::<WpFractionalScaleManagerV1, _, _>(qh, 1..=1, ()),
+        ) {
+            (Ok(viewporter), Ok(fractional_scale_manager)) => {
+                Some((viewporter, fractional_scale_manager))
+            }
+            _ => {
+                info!("fractional scaling is not available, falling back to integer scales");
+                None
+            }
+        };
+
         Self {
             layer_shell,
             // Outputs may be hotplugged at runtime, therefore we need to setup a registry state to
@@ -320,32 +526,300 @@ impl Daemon {
             shm,
             pixel_format,
             shm_format,
+            fractional_scaling,
 
             wallpapers: Vec::new(),
             animator: Animator::new(),
+            deferred_requests: mpsc::channel(),
+            status: String::new(),
+            no_cache: if no_cache {
+                // the outputs' ids are their globals' names
+                globals.contents().with_list(|globals| {
+                    globals
+                        .iter()
+                        .filter(|global| global.interface == "wl_output")
+                        .map(|global| global.name)
+                        .collect()
+                })
+            } else {
+                Vec::new()
+            },
         }
     }
 
     fn recv_socket_msg(&mut self, stream: UnixStream) {
-        let bytes = match utils::ipc::read_socket(&stream) {
-            Ok(bytes) => bytes,
+        // we read requests in the main loop, so a client that never finishes sending one must not
+        // be able to freeze us
+        let deadline = Instant::now() + REQUEST_TIMEOUT;
+        if let Err(e) = stream.set_read_timeout(Some(REQUEST_TIMEOUT)) {
+            error!("failed to set read timeout for socket: {e}");
+            return;
+        }
+        // json commands always start with '{', while our own messages start with ipc::MAGIC
+        let mut first_byte = [0];
+        match rustix::net::recv(&stream, &mut first_byte, RecvFlags::PEEK) {
+            Ok(1) if first_byte[0] == b'{' => {
+                self.recv_json_msg(stream, deadline);
+                return;
+            }
+            // the client only wanted to know whether we are listening
+            Ok(0) => return,
+            _ => (),
+        }
+
+        let msg = match utils::ipc::read_socket_before(&stream, deadline) {
+            Ok(msg) => msg,
             Err(e) => {
-                error!("FATAL: cannot read socket: {e}. Exiting...");
-                exit_daemon();
+                error!("cannot read socket: {e}");
+                if let Err(e) = Answer::Err(e).send(&stream) {
+                    error!("error sending answer to client: {e}");
+                }
+                return;
+            }
+        };
+        if msg.is_handshake() {
+            // whatever version the client is, it can read our header
+            if let Err(e) = utils::ipc::send_handshake(&stream) {
+                error!("error sending handshake to client: {e}");
+            }
+            return;
+        }
+        let request = match Request::receive(msg) {
+            Ok(request) => request,
+            Err(e) => {
+                error!("rejected request: {e}");
+                if let Err(e) = Answer::Err(e).send(&stream) {
+                    error!("error sending answer to client: {e}");
+                }
+                return;
+            }
+        };
+        if let Request::Subscribe = request {
+            match Answer::Ok.send(&stream) {
+                Ok(()) => events::subscribe(stream, events::Encoding::Binary),
+                Err(e) => error!("error sending answer to client: {e}"),
+            }
+            return;
+        }
+        if let Request::ImgByPath(img) = request {
+            self.decode_img(img, move |answer| {
+                if let Err(e) = answer.send(&stream) {
+                    error!("error sending answer to client: {e}");
+                }
+            });
+            return;
+        }
+        let answer = self.handle_request(request);
+        if let Err(e) = answer.send(&stream) {
+            error!("error sending answer to client: {e}");
+        }
+    }
+
+    fn recv_json_msg(&mut self, stream: UnixStream, deadline: Instant) {
+        let answer = match json_ipc::read_command(&stream, deadline) {
+            Ok(JsonCommand::Request(Request::ImgByPath(img))) => {
+                self.decode_img(img, move |answer| json_ipc::send_answer(&stream, &answer));
+                return;
+            }
+            Ok(JsonCommand::Request(request)) => self.handle_request(request),
+            Ok(JsonCommand::Subscribe) => {
+                json_ipc::send_answer(&stream, &Answer::Ok);
+                events::subscribe(stream, events::Encoding::Json);
+                return;
+            }
+            Err(e) => Answer::Err(e),
+        };
+        json_ipc::send_answer(&stream, &answer);
+    }
+
+    /// Decodes the image in another thread, calling `reply` once done
+    fn decode_img<F>(&mut self, img: ImgByPath, reply: F)
+    where
+        F: FnOnce(Answer) + Send + 'static,
+    {
+        if let Err(e) = animations::check_transition(&img.transition) {
+            reply(Answer::Err(e));
+            return;
+        }
+        let groups = match self.group_outputs_by_dim(&img.outputs) {
+            Ok(groups) => groups,
+            Err(e) => {
+                reply(Answer::Err(e));
                 return;
             }
         };
-        let request = Request::receive(&bytes);
-        let answer = match request {
+        for wallpaper in self.find_wallpapers(&img.outputs).unwrap_or_default() {
+            wallpaper.set_img_source(Some(img.clone()));
+        }
+        let requests = self.deferred_requests.0.clone();
+        decoder::spawn(img, groups, requests, reply);
+    }
+
+    /// Tells systemd how many outputs we have and what they display, whenever that changes
+    fn notify_status(&mut self) {
+        let mut status = match self.wallpapers.len() {
+            1 => "1 output".to_string(),
+            n => format!("{n} outputs"),
+        };
+        for (i, wallpaper) in self.wallpapers.iter().enumerate() {
+            let separator = if i == 0 { ": " } else { "; " };
+            status.push_str(&format!(
+                "{separator}{} ({})",
+                wallpaper.output_name(),
+                wallpaper.get_img_info()
+            ));
+        }
+        if status == self.status {
+            return;
+        }
+        if let Err(e) = sd_notify::notify(false, &[sd_notify::NotifyState::Status(&status)]) {
+            error!("Error sending status update to systemd: {e}");
+        }
+        self.status = status;
+    }
+
+    /// What `output` should display when it appears, or when we reload: whatever the schedule says,
+    /// then the image it displayed last (unless `--no-cache` still applies to it), then the
+    /// configuration file's default image. It is shown without a transition
+    fn initial_img(&self, output: &str) -> Option<ImgByPath> {
+        if let Some(img) =
+            scheduler::current_img(output, |selectors| self.selects(selectors, output))
+        {
+            return Some(img);
+        }
+        let transition = Transition {
+            step: u8::MAX,
+            ..Transition::default()
+        };
+        let cached = self
+            .output_info(output)
+            .filter(|info| !self.no_cache.contains(&info.id));
+        let cached = cached.and_then(|info| {
+            cache::get_previous_image(&output_id(&info)).unwrap_or_else(|e| {
+                warn!("failed to read cache for {output}: {e}");
+                None
+            })
+        });
+        if let Some(cached) = cached {
+            return Some(ImgByPath {
+                path: cached.path,
+                outputs: Box::new([output.to_string()]),
+                resize: cached.resize,
+                filter: cached.filter,
+                fill_color: cached.fill_color,
+                transition,
+            });
+        }
+
+        let settings = config().for_output(output);
+        let path = match settings.get("image") {
+            Some(image) => image
+                .as_str()
+                .ok_or_else(|| "\"image\" must be a string".to_string())
+                .and_then(config::resolve_path),
+            None => return None,
+        };
+        match path.and_then(|path| json_ipc::parse_img_by_path(&settings, path)) {
+            Ok(img) => Some(ImgByPath {
+                outputs: Box::new([output.to_string()]),
+                transition,
+                ..img
+            }),
+            Err(e) => {
+                error!("failed to find the initial image for {output}: {e}");
+                None
+            }
+        }
+    }
+
+    /// Draws every output's image again, after reloading the configuration. See `Self::initial_img`
+    fn reapply_all(&mut self) {
+        self.no_cache.clear();
+        let outputs: Vec<String> = self
+            .wallpapers
+            .iter()
+            .map(|wallpaper| wallpaper.output_name().to_string())
+            .collect();
+        for output in outputs {
+            if let Some(img) = self.initial_img(&output) {
+                self.decode_img(img, |answer| {
+                    if let Answer::Err(e) = answer {
+                        error!("failed to display image after reloading: {e}");
+                    }
+                });
+            }
+        }
+    }
+
+    /// Draws `previous` again after the wallpaper was resized, since resizing resets it to black.
+    ///
+    /// Images are decoded again from their source, at the new dimensions. If we do not know where
+    /// the image came from (e.g. it was read from stdin), the output stays black.
+    fn reapply_wallpaper(&mut self, wallpaper: &Wallpaper, previous: BgImg) {
+        let output = wallpaper.output_name().to_string();
+        match previous {
+            BgImg::Color(color) => {
+                let clear = Clear {
+                    color,
+                    outputs: Box::new([output]),
+                };
+                if let Answer::Err(e) = self.handle_request(Request::Clear(clear)) {
+                    error!(
+                        "failed to clear {} after it changed: {e}",
+                        wallpaper.output_name()
+                    );
+                }
+            }
+            BgImg::Img(_) => {
+                let Some(source) = wallpaper.img_source() else {
+                    warn!("cannot redraw {output} after it changed: unknown image source");
+                    return;
+                };
+                let img = ImgByPath {
+                    outputs: Box::new([output]),
+                    // we want the image back as soon as possible
+                    transition: Transition {
+                        step: u8::MAX,
+                        ..Transition::default()
+                    },
+                    ..source
+                };
+                self.decode_img(img, |answer| {
+                    if let Answer::Err(e) = answer {
+                        error!("failed to redecode image after output changed: {e}");
+                    }
+                });
+            }
+        }
+    }
+
+    fn handle_deferred_requests(&mut self) {
+        while let Ok(request) = self.deferred_requests.1.try_recv() {
+            if let Answer::Err(e) = self.handle_request(request) {
+                error!("failed to handle request: {e}");
+            }
+        }
+    }
+
+    fn handle_request(&mut self, request: Request) -> Answer {
+        match request {
             Request::Animation(animations) => {
+                // the client already turned any selectors into names, so there is nothing to report
+                // if some outputs went away in the meantime
                 let mut wallpapers = Vec::new();
                 for (_, names) in animations.iter() {
-                    wallpapers.push(self.find_wallpapers_by_names(names));
+                    wallpapers.push(self.find_wallpapers(names).unwrap_or_default());
                 }
                 self.animator.animate(animations, wallpapers)
             }
             Request::Clear(clear) => {
-                let wallpapers = self.find_wallpapers_by_names(&clear.outputs);
+                let wallpapers = match self.find_wallpapers(&clear.outputs) {
+                    Ok(wallpapers) => wallpapers,
+                    Err(e) => return Answer::Err(e),
+                };
+                for wallpaper in &wallpapers {
+                    wallpaper.set_img_source(None);
+                }
                 let color = clear.color;
                 match std::thread::Builder::new()
                     .stack_size(1 << 15)
@@ -355,7 +829,7 @@ impl Daemon {
                             wallpaper.stop_animations();
                         }
                         for wallpaper in wallpapers {
-                            wallpaper.set_img_info(utils::ipc::BgImg::Color(color));
+                            wallpaper.set_img_info(BgImg::Color(color));
                             wallpaper.clear(color);
                             wallpaper.draw();
                         }
@@ -375,20 +849,60 @@ impl Daemon {
                 Answer::Ok
             }
             Request::Query => Answer::Info(self.wallpapers_info()),
+            Request::Subscribe => {
+                Answer::Err("subscribing requires a connection to send events through".to_string())
+            }
+            Request::Playlist(command) => {
+                playlist::handle(command, &self.deferred_requests.0, |selectors| {
+                    self.selected_outputs(selectors)
+                })
+            }
+            Request::ImgByPath(img) => {
+                self.decode_img(img, |answer| {
+                    if let Answer::Err(e) = answer {
+                        error!("failed to decode image: {e}");
+                    }
+                });
+                Answer::Ok
+            }
             Request::Img((transitions, imgs)) => {
+                if let Err(e) = animations::check_transition(&transitions) {
+                    return Answer::Err(e);
+                }
                 let mut used_wallpapers = Vec::new();
                 for img in imgs.iter() {
-                    let mut wallpapers = self.find_wallpapers_by_names(&img.1);
+                    let mut wallpapers = self.find_wallpapers(&img.1).unwrap_or_default();
+                    let source = img_source(&img.0);
                     for wallpaper in wallpapers.iter_mut() {
                         wallpaper.stop_animations();
+                        wallpaper.set_img_source(source.clone());
+                        if let Some(source) = &source {
+                            self.store_cache(wallpaper.output_name(), source);
+                        }
                     }
                     used_wallpapers.push(wallpapers);
                 }
                 self.animator.transition(transitions, imgs, used_wallpapers)
             }
-        };
-        if let Err(e) = answer.send(&stream) {
-            error!("error sending answer to client: {e}");
+        }
+    }
+
+    /// Groups the selected outputs' names by their dimensions (already multiplied by their scale
+    /// factor). An empty `selectors` slice means all outputs
+    fn group_outputs_by_dim(&self, selectors: &[String]) -> Result<OutputGroups, String> {
+        let mut groups: OutputGroups = Vec::new();
+        for wallpaper in self.find_wallpapers(selectors)? {
+            let dim = wallpaper.get_dimensions();
+            let name = wallpaper.output_name().to_string();
+            match groups.iter_mut().find(|(d, _)| *d == dim) {
+                Some((_, outputs)) => outputs.push(name),
+                None => groups.push((dim, vec![name])),
+            }
+        }
+        if groups.is_empty() {
+            Err("none of the requested outputs are valid".to_string())
+        } else {
+            Ok(groups)
         }
     }
 
@@ -398,16 +912,7 @@ impl Daemon {
             .filter_map(|output| {
                 if let Some(info) = self.output_state.info(&output) {
                     if let Some(wallpaper) = self.wallpapers.iter().find(|w| w.has_id(info.id)) {
-                        return Some(BgInfo {
-                            name: info.name.unwrap_or("?".to_string()),
-                            dim: info
-                                .logical_size
-                                .map(|(width, height)| (width as u32, height as u32))
-                                .unwrap_or((0, 0)),
-                            scale_factor: info.scale_factor,
-                            img: wallpaper.get_img_info(),
-                            pixel_format: pixel_format(),
-                        });
+                        return Some(make_bg_info(info, wallpaper));
                     }
                 }
                 None
@@ -415,25 +920,113 @@ impl Daemon {
             .collect()
     }
 
-    fn find_wallpapers_by_names(&self, names: &[String]) -> Vec<Arc<Wallpaper>> {
+    /// Every output we know of, in the order `swww query` lists them, which is the order index
+    /// selectors count in
+    fn output_infos(&self) -> Vec<OutputInfo> {
         self.output_state
             .outputs()
-            .filter_map(|output| {
-                if let Some(info) = self.output_state.info(&output) {
-                    if let Some(name) = info.name {
-                        if names.is_empty() || names.iter().any(|n| n.as_str() == name) {
-                            if let Some(wallpaper) =
-                                self.wallpapers.iter().find(|w| w.has_id(info.id))
-                            {
-                                return Some(Arc::clone(wallpaper));
-                            }
-                        }
-                    }
-                }
-                None
-            })
+            .filter_map(|output| self.output_state.info(&output))
             .collect()
     }
+
+    /// The wallpapers of the outputs `selectors` select. See `utils::selector`
+    fn find_wallpapers(&self, selectors: &[String]) -> Result<Vec<Arc<Wallpaper>>, String> {
+        let infos = self.output_infos();
+        let ids: Vec<OutputId> = infos.iter().map(output_id).collect();
+        let selected = Selection::parse(selectors)?.select(&ids)?;
+        Ok(selected
+            .into_iter()
+            .filter_map(|i| self.wallpapers.iter().find(|w| w.has_id(infos[i].id)))
+            .cloned()
+            .collect())
+    }
+
+    /// The names of the outputs `selectors` select. See `utils::selector`
+    fn selected_outputs(&self, selectors: &[String]) -> Result<Vec<String>, String> {
+        let wallpapers = self.find_wallpapers(selectors)?;
+        Ok(wallpapers
+            .iter()
+            .map(|wallpaper| wallpaper.output_name().to_string())
+            .collect())
+    }
+
+    /// The information the compositor gave us about the output called `output`
+    fn output_info(&self, output: &str) -> Option<OutputInfo> {
+        self.output_infos()
+            .into_iter()
+            .find(|info| info.name.as_deref() == Some(output))
+    }
+
+    /// Remembers `img` as the image `output` displays. See `utils::cache`
+    fn store_cache(&self, output: &str, img: &ImgByPath) {
+        let Some(info) = self.output_info(output) else {
+            return;
+        };
+        let cached = CachedImg {
+            path: img.path.clone(),
+            resize: img.resize,
+            filter: img.filter,
+            fill_color: img.fill_color,
+        };
+        if let Err(e) = cache::store(&output_id(&info), &cached) {
+            error!("failed to store cache: {e}");
+        }
+    }
+
+    /// Whether `selectors` select the output called `output`
+    fn selects(&self, selectors: &[String], output: &str) -> bool {
+        let Ok(selection) = Selection::parse(selectors) else {
+            return false;
+        };
+        self.output_infos().iter().enumerate().any(|(i, info)| {
+            info.name.as_deref() == Some(output) && selection.matches(i, &output_id(info))
+        })
+    }
+}
+
+fn output_id(info: &OutputInfo) -> OutputId<'_> {
+    OutputId {
+        name: info.name.as_deref().unwrap_or(""),
+        description: info.description.as_deref().unwrap_or(""),
+        make: &info.make,
+        model: &info.model,
+    }
+}
+
+fn make_bg_info(info: OutputInfo, wallpaper: &Wallpaper) -> BgInfo {
+    let dim = wallpaper::logical_size(&info)
+        .map(|(width, height)| (width as u32, height as u32))
+        .unwrap_or((0, 0));
+    BgInfo {
+        name: info.name.unwrap_or("?".to_string()),
+        description: info.description.unwrap_or_default(),
+        make: info.make,
+        model: info.model,
+        position: info.logical_position.unwrap_or((0, 0)),
+        dim,
+        scale_factor: wallpaper.get_scale_factor(),
+        transform: wallpaper.get_transform(),
+        img: wallpaper.get_img_info(),
+        pixel_format: pixel_format(),
+        animating: wallpaper.is_animating(),
+        transitioning: wallpaper.is_transitioning(),
+    }
+}
+
+/// What we need to decode `img` again, should its output change. Images read from stdin cannot be
+/// read twice, and spanned images depend on the layout of all the outputs they span
+fn img_source(img: &ipc::Img) -> Option<ImgByPath> {
+    if img.path == "STDIN" || img.span {
+        return None;
+    }
+    Some(ImgByPath {
+        path: img.path.clone(),
+        outputs: Box::new([]),
+        resize: img.resize,
+        filter: img.filter,
+        fill_color: img.fill_color,
+        transition: Transition::default(),
+    })
 }
 
 impl CompositorHandler for Daemon {
@@ -444,10 +1037,11 @@ impl CompositorHandler for Daemon {
         surface: &wl_surface::WlSurface,
         new_factor: i32,
     ) {
-        for wallpaper in self.wallpapers.iter_mut() {
-            if wallpaper.has_surface(surface) {
-                wallpaper.resize(None, None, Some(NonZeroI32::new(new_factor).unwrap()));
-                return;
+        if let Some(wallpaper) = self.wallpapers.iter().find(|w| w.has_surface(surface)) {
+            let wallpaper = Arc::clone(wallpaper);
+            let previous = wallpaper.get_img_info();
+            if wallpaper.resize(None, None, Some(Scale::Whole(new_factor))) {
+                self.reapply_wallpaper(&wallpaper, previous);
             }
         }
     }
@@ -472,9 +1066,14 @@ impl CompositorHandler for Daemon {
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
         _surface: &wl_surface::WlSurface,
-        _new_transform: wl_output::Transform,
+        new_transform: wl_output::Transform,
     ) {
-        // do not do anything for now
+        // This is only a hint of the buffer transform that would spare the compositor from
+        // transforming our buffers. We keep drawing them untransformed, in the surface's
+        // orientation, which is correct for all eight transforms: the compositor applies the
+        // output's transform itself. When that transform swaps the output's width and height,
+        // `update_output` resizes the wallpaper and draws its image again
+        debug!("preferred buffer transform: {new_transform:?}. Keeping it normal");
     }
 }
 
@@ -502,6 +1101,19 @@ impl OutputHandler for Daemon {
             if let Ok(region) = Region::new(&self.compositor_state) {
                 surface.set_input_region(Some(region.wl_region()));
             }
+            let fractional_scale =
+                self.fractional_scaling
+                    .as_ref()
+                    .map(|(viewporter, fractional_scale_manager)| {
+                        (
+                            viewporter.get_viewport(&surface, qh, ()),
+                            fractional_scale_manager.get_fractional_scale(
+                                &surface,
+                                qh,
+                                surface.clone(),
+                            ),
+                        )
+                    });
             let layer_surface = self.layer_shell.create_layer_surface(
                 qh,
                 surface,
@@ -510,32 +1122,18 @@ impl OutputHandler for Daemon {
                 Some(&output),
             );
 
-            if let Some(name) = &output_info.name {
-                let name = name.to_owned();
-                if let Err(e) = std::thread::Builder::new()
-                    .name("cache loader".to_string())
-                    .stack_size(1 << 14)
-                    .spawn(move || {
-                        // Wait for a bit for the output to be properly configured and stuff
-                        // this is obviously not ideal, but it solves the vast majority of problems
-                        std::thread::sleep(std::time::Duration::from_millis(100));
-                        if let Err(e) = utils::cache::load(&name) {
-                            warn!("failed to load cache: {e}");
-                        }
-                    })
-                {
-                    warn!("failed to spawn `cache loader` thread: {e}");
-                }
-            }
-
             debug!("New output: {output_info:?}");
-            self.wallpapers.push(Arc::new(Wallpaper::new(
-                output_info,
+            let wallpaper = Arc::new(Wallpaper::new(
+                output_info.clone(),
                 layer_surface,
+                fractional_scale,
                 &self.shm,
                 qh,
-            )));
+            ));
+            events::publish(Event::OutputAdded(make_bg_info(output_info, &wallpaper)));
+            self.wallpapers.push(wallpaper);
             debug!("Output count: {}", self.wallpapers.len());
+            status_changed();
         }
     }
 
@@ -546,7 +1144,7 @@ impl OutputHandler for Daemon {
         output: wl_output::WlOutput,
     ) {
         if let Some(output_info) = self.output_state.info(&output) {
-            if let Some(output_size) = output_info.logical_size {
+            if let Some(output_size) = wallpaper::logical_size(&output_info) {
                 if output_size.0 == 0 || output_size.1 == 0 {
                     error!(
                         "output dimensions cannot be '0'. Received: {:#?}",
@@ -554,15 +1152,25 @@ impl OutputHandler for Daemon {
                     );
                     return;
                 }
-                for wallpaper in self.wallpapers.iter_mut() {
-                    if wallpaper.has_id(output_info.id) {
-                        let (width, height) = (
-                            Some(NonZeroI32::new(output_size.0).unwrap()),
-                            Some(NonZeroI32::new(output_size.1).unwrap()),
-                        );
-                        let scale_factor = Some(NonZeroI32::new(output_info.scale_factor).unwrap());
-                        wallpaper.resize(width, height, scale_factor);
-                        return;
+                if let Some(wallpaper) = self.wallpapers.iter().find(|w| w.has_id(output_info.id)) {
+                    let wallpaper = Arc::clone(wallpaper);
+                    let (width, height) = (
+                        Some(NonZeroI32::new(output_size.0).unwrap()),
+                        Some(NonZeroI32::new(output_size.1).unwrap()),
+                    );
+                    let scale_factor = Some(Scale::Whole(output_info.scale_factor));
+                    let previous = wallpaper.get_img_info();
+                    let transformed = wallpaper
+                        .set_transform(wallpaper::transform_from_wl(output_info.transform));
+                    let resized = wallpaper.resize(width, height, scale_factor);
+                    if transformed || resized {
+                        events::publish(Event::OutputChanged(make_bg_info(
+                            output_info,
+                            &wallpaper,
+                        )));
+                    }
+                    if resized {
+                        self.reapply_wallpaper(&wallpaper, previous);
                     }
                 }
             }
@@ -578,6 +1186,10 @@ impl OutputHandler for Daemon {
         if let Some(output_info) = self.output_state.info(&output) {
             self.wallpapers.retain(|w| !w.has_id(output_info.id));
             debug!("Destroyed output: {output_info:?}");
+            status_changed();
+            events::publish(Event::OutputRemoved(
+                output_info.name.unwrap_or("?".to_string()),
+            ));
         }
     }
 }
@@ -628,6 +1240,7 @@ impl LayerShellHandler for Daemon {
     fn closed(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, layer: &LayerSurface) {
         self.wallpapers
             .retain(|w| !w.has_surface(layer.wl_surface()));
+        status_changed();
     }
 
     fn configure(
@@ -640,12 +1253,30 @@ impl LayerShellHandler for Daemon {
     ) {
         // we only care about output configs, so we just set the wallpaper as having been
         // configured
-        for w in &mut self.wallpapers {
-            if w.has_surface(layer.wl_surface()) {
-                w.configured
-                    .store(true, std::sync::atomic::Ordering::Release);
-                break;
-            }
+        let Some(wallpaper) = self
+            .wallpapers
+            .iter()
+            .find(|w| w.has_surface(layer.wl_surface()))
+        else {
+            return;
+        };
+        if wallpaper
+            .configured
+            .swap(true, std::sync::atomic::Ordering::AcqRel)
+        {
+            return;
+        }
+
+        // this is the first time the output has a size, so we can finally decode its image
+        let img = self.initial_img(wallpaper.output_name());
+        // from now on, `--no-cache` no longer applies to it
+        self.no_cache.retain(|id| !wallpaper.has_id(*id));
+        if let Some(img) = img {
+            self.decode_img(img, |answer| {
+                if let Answer::Err(e) = answer {
+                    error!("failed to display initial image: {e}");
+                }
+            });
         }
     }
 }
@@ -668,6 +1299,36 @@ impl Dispatch<WlBuffer, Arc<AtomicBool>> for Daemon {
     }
 }
 
+impl Dispatch<WpFractionalScaleV1, wl_surface::WlSurface> for Daemon {
+    fn event(
+        state: &mut Self,
+        _proxy: &WpFractionalScaleV1,
+        event: <WpFractionalScaleV1 as wayland_client::Proxy>::Event,
+        surface: &wl_surface::WlSurface,
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        match event {
+            wp_fractional_scale_v1::Event::PreferredScale { scale } => {
+                if let Some(wallpaper) = state.wallpapers.iter().find(|w| w.has_surface(surface)) {
+                    let wallpaper = Arc::clone(wallpaper);
+                    let previous = wallpaper.get_img_info();
+                    let scale = Scale::Fractional(scale as i32);
+                    if wallpaper.resize(None, None, Some(scale)) {
+                        state.reapply_wallpaper(&wallpaper, previous);
+                    }
+                }
+            }
+            e => warn!("Unhandled WpFractionalScaleV1 event: {e:?}"),
+        }
+    }
+}
+
+// these have no events
+wayland_client::delegate_noop!(Daemon: WpViewporter);
+wayland_client::delegate_noop!(Daemon: WpViewport);
+wayland_client::delegate_noop!(Daemon: WpFractionalScaleManagerV1);
+
 delegate_compositor!(Daemon);
 delegate_output!(Daemon);
 delegate_layer!(Daemon);
@@ -680,51 +1341,18 @@ impl ProvidesRegistryState for Daemon {
     registry_handlers![OutputState];
 }
 
-fn make_logger(quiet: bool) {
+/// Logs everything. `load_config` sets the actual level, which we can change at runtime
+fn make_logger() {
     let config = simplelog::ConfigBuilder::new()
         .set_thread_level(LevelFilter::Error) // let me see where the processing is happening
         .set_thread_mode(ThreadLogMode::Both)
         .build();
 
     TermLogger::init(
-        if quiet {
-            LevelFilter::Error
-        } else {
-            LevelFilter::Debug
-        },
+        LevelFilter::Debug,
         config,
         TerminalMode::Stderr,
         ColorChoice::AlwaysAnsi,
     )
     .expect("Failed to initialize logger. Cancelling...");
 }
-
-fn is_daemon_running() -> Result<bool, String> {
-    let proc = std::path::PathBuf::from("/proc");
-
-    let entries = match proc.read_dir() {
-        Ok(e) => e,
-        Err(e) => return Err(e.to_string()),
-    };
-
-    for entry in entries.flatten() {
-        let dirname = entry.file_name();
-        if let Ok(pid) = dirname.to_string_lossy().parse::<u32>() {
-            if std::process::id() == pid {
-                continue;
-            }
-            let mut entry_path = entry.path();
-            entry_path.push("cmdline");
-            if let Ok(cmd) = std::fs::read_to_string(entry_path) {
-                let mut args = cmd.split(&[' ', '\0']);
-                if let Some(arg0) = args.next() {
-                    if arg0.ends_with("swww-daemon") {
-                        return Ok(true);
-                    }
-                }
-            }
-        }
-    }
-
-    Ok(false)
-}
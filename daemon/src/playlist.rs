@@ -0,0 +1,511 @@
+//! Cycling through images by ourselves, at regular intervals.
+//!
+//! Every playlist runs in its own thread, which sleeps until it is time to display the next image,
+//! or until it receives a `Control`. Images are displayed by sending `Request::ImgByPath`s to the
+//! main thread, just like a client using `--daemon-decode` would.
+//!
+//! Like the event subscribers, running playlists are kept in a static. Their state is stored in
+//! the cache directory every time it changes, so that we can resume them after restarting.
+
+use std::{
+    collections::VecDeque,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Mutex,
+    },
+    time::Instant,
+};
+
+use log::{debug, error, warn};
+use rand::{seq::SliceRandom, Rng};
+use utils::{
+    cache::{self, PlaylistState},
+    imgproc::is_image_path,
+    ipc::{Answer, ImgByPath, Playlist, PlaylistCommand, PlaylistOrder, Request},
+    selector::Selection,
+};
+
+/// How many images we remember for `Control::Prev`
+const HISTORY_LEN: usize = 64;
+
+static PLAYLISTS: Mutex<Vec<Running>> = Mutex::new(Vec::new());
+
+/// Playlist ids are never reused, so that a stopped playlist's thread cannot be mistaken for a
+/// newer one
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy)]
+enum Control {
+    Next,
+    Prev,
+    Pause,
+    Resume,
+    Stop,
+}
+
+struct Running {
+    id: u64,
+    state: PlaylistState,
+    controls: Sender<Control>,
+}
+
+/// `selected` returns the names of the outputs some selectors select right now
+pub(super) fn handle(
+    command: PlaylistCommand,
+    requests: &Sender<Request>,
+    selected: impl Fn(&[String]) -> Result<Vec<String>, String>,
+) -> Answer {
+    let (outputs, control) = match command {
+        PlaylistCommand::Start(playlist) => {
+            return match start(playlist, requests.clone(), None, false, &selected) {
+                Ok(()) => Answer::Ok,
+                Err(e) => Answer::Err(e),
+            }
+        }
+        PlaylistCommand::Next(outputs) => (outputs, Control::Next),
+        PlaylistCommand::Prev(outputs) => (outputs, Control::Prev),
+        PlaylistCommand::Pause(outputs) => (outputs, Control::Pause),
+        PlaylistCommand::Resume(outputs) => (outputs, Control::Resume),
+        PlaylistCommand::Stop(outputs) => (outputs, Control::Stop),
+    };
+    if let Err(e) = Selection::parse(&outputs) {
+        return Answer::Err(e);
+    }
+
+    let mut playlists = PLAYLISTS.lock().unwrap();
+    let mut found = false;
+    playlists.retain_mut(|running| {
+        if !overlaps(&running.state.playlist.outputs, &outputs, &selected) {
+            return true;
+        }
+        found = true;
+        match control {
+            Control::Pause => running.state.paused = true,
+            Control::Resume => running.state.paused = false,
+            _ => (),
+        }
+        // if the thread is gone, there is nothing left to control
+        running.controls.send(control).is_ok() && !matches!(control, Control::Stop)
+    });
+    save(&playlists);
+
+    if found {
+        Answer::Ok
+    } else {
+        Answer::Err("there are no playlists running on the requested outputs".to_string())
+    }
+}
+
+/// Resumes the playlists that were running when the daemon last exited.
+///
+/// We do not display anything right away, since the cache already restores the current images.
+pub(super) fn restore(
+    requests: &Sender<Request>,
+    selected: impl Fn(&[String]) -> Result<Vec<String>, String>,
+) {
+    let states = match cache::load_playlists() {
+        Ok(states) => states,
+        Err(e) => {
+            warn!("failed to load playlists: {e}");
+            return;
+        }
+    };
+    for state in states {
+        if let Err(e) = start(
+            state.playlist,
+            requests.clone(),
+            state.current,
+            state.paused,
+            &selected,
+        ) {
+            error!("failed to restore playlist: {e}");
+        }
+    }
+}
+
+/// Starts a new playlist, stopping the ones that were running on any of its outputs. Unless we
+/// are given the `current` image, we display the first one right away
+fn start(
+    playlist: Playlist,
+    requests: Sender<Request>,
+    current: Option<String>,
+    paused: bool,
+    selected: &impl Fn(&[String]) -> Result<Vec<String>, String>,
+) -> Result<(), String> {
+    if playlist.sources.is_empty() {
+        return Err("playlists must have at least one image".to_string());
+    }
+    crate::animations::check_transition(&playlist.transition)?;
+    // the outputs may not be plugged in yet, so we only make sure the selectors are valid
+    Selection::parse(&playlist.outputs)?;
+    let mut playlists = PLAYLISTS.lock().unwrap();
+    playlists.retain(|running| {
+        if overlaps(&running.state.playlist.outputs, &playlist.outputs, selected) {
+            let _ = running.controls.send(Control::Stop);
+            false
+        } else {
+            true
+        }
+    });
+
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let (sender, receiver) = mpsc::channel();
+    let thread_playlist = playlist.clone();
+    let thread_current = current.clone();
+    std::thread::Builder::new()
+        .name("playlist".to_string())
+        .spawn(move || {
+            run(
+                id,
+                thread_playlist,
+                thread_current,
+                receiver,
+                requests,
+                paused,
+            )
+        })
+        .map_err(|e| format!("failed to spawn 'playlist' thread: {e}"))?;
+
+    playlists.push(Running {
+        id,
+        state: PlaylistState {
+            playlist,
+            current,
+            paused,
+        },
+        controls: sender,
+    });
+    save(&playlists);
+    Ok(())
+}
+
+fn run(
+    id: u64,
+    playlist: Playlist,
+    current: Option<String>,
+    controls: Receiver<Control>,
+    requests: Sender<Request>,
+    mut paused: bool,
+) {
+    let show_first = current.is_none();
+    // this scans the directories, so we do it here instead of blocking the main thread
+    let mut cycle = Cycle::new(&playlist, current);
+    if show_first && !show(id, &playlist, cycle.next(), &requests) {
+        return;
+    }
+    let mut deadline = Instant::now() + playlist.interval;
+    loop {
+        let control = if paused {
+            controls.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        } else {
+            controls.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        };
+        let path = match control {
+            Ok(Control::Next) | Err(RecvTimeoutError::Timeout) => cycle.next(),
+            Ok(Control::Prev) => match cycle.prev() {
+                Some(path) => Some(path),
+                None => continue,
+            },
+            Ok(Control::Pause) => {
+                paused = true;
+                continue;
+            }
+            Ok(Control::Resume) => {
+                paused = false;
+                deadline = Instant::now() + playlist.interval;
+                continue;
+            }
+            Ok(Control::Stop) | Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if !show(id, &playlist, path, &requests) {
+            break;
+        }
+        deadline = Instant::now() + playlist.interval;
+    }
+    debug!("playlist {id} stopped");
+}
+
+/// Returns false if the daemon is exiting
+fn show(id: u64, playlist: &Playlist, path: Option<String>, requests: &Sender<Request>) -> bool {
+    let Some(path) = path else {
+        warn!("playlist {id} has no images to display");
+        return true;
+    };
+    let img = ImgByPath {
+        path: path.clone(),
+        outputs: playlist.outputs.clone(),
+        resize: playlist.resize,
+        filter: playlist.filter,
+        fill_color: playlist.fill_color,
+        transition: playlist.transition.clone(),
+    };
+    if requests.send(Request::ImgByPath(img)).is_err() {
+        return false;
+    }
+    crate::wake_poll();
+
+    let mut playlists = PLAYLISTS.lock().unwrap();
+    if let Some(running) = playlists.iter_mut().find(|running| running.id == id) {
+        running.state.current = Some(path);
+        save(&playlists);
+    }
+    true
+}
+
+fn save(playlists: &[Running]) {
+    let states: Vec<PlaylistState> = playlists
+        .iter()
+        .map(|running| PlaylistState {
+            playlist: running.state.playlist.clone(),
+            current: running.state.current.clone(),
+            paused: running.state.paused,
+        })
+        .collect();
+    if let Err(e) = cache::store_playlists(&states) {
+        error!("failed to store playlists: {e}");
+    }
+}
+
+/// Whether two lists of output selectors select any output in common right now. Lists written the
+/// same way always overlap, even if the outputs they select are not plugged in
+fn overlaps(
+    a: &[String],
+    b: &[String],
+    selected: &impl Fn(&[String]) -> Result<Vec<String>, String>,
+) -> bool {
+    if a == b {
+        return true;
+    }
+    match (selected(a), selected(b)) {
+        (Ok(a), Ok(b)) => a.iter().any(|name| b.contains(name)),
+        _ => false,
+    }
+}
+
+/// Decides which image comes next
+struct Cycle {
+    order: PlaylistOrder,
+    sources: Box<[String]>,
+    images: Vec<String>,
+    /// Images not yet displayed in this pass, for `PlaylistOrder::ShuffleNoRepeat`
+    bag: Vec<String>,
+    history: VecDeque<String>,
+    /// Where we are in `history`. Anything after it was displayed before going back with `prev`
+    cursor: usize,
+}
+
+impl Cycle {
+    fn new(playlist: &Playlist, current: Option<String>) -> Self {
+        let mut cycle = Self {
+            order: playlist.order,
+            sources: playlist.sources.clone(),
+            images: Vec::new(),
+            bag: Vec::new(),
+            history: VecDeque::with_capacity(HISTORY_LEN),
+            cursor: 0,
+        };
+        cycle.rescan();
+        if let Some(current) = current {
+            cycle.bag.retain(|img| *img != current);
+            cycle.history.push_back(current);
+        }
+        cycle
+    }
+
+    fn next(&mut self) -> Option<String> {
+        if self.cursor + 1 < self.history.len() {
+            self.cursor += 1;
+            return self.history.get(self.cursor).cloned();
+        }
+
+        let last = self.history.back().cloned();
+        let next = match self.order {
+            PlaylistOrder::Sequential => {
+                let i = last
+                    .and_then(|last| self.images.iter().position(|img| *img == last))
+                    .map_or(0, |i| i + 1);
+                if i >= self.images.len() {
+                    self.rescan();
+                    self.images.first().cloned()
+                } else {
+                    self.images.get(i).cloned()
+                }
+            }
+            PlaylistOrder::Shuffle => {
+                if self.images.is_empty() {
+                    self.rescan();
+                }
+                let mut i = rand::thread_rng().gen_range(0..self.images.len().max(1));
+                // avoid displaying the same image twice in a row
+                if self.images.len() > 1 && self.images.get(i) == last.as_ref() {
+                    i = (i + 1) % self.images.len();
+                }
+                self.images.get(i).cloned()
+            }
+            PlaylistOrder::ShuffleNoRepeat => {
+                if self.bag.is_empty() {
+                    self.rescan();
+                    // the bag is popped from the back, so this would be displayed twice in a row
+                    if self.bag.len() > 1 && self.bag.last() == last.as_ref() {
+                        let last = self.bag.len() - 1;
+                        self.bag.swap(0, last);
+                    }
+                }
+                self.bag.pop()
+            }
+        }?;
+
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(next.clone());
+        self.cursor = self.history.len() - 1;
+        Some(next)
+    }
+
+    fn prev(&mut self) -> Option<String> {
+        if self.cursor == 0 {
+            return None;
+        }
+        self.cursor -= 1;
+        self.history.get(self.cursor).cloned()
+    }
+
+    /// Looks for images in the sources again, so that we pick up new ones
+    fn rescan(&mut self) {
+        self.images.clear();
+        for source in self.sources.iter() {
+            let path = Path::new(source);
+            if path.is_dir() {
+                find_images(path, &mut self.images);
+            } else {
+                self.images.push(source.clone());
+            }
+        }
+        self.images.sort_unstable();
+        self.images.dedup();
+
+        if self.order == PlaylistOrder::ShuffleNoRepeat {
+            self.bag = self.images.clone();
+            self.bag.shuffle(&mut rand::thread_rng());
+        }
+    }
+}
+
+/// Collects every image under `dir`. Symlinked directories are not followed, so that a link
+/// cycle cannot make us walk forever
+fn find_images(dir: &Path, images: &mut Vec<String>) {
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("failed to read directory {dir:?}: {e}");
+                continue;
+            }
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => dirs.push(path),
+                Ok(_) if is_image_path(&path) => images.push(path.to_string_lossy().to_string()),
+                _ => (),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use utils::ipc::{Filter, ResizeStrategy, Transition};
+
+    /// Sources that are not directories are taken as they are, so these need not exist
+    fn cycle(order: PlaylistOrder, sources: &[&str], current: Option<&str>) -> Cycle {
+        let playlist = Playlist {
+            outputs: Box::default(),
+            sources: sources.iter().map(|s| s.to_string()).collect(),
+            interval: Duration::from_secs(60),
+            order,
+            resize: ResizeStrategy::default(),
+            filter: Filter::default(),
+            fill_color: [0, 0, 0],
+            transition: Transition::default(),
+        };
+        Cycle::new(&playlist, current.map(str::to_string))
+    }
+
+    fn take(cycle: &mut Cycle, n: usize) -> Vec<String> {
+        (0..n).map(|_| cycle.next().unwrap()).collect()
+    }
+
+    #[test]
+    fn should_go_through_images_in_order() {
+        let mut c = cycle(PlaylistOrder::Sequential, &["c", "a", "b", "a"], None);
+        assert_eq!(take(&mut c, 5), ["a", "b", "c", "a", "b"]);
+
+        let mut c = cycle(PlaylistOrder::Sequential, &["a", "b", "c"], Some("b"));
+        assert_eq!(take(&mut c, 2), ["c", "a"]);
+    }
+
+    #[test]
+    fn should_go_back_and_forth_through_history() {
+        let mut c = cycle(PlaylistOrder::Sequential, &["a", "b", "c"], None);
+        assert_eq!(c.prev(), None);
+        assert_eq!(take(&mut c, 3), ["a", "b", "c"]);
+        assert_eq!(c.prev().as_deref(), Some("b"));
+        assert_eq!(c.prev().as_deref(), Some("a"));
+        assert_eq!(c.prev(), None);
+        // next replays what we went back over before picking new images
+        assert_eq!(take(&mut c, 3), ["b", "c", "a"]);
+
+        let mut c = cycle(PlaylistOrder::Sequential, &["a", "b"], Some("b"));
+        assert_eq!(c.next().as_deref(), Some("a"));
+        assert_eq!(c.prev().as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn should_only_remember_so_much_history() {
+        let mut c = cycle(PlaylistOrder::Sequential, &["a", "b", "c"], None);
+        take(&mut c, HISTORY_LEN + 10);
+        for _ in 1..HISTORY_LEN {
+            assert!(c.prev().is_some());
+        }
+        assert_eq!(c.prev(), None);
+    }
+
+    #[test]
+    fn should_not_shuffle_the_same_image_twice_in_a_row() {
+        let mut c = cycle(PlaylistOrder::Shuffle, &["a", "b"], Some("a"));
+        let images = take(&mut c, 100);
+        assert_eq!(images[0], "b");
+        assert!(images.windows(2).all(|w| w[0] != w[1]));
+
+        let mut c = cycle(PlaylistOrder::Shuffle, &["a"], None);
+        assert_eq!(take(&mut c, 3), ["a", "a", "a"]);
+    }
+
+    #[test]
+    fn should_show_every_image_once_per_pass_without_repeating() {
+        let sources = ["a", "b", "c", "d", "e"];
+        let mut c = cycle(PlaylistOrder::ShuffleNoRepeat, &sources, Some("c"));
+        // the current image counts as shown in the first pass
+        let mut first = take(&mut c, 4);
+        first.sort();
+        assert_eq!(first, ["a", "b", "d", "e"]);
+
+        let mut last = c.history.back().cloned();
+        for _ in 0..20 {
+            let pass = take(&mut c, sources.len());
+            assert_ne!(pass.first(), last.as_ref());
+            assert!(pass.windows(2).all(|w| w[0] != w[1]));
+            let mut sorted = pass.clone();
+            sorted.sort();
+            assert_eq!(sorted, sources);
+            last = pass.last().cloned();
+        }
+    }
+}
@@ -0,0 +1,279 @@
+//! Changing wallpapers at given times of the day, as configured in the schedule file.
+//!
+//! The schedule is read at startup, and again when we reload. A thread sleeps until the next entry
+//! is due, and then sends its image to the main thread as a `Request::ImgByPath`, just like
+//! playlists do. We check the wall clock at least once a minute, so that suspending the computer
+//! or changing time zones does not make us miss entries.
+//!
+//! Outputs that appear after an entry fired (including every output, when we start) get whatever
+//! the schedule says they should be displaying right now, through `current_img`.
+
+use std::{
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use log::{debug, error, info};
+use serde_json::Value;
+use utils::{
+    config,
+    ipc::{ImgByPath, Request, Transition},
+    schedule::{local_day, Location, TimeOfDay},
+    toml,
+};
+
+/// The longest we sleep without looking at the wall clock again, in seconds
+const MAX_SLEEP: i64 = 60;
+
+const SECS_PER_DAY: i64 = 24 * 60 * 60;
+
+static SCHEDULE: Mutex<Option<Running>> = Mutex::new(None);
+
+struct Running {
+    schedule: Arc<Schedule>,
+    /// Dropping this stops the thread
+    _stop: Sender<()>,
+}
+
+struct Schedule {
+    location: Option<Location>,
+    entries: Vec<Entry>,
+}
+
+struct Entry {
+    at: TimeOfDay,
+    /// An empty `img.outputs` means all outputs
+    img: ImgByPath,
+}
+
+/// Loads the schedule file, if there is one, and starts following it. Stops following the
+/// previous one, if any
+pub(super) fn start(requests: &Sender<Request>) {
+    SCHEDULE.lock().unwrap().take();
+    let Some(path) = config::config_file("schedule.toml") else {
+        return;
+    };
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+        Err(e) => {
+            error!("failed to read schedule {path:?}: {e}");
+            return;
+        }
+    };
+    let schedule = match parse(&contents) {
+        Ok(schedule) => schedule,
+        Err(e) => {
+            error!("failed to load schedule {path:?}: {e}");
+            return;
+        }
+    };
+    if schedule.entries.is_empty() {
+        return;
+    }
+    info!(
+        "loaded {} schedule entries from {path:?}",
+        schedule.entries.len()
+    );
+
+    let schedule = Arc::new(schedule);
+    let (stop, stopped) = mpsc::channel();
+    let thread_schedule = Arc::clone(&schedule);
+    let requests = requests.clone();
+    if let Err(e) = std::thread::Builder::new()
+        .name("scheduler".to_string())
+        .stack_size(1 << 16)
+        .spawn(move || run(&thread_schedule, &stopped, &requests))
+    {
+        error!("failed to spawn 'scheduler' thread: {e}");
+        return;
+    }
+    *SCHEDULE.lock().unwrap() = Some(Running {
+        schedule,
+        _stop: stop,
+    });
+}
+
+/// What the schedule says `output` should be displaying right now, to be shown without a
+/// transition. `selects` tells whether an entry's output selectors select `output`
+pub(super) fn current_img(output: &str, selects: impl Fn(&[String]) -> bool) -> Option<ImgByPath> {
+    let schedule = Arc::clone(&SCHEDULE.lock().unwrap().as_ref()?.schedule);
+    let entry = schedule.active_on(&selects, now())?;
+    Some(ImgByPath {
+        outputs: Box::new([output.to_string()]),
+        transition: Transition {
+            step: u8::MAX,
+            ..Transition::default()
+        },
+        ..entry.img.clone()
+    })
+}
+
+fn run(schedule: &Schedule, stopped: &Receiver<()>, requests: &Sender<Request>) {
+    let mut last = now();
+    loop {
+        let wait = schedule
+            .next_after(last)
+            .map_or(MAX_SLEEP, |next| (next - now()).clamp(0, MAX_SLEEP));
+        if stopped.recv_timeout(Duration::from_secs(wait as u64)) != Err(RecvTimeoutError::Timeout)
+        {
+            debug!("stopped following the schedule");
+            return;
+        }
+
+        let now = now();
+        // after a long suspend, only what should be displayed right now matters
+        let due = schedule.due(last.max(now - SECS_PER_DAY), now);
+        last = now;
+
+        // skip entries that are immediately replaced by later ones
+        let mut shown: Vec<&Entry> = Vec::new();
+        for entry in due.into_iter().rev() {
+            if !shown
+                .iter()
+                .any(|later| covers(&later.img.outputs, &entry.img.outputs))
+            {
+                shown.push(entry);
+            }
+        }
+        for entry in shown.into_iter().rev() {
+            debug!("schedule entry at {} is due", entry.at);
+            if requests
+                .send(Request::ImgByPath(entry.img.clone()))
+                .is_err()
+            {
+                return; // the daemon is exiting
+            }
+            crate::wake_poll();
+        }
+    }
+}
+
+impl Schedule {
+    fn occurrence(&self, entry: &Entry, day: i64) -> Option<i64> {
+        // the offset at noon is the right one for the whole day, except around DST changes
+        let utc_offset = utc_offset(day * SECS_PER_DAY + SECS_PER_DAY / 2);
+        entry.at.on_day(day, utc_offset, self.location.as_ref())
+    }
+
+    /// The entry that last happened at or before `time` on the output `selects` looks for
+    fn active_on(&self, selects: &dyn Fn(&[String]) -> bool, time: i64) -> Option<&Entry> {
+        let today = local_day(time, utc_offset(time));
+        // one of yesterday's entries has always happened, unless they follow a sun that did not
+        // rise or set
+        self.entries
+            .iter()
+            .filter(|entry| entry.img.outputs.is_empty() || selects(&entry.img.outputs))
+            .flat_map(|entry| {
+                (today - 2..=today)
+                    .filter_map(move |day| Some((self.occurrence(entry, day)?, entry)))
+            })
+            .filter(|(occurrence, _)| *occurrence <= time)
+            .max_by_key(|(occurrence, _)| *occurrence)
+            .map(|(_, entry)| entry)
+    }
+
+    /// The first entry happening after `time`
+    fn next_after(&self, time: i64) -> Option<i64> {
+        let today = local_day(time, utc_offset(time));
+        self.entries
+            .iter()
+            .flat_map(|entry| {
+                (today..=today + 2).filter_map(move |day| self.occurrence(entry, day))
+            })
+            .filter(|occurrence| *occurrence > time)
+            .min()
+    }
+
+    /// The entries happening after `from` and at or before `to`, in order
+    fn due(&self, from: i64, to: i64) -> Vec<&Entry> {
+        let first = local_day(from, utc_offset(from)) - 1;
+        let last = local_day(to, utc_offset(to)) + 1;
+        let mut due: Vec<(i64, &Entry)> = self
+            .entries
+            .iter()
+            .flat_map(|entry| {
+                (first..=last).filter_map(move |day| Some((self.occurrence(entry, day)?, entry)))
+            })
+            .filter(|(occurrence, _)| *occurrence > from && *occurrence <= to)
+            .collect();
+        due.sort_by_key(|(occurrence, _)| *occurrence);
+        due.into_iter().map(|(_, entry)| entry).collect()
+    }
+}
+
+/// Whether showing an image on `a` hides everything shown on `b`. Empty slices mean all outputs.
+/// We compare selectors as written, so this may miss some entries we could skip
+fn covers(a: &[String], b: &[String]) -> bool {
+    a.is_empty() || (!b.is_empty() && b.iter().all(|name| a.contains(name)))
+}
+
+fn parse(contents: &str) -> Result<Schedule, String> {
+    let value = toml::parse(contents)?;
+    let location = match (value.get("latitude"), value.get("longitude")) {
+        (None, None) => None,
+        (Some(latitude), Some(longitude)) => Some(Location::new(
+            latitude.as_f64().ok_or("\"latitude\" must be a number")?,
+            longitude.as_f64().ok_or("\"longitude\" must be a number")?,
+        )?),
+        _ => return Err("\"latitude\" and \"longitude\" must be set together".to_string()),
+    };
+
+    let entries = match value.get("entry") {
+        None => &[][..],
+        Some(Value::Array(entries)) => &entries[..],
+        Some(_) => return Err("entries must be given as [[entry]] tables".to_string()),
+    };
+    let mut schedule = Schedule {
+        location,
+        entries: Vec::with_capacity(entries.len()),
+    };
+    for (i, entry) in entries.iter().enumerate() {
+        let entry = parse_entry(entry, schedule.location.as_ref())
+            .map_err(|e| format!("entry {}: {e}", i + 1))?;
+        schedule.entries.push(entry);
+    }
+    Ok(schedule)
+}
+
+fn parse_entry(value: &Value, location: Option<&Location>) -> Result<Entry, String> {
+    let at: TimeOfDay = value
+        .get("at")
+        .and_then(Value::as_str)
+        .ok_or("entries must have an \"at\" string")?
+        .parse()?;
+    if at.needs_location() && location.is_none() {
+        return Err(format!(
+            "'{at}' requires setting \"latitude\" and \"longitude\""
+        ));
+    }
+    let image = value
+        .get("image")
+        .and_then(Value::as_str)
+        .ok_or("entries must have an \"image\" string")?;
+    let path = config::resolve_path(image)?;
+    Ok(Entry {
+        at,
+        img: crate::json_ipc::parse_img_by_path(value, path)?,
+    })
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+/// The local time zone's offset from UTC at `time`, in seconds
+fn utc_offset(time: i64) -> i64 {
+    let time = time as libc::time_t;
+    // SAFETY: an all zeroes `tm` is valid, and `localtime_r` only writes to it
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return 0;
+    }
+    tm.tm_gmtoff as i64
+}
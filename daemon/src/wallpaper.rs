@@ -1,10 +1,11 @@
-use utils::ipc::BgImg;
+use utils::ipc::{BgImg, Event, ImgByPath, Scale, Transform};
 
 use std::{
     num::NonZeroI32,
+    ops::Range,
     sync::{
         atomic::{AtomicBool, AtomicUsize, Ordering},
-        Arc, Condvar, Mutex, RwLock,
+        Arc, Condvar, Mutex, MutexGuard, RwLock,
     },
 };
 
@@ -17,14 +18,25 @@ use smithay_client_toolkit::{
     shm::Shm,
 };
 
-use wayland_client::{protocol::wl_surface::WlSurface, QueueHandle};
+use wayland_client::{
+    protocol::{wl_output, wl_surface::WlSurface},
+    QueueHandle,
+};
+use wayland_protocols::wp::{
+    fractional_scale::v1::client::wp_fractional_scale_v1::WpFractionalScaleV1,
+    viewporter::client::wp_viewport::WpViewport,
+};
 
-use crate::{bump_pool::BumpPool, Daemon};
+use crate::{bump_pool::BumpPool, events, Daemon};
 
 #[derive(Debug)]
 struct AnimationState {
     id: AtomicUsize,
     transition_finished: Arc<AtomicBool>,
+    /// Whether a transition is drawing to this wallpaper. Only used for reporting
+    transitioning: AtomicBool,
+    /// Whether an animation is drawing to this wallpaper. Only used for reporting
+    animating: AtomicBool,
 }
 
 #[derive(Debug)]
@@ -41,15 +53,106 @@ impl AnimationToken {
     pub(super) fn set_transition_done(&self, wallpaper: &Wallpaper) {
         if wallpaper.has_animation_id(self) {
             self.transition_done.store(true, Ordering::Release);
+            wallpaper
+                .animation_state
+                .transitioning
+                .store(false, Ordering::Release);
+            events::publish(Event::TransitionFinished(wallpaper.output_name.clone()));
+        }
+    }
+}
+
+/// A rectangle of a wallpaper's buffer that changed, in buffer coordinates (that is, already
+/// multiplied by the scale factor)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) struct Damage {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Damage {
+    /// Past this many rectangles, we just send their bounding box
+    const MAX_RECTS: usize = 16;
+
+    /// The rows covering `pixels`, in a buffer `width` pixels wide. If they are all in the same row,
+    /// just the columns covering them
+    pub(super) fn from_pixels(pixels: Range<usize>, width: u32) -> Self {
+        let width = width as usize;
+        let (first_row, last_row) = (pixels.start / width, (pixels.end - 1) / width);
+        if first_row == last_row {
+            return Self {
+                x: (pixels.start % width) as u32,
+                y: first_row as u32,
+                width: pixels.len() as u32,
+                height: 1,
+            };
+        }
+        Self {
+            x: 0,
+            y: first_row as u32,
+            width: width as u32,
+            height: (last_row - first_row + 1) as u32,
+        }
+    }
+
+    /// Merges each row's changed columns (`None` for rows that did not change, and otherwise the
+    /// first and last ones that did) into rectangles, one per run of changed rows
+    pub(super) fn from_rows(rows: &[Option<(u32, u32)>]) -> Vec<Self> {
+        let mut rects: Vec<Self> = Vec::new();
+        let mut previous_changed = false;
+        for (y, row) in rows.iter().enumerate() {
+            let Some((first, last)) = *row else {
+                previous_changed = false;
+                continue;
+            };
+            let row = Self {
+                x: first,
+                y: y as u32,
+                width: last - first + 1,
+                height: 1,
+            };
+            match rects.last_mut() {
+                Some(rect) if previous_changed => *rect = rect.union(&row),
+                _ => rects.push(row),
+            }
+            previous_changed = true;
+        }
+        if rects.len() > Self::MAX_RECTS {
+            let bounds = rects.iter().skip(1).fold(rects[0], |a, b| a.union(b));
+            return vec![bounds];
+        }
+        rects
+    }
+
+    fn union(&self, other: &Self) -> Self {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Self {
+            x,
+            y,
+            width: (self.x + self.width).max(other.x + other.width) - x,
+            height: (self.y + self.height).max(other.y + other.height) - y,
         }
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FrameState {
+    /// We committed a frame, and the compositor has not asked for the next one yet
+    Pending,
+    /// We have not drawn anything since the surface was (re)configured, so there is no frame
+    /// callback to wait for
+    Ready,
+    /// The compositor asked for a new frame. This is the time from its frame callback, in
+    /// milliseconds with an undefined base, so it is only good for measuring intervals
+    Done(u32),
+}
+
 struct FrameCallbackHandler {
     cvar: Condvar,
-    /// This time doesn't really mean anything. We don't really use it for frame timing, but we
-    /// store it for the sake of signaling when the compositor emitted the last frame callback
-    time: Mutex<Option<u32>>,
+    state: Mutex<FrameState>,
 }
 
 /// Owns all the necessary information for drawing.
@@ -57,7 +160,12 @@ struct FrameCallbackHandler {
 struct WallpaperInner {
     width: NonZeroI32,
     height: NonZeroI32,
-    scale_factor: NonZeroI32,
+    scale_factor: Scale,
+    /// We leave the buffer transform as normal and draw in the surface's (logical) orientation, so
+    /// the compositor applies every transform, flips included, for us. All the transform changes
+    /// for us is whether the surface's width and height are swapped, which `logical_size` takes
+    /// care of
+    transform: Transform,
 
     pool: BumpPool,
     img: BgImg,
@@ -65,10 +173,17 @@ struct WallpaperInner {
 
 pub(super) struct Wallpaper {
     output_id: u32,
+    output_name: String,
     inner: RwLock<WallpaperInner>,
     layer_surface: LayerSurface,
+    /// Only present if the compositor supports fractional scaling, in which case we use them
+    /// instead of `set_buffer_scale`
+    fractional_scale: Option<(WpViewport, WpFractionalScaleV1)>,
 
     animation_state: AnimationState,
+    /// Where the current image came from, and how it was resized, so that we can decode it again
+    /// if the output changes
+    img_source: Mutex<Option<ImgByPath>>,
     pub configured: AtomicBool,
     qh: QueueHandle<Daemon>,
     frame_callback_handler: FrameCallbackHandler,
@@ -78,25 +193,28 @@ impl Wallpaper {
     pub(crate) fn new(
         output_info: OutputInfo,
         layer_surface: LayerSurface,
+        fractional_scale: Option<(WpViewport, WpFractionalScaleV1)>,
         shm: &Shm,
         qh: &QueueHandle<Daemon>,
     ) -> Self {
-        let (width, height): (NonZeroI32, NonZeroI32) = if let Some(size) = output_info.logical_size
-        {
-            if size.0 == 0 || size.1 == 0 {
-                (256.try_into().unwrap(), 256.try_into().unwrap())
+        let (width, height): (NonZeroI32, NonZeroI32) =
+            if let Some(size) = logical_size(&output_info) {
+                if size.0 == 0 || size.1 == 0 {
+                    (256.try_into().unwrap(), 256.try_into().unwrap())
+                } else {
+                    (size.0.try_into().unwrap(), size.1.try_into().unwrap())
+                }
             } else {
-                (size.0.try_into().unwrap(), size.1.try_into().unwrap())
-            }
-        } else {
-            (256.try_into().unwrap(), 256.try_into().unwrap())
-        };
+                (256.try_into().unwrap(), 256.try_into().unwrap())
+            };
 
-        let scale_factor = NonZeroI32::new(output_info.scale_factor).unwrap();
+        // we only learn about fractional scales once the compositor sends us the preferred one
+        let scale_factor = Scale::Whole(output_info.scale_factor);
+        let transform = transform_from_wl(output_info.transform);
 
         let frame_callback_handler = FrameCallbackHandler {
             cvar: Condvar::new(),
-            time: Mutex::new(Some(0)), // we do not have to wait for the first frame
+            state: Mutex::new(FrameState::Ready),
         };
 
         // Configure the layer surface
@@ -105,33 +223,42 @@ impl Wallpaper {
         layer_surface.set_margin(0, 0, 0, 0);
         layer_surface.set_keyboard_interactivity(KeyboardInteractivity::None);
         layer_surface.set_size(width.get() as u32, height.get() as u32);
-        layer_surface
-            .set_buffer_scale(scale_factor.get() as u32)
-            .unwrap();
+        set_surface_scale(
+            &layer_surface,
+            fractional_scale.as_ref(),
+            scale_factor,
+            width,
+            height,
+        );
         // commit so that the compositor send the initial configuration
         layer_surface.commit();
         layer_surface
             .wl_surface()
             .frame(qh, layer_surface.wl_surface().clone());
 
-        let w = width.get() * scale_factor.get();
-        let h = height.get() * scale_factor.get();
-        let pool = BumpPool::new(w, h, shm, qh);
+        let (w, h) = scale_factor.mul_dim(width.get() as u32, height.get() as u32);
+        let pool = BumpPool::new(w as i32, h as i32, shm, qh);
 
         Self {
             output_id: output_info.id,
+            output_name: output_info.name.unwrap_or("?".to_string()),
             layer_surface,
+            fractional_scale,
             inner: RwLock::new(WallpaperInner {
                 width,
                 height,
                 scale_factor,
+                transform,
                 img: BgImg::Color([0, 0, 0]),
                 pool,
             }),
             animation_state: AnimationState {
                 id: AtomicUsize::new(0),
                 transition_finished: Arc::new(AtomicBool::new(false)),
+                transitioning: AtomicBool::new(false),
+                animating: AtomicBool::new(false),
             },
+            img_source: Mutex::new(None),
             configured: AtomicBool::new(false),
             qh: qh.clone(),
             frame_callback_handler,
@@ -143,6 +270,11 @@ impl Wallpaper {
         self.output_id == id
     }
 
+    #[inline]
+    pub(super) fn output_name(&self) -> &str {
+        &self.output_name
+    }
+
     #[inline]
     pub(super) fn has_animation_id(&self, token: &AnimationToken) -> bool {
         self.animation_state
@@ -160,8 +292,28 @@ impl Wallpaper {
         let inner = self.inner.read().unwrap();
         let width = inner.width.get() as u32;
         let height = inner.height.get() as u32;
-        let scale_factor = inner.scale_factor.get() as u32;
-        (width * scale_factor, height * scale_factor)
+        inner.scale_factor.mul_dim(width, height)
+    }
+
+    #[inline]
+    pub(super) fn get_scale_factor(&self) -> Scale {
+        self.inner.read().unwrap().scale_factor
+    }
+
+    #[inline]
+    pub(super) fn get_transform(&self) -> Transform {
+        self.inner.read().unwrap().transform
+    }
+
+    /// Returns whether the transform actually changed
+    pub(super) fn set_transform(&self, transform: Transform) -> bool {
+        let mut inner = self.inner.write().unwrap();
+        if inner.transform == transform {
+            return false;
+        }
+        log::debug!("output {} - transform: {transform}", self.output_id);
+        inner.transform = transform;
+        true
     }
 
     pub(super) fn canvas_change<F, T>(&self, f: F) -> T
@@ -177,6 +329,16 @@ impl Wallpaper {
         self.inner.read().unwrap().img.clone()
     }
 
+    #[inline]
+    pub(super) fn img_source(&self) -> Option<ImgByPath> {
+        self.img_source.lock().unwrap().clone()
+    }
+
+    #[inline]
+    pub(super) fn set_img_source(&self, source: Option<ImgByPath>) {
+        *self.img_source.lock().unwrap() = source;
+    }
+
     #[inline]
     pub(super) fn create_animation_token(&self) -> AnimationToken {
         let id = self.animation_state.id.load(Ordering::Acquire);
@@ -188,10 +350,28 @@ impl Wallpaper {
 
     #[inline]
     pub(super) fn frame_callback_completed(&self, time: u32) {
-        *self.frame_callback_handler.time.lock().unwrap() = Some(time);
+        *self.frame_callback_handler.state.lock().unwrap() = FrameState::Done(time);
         self.frame_callback_handler.cvar.notify_all();
     }
 
+    /// Blocks until the compositor wants a new frame. Returns the time from the frame callback
+    /// (see `FrameState::Done`), or `None` if there was no callback to wait for
+    pub(super) fn wait_frame(&self) -> Option<u32> {
+        match *self.lock_ready_frame() {
+            FrameState::Done(time) => Some(time),
+            _ => None,
+        }
+    }
+
+    fn lock_ready_frame(&self) -> MutexGuard<'_, FrameState> {
+        let mut state = self.frame_callback_handler.state.lock().unwrap();
+        while *state == FrameState::Pending {
+            log::debug!("waiting for condvar");
+            state = self.frame_callback_handler.cvar.wait(state).unwrap();
+        }
+        state
+    }
+
     /// Stops all animations with the current id, by increasing that id
     #[inline]
     pub(super) fn stop_animations(&self) {
@@ -199,6 +379,41 @@ impl Wallpaper {
         self.animation_state
             .transition_finished
             .store(false, Ordering::Release);
+        self.animation_state
+            .transitioning
+            .store(false, Ordering::Release);
+        self.animation_state
+            .animating
+            .store(false, Ordering::Release);
+    }
+
+    #[inline]
+    pub(super) fn is_transitioning(&self) -> bool {
+        self.animation_state.transitioning.load(Ordering::Acquire)
+    }
+
+    #[inline]
+    pub(super) fn is_animating(&self) -> bool {
+        self.animation_state.animating.load(Ordering::Acquire)
+    }
+
+    /// Marks the transition (if `token` is still current) as running
+    pub(super) fn transition_started(&self, token: &AnimationToken) {
+        if self.has_animation_id(token) {
+            self.animation_state
+                .transitioning
+                .store(true, Ordering::Release);
+            events::publish(Event::TransitionStarted(self.output_name.clone()));
+        }
+    }
+
+    /// Marks the animation (if `token` is still current) as running or stopped
+    pub(super) fn set_animating(&self, token: &AnimationToken, animating: bool) {
+        if self.has_animation_id(token) {
+            self.animation_state
+                .animating
+                .store(animating, Ordering::Release);
+        }
     }
 
     pub(super) fn clear(&self, color: [u8; 3]) {
@@ -211,26 +426,46 @@ impl Wallpaper {
 
     pub(super) fn set_img_info(&self, img_info: BgImg) {
         log::debug!("output {} - drawing: {}", self.output_id, img_info);
-        self.inner.write().unwrap().img = img_info;
+        self.inner.write().unwrap().img = img_info.clone();
+        crate::status_changed();
+        events::publish(Event::ImageChanged {
+            output: self.output_name.clone(),
+            img: img_info,
+        });
     }
 
+    /// Commits the whole buffer
     pub(super) fn draw(&self) {
-        {
-            let mut time = self.frame_callback_handler.time.lock().unwrap();
-            while time.is_none() {
-                log::debug!("waiting for condvar");
-                time = self.frame_callback_handler.cvar.wait(time).unwrap();
-            }
-            *time = None;
-        }
+        let (width, height) = self.get_dimensions();
+        self.draw_damage(&[Damage {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        }]);
+    }
+
+    /// Commits the buffer, telling the compositor only `damage` changed
+    pub(super) fn draw_damage(&self, damage: &[Damage]) {
+        *self.lock_ready_frame() = FrameState::Pending;
         let inner = self.inner.read().unwrap();
         if let Some(buf) = inner.pool.get_commitable_buffer() {
-            let width = inner.width.get() * inner.scale_factor.get();
-            let height = inner.height.get() * inner.scale_factor.get();
             let surface = self.layer_surface.wl_surface();
             surface.attach(Some(buf), 0, 0);
             drop(inner);
-            surface.damage_buffer(0, 0, width, height);
+            for rect in damage {
+                surface.damage_buffer(
+                    rect.x as i32,
+                    rect.y as i32,
+                    rect.width as i32,
+                    rect.height as i32,
+                );
+            }
+            if damage.is_empty() {
+                // the compositor may not send frame callbacks for a surface that does not change,
+                // and we wait for those to draw again
+                surface.damage_buffer(0, 0, 1, 1);
+            }
             surface.commit();
             surface.frame(&self.qh, surface.clone());
         } else {
@@ -242,21 +477,24 @@ impl Wallpaper {
         }
     }
 
+    /// Returns whether the dimensions or the scale factor actually changed.
+    ///
+    /// Whole scale factors are ignored once we have received a fractional one.
     pub(super) fn resize(
         &self,
         width: Option<NonZeroI32>,
         height: Option<NonZeroI32>,
-        scale_factor: Option<NonZeroI32>,
-    ) {
-        if let Some(s) = scale_factor {
-            self.layer_surface.set_buffer_scale(s.get() as u32).unwrap();
-        }
+        scale_factor: Option<Scale>,
+    ) -> bool {
         let mut inner = self.inner.write().unwrap();
         let width = width.unwrap_or(inner.width);
         let height = height.unwrap_or(inner.height);
-        let scale_factor = scale_factor.unwrap_or(inner.scale_factor);
+        let scale_factor = match scale_factor {
+            Some(s) if s.priority() >= inner.scale_factor.priority() => s,
+            _ => inner.scale_factor,
+        };
         if (width, height, scale_factor) == (inner.width, inner.height, inner.scale_factor) {
-            return;
+            return false;
         }
         self.stop_animations();
 
@@ -265,18 +503,132 @@ impl Wallpaper {
         inner.scale_factor = scale_factor;
         inner.img = BgImg::Color([0, 0, 0]);
 
-        let w = width.get() * scale_factor.get();
-        let h = height.get() * scale_factor.get();
-        inner.pool.resize(w, h, &self.qh);
+        let (w, h) = scale_factor.mul_dim(width.get() as u32, height.get() as u32);
+        inner.pool.resize(w as i32, h as i32, &self.qh);
         drop(inner);
 
-        *self.frame_callback_handler.time.lock().unwrap() = Some(0);
+        *self.frame_callback_handler.state.lock().unwrap() = FrameState::Ready;
         self.layer_surface
             .set_size(width.get() as u32, height.get() as u32);
+        set_surface_scale(
+            &self.layer_surface,
+            self.fractional_scale.as_ref(),
+            scale_factor,
+            width,
+            height,
+        );
         self.layer_surface.commit();
         self.layer_surface
             .wl_surface()
             .frame(&self.qh, self.layer_surface.wl_surface().clone());
         self.configured.store(false, Ordering::Release);
+        true
+    }
+}
+
+/// The output's size in surface coordinates. `logical_size` comes from xdg-output, and already has
+/// the transform applied. Without it, we fall back to the current mode, which is in the output's
+/// untransformed orientation
+pub(super) fn logical_size(output_info: &OutputInfo) -> Option<(i32, i32)> {
+    if output_info.logical_size.is_some() {
+        return output_info.logical_size;
+    }
+    let mode = output_info.modes.iter().find(|mode| mode.current)?;
+    Some(surface_size(
+        mode.dimensions,
+        output_info.scale_factor,
+        transform_from_wl(output_info.transform),
+    ))
+}
+
+/// The size, in surface coordinates, of an output whose current mode is `mode`. Quarter turns
+/// (flipped or not) swap its width and height, while flips and half turns keep them
+fn surface_size(mode: (i32, i32), scale: i32, transform: Transform) -> (i32, i32) {
+    let scale = scale.max(1);
+    let (width, height) = (mode.0 / scale, mode.1 / scale);
+    if transform.swaps_dimensions() {
+        (height, width)
+    } else {
+        (width, height)
+    }
+}
+
+pub(super) fn transform_from_wl(transform: wl_output::Transform) -> Transform {
+    match transform {
+        wl_output::Transform::_90 => Transform::_90,
+        wl_output::Transform::_180 => Transform::_180,
+        wl_output::Transform::_270 => Transform::_270,
+        wl_output::Transform::Flipped => Transform::Flipped,
+        wl_output::Transform::Flipped90 => Transform::Flipped90,
+        wl_output::Transform::Flipped180 => Transform::Flipped180,
+        wl_output::Transform::Flipped270 => Transform::Flipped270,
+        _ => Transform::Normal,
+    }
+}
+
+impl Drop for Wallpaper {
+    fn drop(&mut self) {
+        if let Some((viewport, fractional_scale)) = &self.fractional_scale {
+            viewport.destroy();
+            fractional_scale.destroy();
+        }
+    }
+}
+
+/// With fractional scales, our buffer is attached with scale 1 and the viewport scales it back
+/// down to the surface's logical size
+fn set_surface_scale(
+    layer_surface: &LayerSurface,
+    fractional_scale: Option<&(WpViewport, WpFractionalScaleV1)>,
+    scale_factor: Scale,
+    width: NonZeroI32,
+    height: NonZeroI32,
+) {
+    match scale_factor {
+        Scale::Whole(s) => {
+            if let Some((viewport, _)) = fractional_scale {
+                // unset the destination, so that the buffer scale applies again
+                viewport.set_destination(-1, -1);
+            }
+            layer_surface.set_buffer_scale(s as u32).unwrap();
+        }
+        Scale::Fractional(_) => {
+            // we can only receive fractional scales if we have the viewport
+            if let Some((viewport, _)) = fractional_scale {
+                layer_surface.set_buffer_scale(1).unwrap();
+                viewport.set_destination(width.get(), height.get());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_size_surfaces_for_every_transform() {
+        use wl_output::Transform as Wl;
+        let transforms = [
+            (Wl::Normal, (1920, 1080)),
+            (Wl::_90, (1080, 1920)),
+            (Wl::_180, (1920, 1080)),
+            (Wl::_270, (1080, 1920)),
+            (Wl::Flipped, (1920, 1080)),
+            (Wl::Flipped90, (1080, 1920)),
+            (Wl::Flipped180, (1920, 1080)),
+            (Wl::Flipped270, (1080, 1920)),
+        ];
+        let mut seen = Vec::new();
+        for (wl, size) in transforms {
+            let transform = transform_from_wl(wl);
+            assert!(!seen.contains(&transform), "{wl:?} maps to {transform}");
+            seen.push(transform);
+            assert_eq!(
+                surface_size((3840, 2160), 2, transform),
+                size,
+                "{transform}"
+            );
+        }
     }
 }
@@ -1,6 +1,6 @@
 /// Note: this file only has basic declarations and some definitions in order to be possible to
 /// import it in the build script, to automate shell completion
-use clap::{Parser, ValueEnum};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use std::path::PathBuf;
 
 fn from_hex(hex: &str) -> Result<[u8; 3], String> {
@@ -74,50 +74,76 @@ impl std::str::FromStr for Filter {
     }
 }
 
+/// Shorthands for effects in `transitions::TRANSITIONS` with some of their options preset
+const TRANSITION_ALIASES: &[(&str, &str)] = &[
+    (
+        "none",
+        "simple, with --transition-step 255, so it finishes instantly",
+    ),
+    ("left", "wipe, from the left"),
+    ("right", "wipe, from the right"),
+    ("top", "wipe, from the top"),
+    ("bottom", "wipe, from the bottom"),
+    ("center", "grow, from the center of the screen"),
+    ("any", "grow or outer, from a random point on screen"),
+    ("random", "any effect, at a random angle and position"),
+];
+
 #[derive(Clone)]
 pub enum TransitionType {
     None,
-    Simple,
-    Fade,
     Left,
     Right,
     Top,
     Bottom,
     Center,
-    Outer,
     Any,
     Random,
-    Wipe,
-    Wave,
-    Grow,
+    /// One of the effects in `transitions::TRANSITIONS`
+    Effect(&'static str),
 }
 
 impl std::str::FromStr for TransitionType {
-    type Err = &'static str;
+    type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "none" => Ok(Self::None),
-            "simple" => Ok(Self::Simple),
             "left" => Ok(Self::Left),
             "right" => Ok(Self::Right),
             "top" => Ok(Self::Top),
             "bottom" => Ok(Self::Bottom),
-            "wipe" => Ok(Self::Wipe),
-            "grow" => Ok(Self::Grow),
             "center" => Ok(Self::Center),
-            "outer" => Ok(Self::Outer),
             "any" => Ok(Self::Any),
-            "wave" => Ok(Self::Wave),
             "random" => Ok(Self::Random),
-            "fade" => Ok(Self::Fade),
-            _ => Err("unrecognized transition type.\nValid transitions are:\n\
-                     \tsimple | fade | left | right | top | bottom | wipe | grow | center | outer | random | wave\n\
-                     see swww img --help for more details"),
+            _ => match crate::transitions::find(s) {
+                Some(t) => Ok(Self::Effect(t.name)),
+                None => {
+                    let aliases: Vec<&str> = TRANSITION_ALIASES.iter().map(|a| a.0).collect();
+                    Err(format!(
+                        "unrecognized transition type.\nValid transitions are:\n\t{} | {}\n\
+                         see swww img --help for more details",
+                        crate::transitions::names(),
+                        aliases.join(" | ")
+                    ))
+                }
+            },
         }
     }
 }
 
+/// Parses `--transition-type`, listing the effects and their aliases as its possible values
+fn transition_type_parser() -> impl clap::builder::TypedValueParser<Value = TransitionType> {
+    use clap::builder::{PossibleValue, PossibleValuesParser, TypedValueParser};
+    let effects = crate::transitions::TRANSITIONS
+        .iter()
+        .map(|t| PossibleValue::new(t.name).help(t.help));
+    let aliases = TRANSITION_ALIASES
+        .iter()
+        .map(|&(name, help)| PossibleValue::new(name).help(help));
+    PossibleValuesParser::new(effects.chain(aliases)).try_map(|s| s.parse::<TransitionType>())
+}
+
 #[derive(Clone)]
 pub enum CliCoord {
     Percent(f32),
@@ -137,8 +163,26 @@ impl CliPosition {
     }
 }
 
+///The `--socket` option, shared by every subcommand.
+///
+///It is not part of `Swww` because that is an enum. We read it straight from the matches instead.
+fn socket_arg() -> clap::Arg {
+    clap::Arg::new("socket")
+        .long("socket")
+        .env("SWWW_SOCKET")
+        .value_name("PATH")
+        .value_parser(clap::value_parser!(PathBuf))
+        .global(true)
+        .help("The daemon's socket. Defaults to $XDG_RUNTIME_DIR/swww.socket")
+        .long_help(
+            "The daemon's socket. Defaults to $XDG_RUNTIME_DIR/swww.socket\n\n\
+            Use different sockets to run one daemon per wayland session, such as in nested \
+            compositors. `swww init` passes it on to the daemon.",
+        )
+}
+
 #[derive(Parser)]
-#[command(version, name = "swww")]
+#[command(version, name = "swww", arg(socket_arg()))]
 ///A Solution to your Wayland Wallpaper Woes
 ///
 ///Change what your monitors display as a background by controlling the swww daemon at runtime.
@@ -159,9 +203,16 @@ pub enum Swww {
     ///Clears the swww cache.
     ///
     ///We currently store the address of the last file set as wallpaper for each monitor, as well
-    ///as the animation frames of every gif ever set for a given version of `swww`.
+    ///as the animation frames of recently used gifs. See `swww cache` to only remove some of them.
     ClearCache,
 
+    ///Inspects and trims the swww cache.
+    ///
+    ///The cache evicts the least recently used animations by itself once it grows over the
+    ///`[cache]` table's `max_size` in the configuration file (in MiB, 512 by default).
+    #[command(subcommand)]
+    Cache(Cache),
+
     /// Sends an image (or animated gif) for the daemon to display.
     ///
     /// Use `-` to read from stdin
@@ -169,8 +220,7 @@ pub enum Swww {
 
     /// Initializes the daemon.
     ///
-    /// Exits if there is already a daemon running. We check that by seeing if
-    /// $XDG_RUNTIME_DIR/swww.socket exists.
+    /// Exits if there is already a daemon listening at the socket.
     Init {
         ///Don't fork the daemon. This will keep it running in the current terminal.
         ///
@@ -179,7 +229,7 @@ pub enum Swww {
         #[clap(long)]
         no_daemon: bool,
 
-        ///Don't load the cache *during initialization* (it still loads on monitor (re)connection)
+        ///Don't load the cache. This passes `--no-cache` to `swww-daemon`
         ///
         ///If want to always pass an image for `swww` to load, this option can help make the
         ///results some reliable: `swww init --no-cache && swww img <some img>`
@@ -202,7 +252,136 @@ pub enum Swww {
     ///
     ///You may use this to find out valid values for the <swww-img --outputs> option. If you want
     ///more detailed information about your outputs, I would recommend trying wlr-randr.
-    Query,
+    Query {
+        ///Print the outputs as json, with a stable set of fields. See `man swww-query`
+        #[clap(long)]
+        json: bool,
+    },
+
+    ///Prints events from the daemon as they happen, until it exits.
+    ///
+    ///Events are: outputs being added, changed or removed; images changing; transitions starting
+    ///and finishing; and animations stopping. Use this instead of polling `swww query`.
+    Subscribe {
+        ///Print each event as a json object, one per line
+        #[clap(long)]
+        json: bool,
+    },
+
+    ///Makes the daemon cycle through images by itself, at regular intervals.
+    ///
+    ///Playlists keep running (even across daemon restarts) until they are stopped, or until
+    ///another playlist is started on the same outputs.
+    #[command(subcommand)]
+    Playlist(Playlist),
+}
+
+#[derive(Subcommand)]
+pub enum Cache {
+    /// Lists the files in the cache, least recently used first
+    List,
+
+    /// Removes files from older versions of swww, then the least recently used animations,
+    /// until the cache fits in its maximum size
+    Prune {
+        /// The size to fit in, in MiB, instead of the configuration's `max_size`
+        #[arg(long)]
+        max_size: Option<u64>,
+    },
+
+    /// Prints how much space the cache takes
+    Stats,
+}
+
+#[derive(Subcommand)]
+pub enum Playlist {
+    /// Starts a new playlist, replacing the playlists running on the same outputs
+    Start(PlaylistStart),
+
+    /// Displays the next image right away
+    Next(PlaylistOutputs),
+
+    /// Goes back to the previously displayed image
+    Prev(PlaylistOutputs),
+
+    /// Stops switching images until the playlist is resumed
+    Pause(PlaylistOutputs),
+
+    /// Resumes a paused playlist. The next image comes after a full interval
+    Resume(PlaylistOutputs),
+
+    /// Stops the playlist, leaving the current image on screen
+    Stop(PlaylistOutputs),
+}
+
+#[derive(Parser)]
+pub struct PlaylistOutputs {
+    /// Comma separated list of outputs whose playlists to control.
+    ///
+    /// Besides names, entries may be selectors, such as `DP-*`, `desc:*U2720Q*`, `make:<glob>`,
+    /// `model:<glob>`, `index:<n>` or `!<selector>`. See `man swww-img`.
+    ///
+    /// If it isn't set, all playlists are affected.
+    #[arg(short, long, default_value = "")]
+    pub outputs: String,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PlaylistOrder {
+    #[default]
+    /// Go through the images in alphabetical order
+    Sequential,
+    /// Pick images at random. An image may come up again before all the others were displayed
+    Shuffle,
+    /// Pick images at random, but only repeat them once all the others were displayed
+    ShuffleNoRepeat,
+}
+
+#[derive(Parser)]
+pub struct PlaylistStart {
+    /// Images or directories to cycle through.
+    ///
+    /// Directories are searched recursively, and searched again every time we have gone through
+    /// all of their images, so new images are picked up automatically.
+    #[arg(required = true)]
+    pub paths: Vec<PathBuf>,
+
+    /// Comma separated list of outputs to run the playlist on.
+    ///
+    /// Besides names, entries may be selectors, such as `DP-*`, `desc:*U2720Q*`, `make:<glob>`,
+    /// `model:<glob>`, `index:<n>` or `!<selector>`. See `man swww-img`.
+    ///
+    /// If it isn't set, the playlist runs on all outputs.
+    #[arg(short, long, default_value = "")]
+    pub outputs: String,
+
+    /// How long to display each image for, in seconds
+    #[arg(
+        short,
+        long,
+        default_value = "300",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub interval: u64,
+
+    /// In which order to go through the images
+    #[arg(long, default_value = "sequential")]
+    pub order: PlaylistOrder,
+
+    /// Whether to resize the images and the method by which to resize them
+    #[arg(long, default_value = "crop")]
+    pub resize: ResizeStrategy,
+
+    /// Which color to fill the padding with when output image does not fill screen
+    #[arg(value_parser = from_hex, long, default_value = "000000")]
+    pub fill_color: [u8; 3],
+
+    /// Filter to use when scaling images (see swww img --help for the options)
+    #[arg(short, long, default_value = "Lanczos3")]
+    pub filter: Filter,
+
+    #[command(flatten)]
+    pub transition: Transition,
 }
 
 #[derive(Parser)]
@@ -215,6 +394,9 @@ pub struct Clear {
 
     /// Comma separated list of outputs to display the image at.
     ///
+    /// Besides names, entries may be selectors, such as `DP-*`, `desc:*U2720Q*`, `make:<glob>`,
+    /// `model:<glob>`, `index:<n>` or `!<selector>`. See `man swww-img`.
+    ///
     /// If it isn't set, the image is displayed on all outputs.
     #[clap(short, long, default_value = "")]
     pub outputs: String,
@@ -239,6 +421,9 @@ pub enum ResizeStrategy {
 pub struct Restore {
     /// Comma separated list of outputs to restore.
     ///
+    /// Besides names, entries may be selectors, such as `DP-*`, `desc:*U2720Q*`, `make:<glob>`,
+    /// `model:<glob>`, `index:<n>` or `!<selector>`. See `man swww-img`.
+    ///
     /// If it isn't set, all outputs will be restored.
     #[arg(short, long, default_value = "")]
     pub outputs: String,
@@ -251,6 +436,9 @@ pub struct Img {
 
     /// Comma separated list of outputs to display the image at.
     ///
+    /// Besides names, entries may be selectors, such as `DP-*`, `desc:*U2720Q*`, `make:<glob>`,
+    /// `model:<glob>`, `index:<n>` or `!<selector>`. See `man swww-img`.
+    ///
     /// If it isn't set, the image is displayed on all outputs.
     #[arg(short, long, default_value = "")]
     pub outputs: String,
@@ -276,6 +464,22 @@ pub struct Img {
     #[arg(value_parser = from_hex, long, default_value = "000000")]
     pub fill_color: [u8; 3],
 
+    /// Let the daemon decode and resize the image, instead of doing it in the client.
+    ///
+    /// This avoids sending the resized image through the socket. It does not work when reading
+    /// from stdin.
+    #[arg(long)]
+    pub daemon_decode: bool,
+
+    /// Span the image across all the selected outputs, following their positions, instead of
+    /// displaying the whole image on each of them.
+    ///
+    /// The image is resized (according to --resize) to cover the bounding box of the outputs, and
+    /// each output displays its own piece of it. Gaps between the outputs hide the parts of the
+    /// image behind them. This does not work with animated images.
+    #[arg(long, conflicts_with = "daemon_decode")]
+    pub span: bool,
+
     ///Filter to use when scaling images (run swww img --help to see options).
     ///
     ///Available options are:
@@ -293,12 +497,15 @@ pub struct Img {
     #[arg(short, long, default_value = "Lanczos3")]
     pub filter: Filter,
 
+    #[command(flatten)]
+    pub transition: Transition,
+}
+
+/// How to transition from the current image to the new one
+#[derive(Args)]
+pub struct Transition {
     ///Sets the type of transition. Default is 'simple', that fades into the new image
     ///
-    ///Possible transitions are:
-    ///
-    ///none | simple | fade | left | right | top | bottom | wipe | wave | grow | center | any | outer | random
-    ///
     ///The 'left', 'right', 'top' and 'bottom' options make the transition happen from that
     ///position to its opposite in the screen.
     ///
@@ -320,8 +527,14 @@ pub struct Img {
     ///
     ///'outer' is the same as grow but the circle shrinks instead of growing.
     ///
-    ///Finally, 'random' will select a transition effect at random
-    #[arg(short, long, env = "SWWW_TRANSITION", default_value = "simple")]
+    ///Finally, 'random' will select a transition effect at random, out of all of them
+    #[arg(
+        short,
+        long,
+        env = "SWWW_TRANSITION",
+        default_value = "simple",
+        value_parser = transition_type_parser()
+    )]
     pub transition_type: TransitionType,
 
     ///How fast the transition approaches the new image.
@@ -347,16 +560,18 @@ pub struct Img {
     #[arg(long, env = "SWWW_TRANSITION_DURATION", default_value = "3")]
     pub transition_duration: f32,
 
-    ///Frame rate for the transition effect.
+    ///Maximum frame rate for the transition effect.
     ///
-    ///Note there is no point in setting this to a value smaller than what your monitor supports.
+    ///Transitions draw a frame whenever the compositor asks for one, which follows your monitor's
+    ///refresh rate. Set this to draw less often, to save some power. 0 means no limit.
     ///
     ///Also note this is **different** from the transition-step. That one controls by how much we
     ///approach the new image every frame.
-    #[arg(long, env = "SWWW_TRANSITION_FPS", default_value = "30")]
+    #[arg(long, env = "SWWW_TRANSITION_FPS", default_value = "0")]
     pub transition_fps: u16,
 
-    ///This is used for the 'wipe' and 'wave' transitions. It controls the angle of the wipe
+    ///This is used for the 'wipe', 'wave', 'slide' and 'push' transitions. It controls the angle
+    ///of the wipe, or which way the images move
     ///
     ///Note that the angle is in degrees, where '0' is right to left and '90' is top to bottom, and '270' bottom to top
     #[arg(long, env = "SWWW_TRANSITION_ANGLE", default_value = "45")]
@@ -388,6 +603,15 @@ pub struct Img {
     ///currently only used for 'wave' transition to control the width and height of each wave
     #[arg(long, env = "SWWW_TRANSITION_WAVE", default_value = "20,20", value_parser = parse_wave)]
     pub transition_wave: (f32, f32),
+
+    ///How many slats the 'horizontal-blinds' and 'vertical-blinds' transitions have
+    #[arg(
+        long,
+        env = "SWWW_TRANSITION_SLATS",
+        default_value = "8",
+        value_parser = clap::value_parser!(u16).range(1..)
+    )]
+    pub transition_slats: u16,
 }
 
 fn parse_wave(raw: &str) -> Result<(f32, f32), String> {
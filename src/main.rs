@@ -1,9 +1,19 @@
-use clap::Parser;
-use std::{os::unix::net::UnixStream, path::PathBuf, process::Stdio, time::Duration};
+use clap::{CommandFactory, FromArgMatches};
+use serde_json::{json, Value};
+use std::{
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    process::Stdio,
+    time::{Duration, SystemTime},
+};
 
 use utils::{
     cache,
-    ipc::{self, get_socket_path, read_socket, AnimationRequest, Answer, Request},
+    config::Config,
+    imgproc::{compress_frames, img_resize_mmap, img_span, Image, ImgBuf, SpanOutput},
+    ipc::{self, get_socket_path, read_socket, AnimationRequest, Answer, Capabilities, Request},
+    selector::Selection,
+    transitions,
 };
 
 mod imgproc;
@@ -13,43 +23,37 @@ mod cli;
 use cli::{ResizeStrategy, Swww};
 
 fn main() -> Result<(), String> {
-    let swww = Swww::parse();
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("WARNING: ignoring configuration file: {e}");
+        Config::new()
+    });
+    let swww = parse_args(config.defaults());
     if let Swww::Init {
-        no_daemon, format, ..
+        no_daemon,
+        no_cache,
+        format,
     } = &swww
     {
         eprintln!(
             "DEPRECATION WARNING: `swww init` IS DEPRECATED. Call `swww-daemon` directly instead"
         );
-        match is_daemon_running() {
-            Ok(false) => {
-                let socket_path = get_socket_path();
-                if socket_path.exists() {
-                    eprintln!(
-                        "WARNING: socket file {} was not deleted when the previous daemon exited",
-                        socket_path.to_string_lossy()
-                    );
-                    if let Err(e) = std::fs::remove_file(socket_path) {
-                        return Err(format!("failed to delete previous socket: {e}"));
-                    }
-                }
-            }
-            Ok(true) => {
-                return Err("There seems to already be another instance running...".to_string())
-            }
-            Err(e) => {
-                eprintln!("WARNING: failed to read '/proc' directory to determine whether the daemon is running: {e}
-                          Falling back to trying to checking if the socket file exists...");
-                let socket_path = get_socket_path();
-                if socket_path.exists() {
-                    return Err(format!(
-                        "Found socket at {}. There seems to be an instance already running...",
-                        socket_path.to_string_lossy()
-                    ));
-                }
+        let socket_path = get_socket_path();
+        if ipc::is_daemon_listening(&socket_path) {
+            return Err(format!(
+                "There is already a daemon listening at {}",
+                socket_path.to_string_lossy()
+            ));
+        }
+        if socket_path.exists() {
+            eprintln!(
+                "WARNING: socket file {} was not deleted when the previous daemon exited",
+                socket_path.to_string_lossy()
+            );
+            if let Err(e) = std::fs::remove_file(socket_path) {
+                return Err(format!("failed to delete previous socket: {e}"));
             }
         }
-        spawn_daemon(*no_daemon, format)?;
+        spawn_daemon(*no_daemon, *no_cache, format)?;
         if *no_daemon {
             return Ok(());
         }
@@ -58,13 +62,17 @@ fn main() -> Result<(), String> {
     if let Swww::ClearCache = &swww {
         return cache::clean();
     }
+    if let Swww::Cache(command) = &swww {
+        return cache_command(command, &config);
+    }
 
+    check_daemon(&swww)?;
     let mut configured = false;
     while !configured {
         let socket = connect_to_socket(5, 100)?;
         Request::Ping.send(&socket)?;
-        let bytes = read_socket(&socket)?;
-        let answer = Answer::receive(&bytes);
+        let msg = read_socket(&socket)?;
+        let answer = Answer::receive(&msg)?;
         if let Answer::Ping(c) = answer {
             configured = c;
         } else {
@@ -73,23 +81,143 @@ fn main() -> Result<(), String> {
         std::thread::sleep(Duration::from_millis(1));
     }
 
-    process_swww_args(&swww)?;
+    match &swww {
+        Swww::Img(img) if !img.span && config.outputs().next().is_some() => {
+            img_with_output_settings(img, &config)
+        }
+        _ => process_swww_args(&swww, &config),
+    }
+}
 
+/// Asks the daemon for its version and capabilities before sending it anything, so that we can
+/// tell whether it will understand us
+fn check_daemon(swww: &Swww) -> Result<(), String> {
+    let socket = connect_to_socket(5, 100)?;
+    let daemon = ipc::handshake(&socket)?;
+    let needs = match swww {
+        Swww::Subscribe { .. } => Some((Capabilities::SUBSCRIBE, "subscribing to events")),
+        Swww::Playlist(_) => Some((Capabilities::PLAYLIST, "playlists")),
+        Swww::Img(img) if img.daemon_decode => {
+            Some((Capabilities::DAEMON_DECODE, "--daemon-decode"))
+        }
+        _ => None,
+    };
+    if let Some((capability, feature)) = needs {
+        if !daemon.capabilities.contains(capability) {
+            return Err(format!(
+                "swww-daemon does not support {feature}. Try updating it"
+            ));
+        }
+    }
+    if daemon.version != ipc::PROTOCOL_VERSION {
+        return Err(format!(
+            "swww-daemon uses protocol version {}, but this client uses version {}. Make sure swww \
+             and swww-daemon are the same version",
+            daemon.version,
+            ipc::PROTOCOL_VERSION
+        ));
+    }
     Ok(())
 }
 
-fn process_swww_args(args: &Swww) -> Result<(), String> {
-    let request = match make_request(args)? {
-        Some(request) => request,
-        None => return Ok(()),
+/// Parses the command line. The configuration file's `settings` become the default values of the
+/// flags used to display images, so that both environment variables and flags override them
+fn parse_args(settings: &Value) -> Swww {
+    let mut command = Swww::command();
+    if let Value::Object(settings) = settings {
+        let set_defaults = |mut command: clap::Command| {
+            for (key, value) in settings.iter().filter(|(key, _)| *key != "image") {
+                let value = match value {
+                    Value::String(s) => s.clone(),
+                    value => value.to_string(),
+                };
+                command = command.mut_arg(key, |arg| arg.default_value(value));
+            }
+            command
+        };
+        command = command
+            .mut_subcommand("img", set_defaults)
+            .mut_subcommand("playlist", |playlist| {
+                playlist.mut_subcommand("start", set_defaults)
+            });
+    }
+    let matches = command.get_matches();
+    if let Some(socket) = matches.get_one::<PathBuf>("socket") {
+        // `get_socket_path` reads it, and so does the daemon, if we spawn it
+        std::env::set_var(ipc::SOCKET_ENV, socket);
+    }
+    Swww::from_arg_matches(&matches).unwrap_or_else(|e| e.exit())
+}
+
+/// Sends `swww img` requests when the configuration file has settings for specific outputs. The
+/// requested outputs are grouped by the settings they use, with one request per group
+fn img_with_output_settings(img: &cli::Img, config: &Config) -> Result<(), String> {
+    let infos = query_outputs()?;
+    let outputs = select_outputs(&infos, &split_cmdline_outputs(&img.outputs))?
+        .into_iter()
+        .map(|info| info.name.clone());
+
+    // outputs without settings of their own share the defaults
+    let mut groups: Vec<(Option<&str>, Vec<String>)> = Vec::new();
+    for output in outputs {
+        let settings = config.outputs().find(|name| *name == output);
+        match groups.iter_mut().find(|(s, _)| *s == settings) {
+            Some((_, names)) => names.push(output),
+            None => groups.push((settings, vec![output])),
+        }
+    }
+    // the image is read only once, since standard input cannot be read again for the next group
+    let imgbuf = if img.daemon_decode {
+        None
+    } else {
+        Some(ImgBuf::new(&img.path)?)
     };
+    for (settings, names) in groups {
+        let mut swww = match settings {
+            Some(output) => parse_args(&config.for_output(output)),
+            None => parse_args(config.defaults()),
+        };
+        if let Swww::Img(img) = &mut swww {
+            img.outputs = names.join(",");
+        }
+        match (&swww, &imgbuf) {
+            (Swww::Img(img), Some(imgbuf)) => {
+                if let Some(request) = make_img_request_from(img, imgbuf, config)? {
+                    send_request(&swww, request)?;
+                }
+            }
+            _ => process_swww_args(&swww, config)?,
+        }
+    }
+    Ok(())
+}
+
+fn process_swww_args(args: &Swww, config: &Config) -> Result<(), String> {
+    if let Swww::Subscribe { json } = args {
+        return subscribe(*json);
+    }
+    match make_request(args, config)? {
+        Some(request) => send_request(args, request),
+        None => Ok(()),
+    }
+}
+
+/// Sends `request` to the daemon, and handles its answer
+fn send_request(args: &Swww, request: Request) -> Result<(), String> {
     let socket = connect_to_socket(5, 100)?;
     request.send(&socket)?;
-    let bytes = read_socket(&socket)?;
+    let msg = read_socket(&socket)?;
     drop(socket);
-    match Answer::receive(&bytes) {
+    match Answer::receive(&msg)? {
         Answer::Err(msg) => return Err(msg.to_string()),
-        Answer::Info(info) => info.iter().for_each(|i| println!("{}", i)),
+        Answer::Info(info) => {
+            if let Swww::Query { json: true } = args {
+                let outputs: Vec<Value> = info.iter().map(ipc::BgInfo::to_json).collect();
+                println!("{}", json!({ "outputs": outputs }));
+            } else {
+                info.iter().for_each(|i| println!("{}", i));
+            }
+        }
         Answer::Ok => {
             if let Swww::Kill = args {
                 #[cfg(debug_assertions)]
@@ -111,11 +239,35 @@ fn process_swww_args(args: &Swww) -> Result<(), String> {
         Answer::Ping(_) => {
             return Ok(());
         }
+        Answer::Event(_) => return Err("Daemon sent an event we did not subscribe to".to_string()),
     }
     Ok(())
 }
 
-fn make_request(args: &Swww) -> Result<Option<Request>, String> {
+fn subscribe(json: bool) -> Result<(), String> {
+    let socket = connect_to_socket(5, 100)?;
+    Request::Subscribe.send(&socket)?;
+    let msg = read_socket(&socket)?;
+    if let Answer::Err(e) = Answer::receive(&msg)? {
+        return Err(format!("daemon refused subscription: {e}"));
+    }
+
+    // events may take arbitrarily long to happen
+    if let Err(e) = socket.set_read_timeout(None) {
+        return Err(format!("failed to unset read timeout for socket: {e}"));
+    }
+    loop {
+        let msg = read_socket(&socket).map_err(|e| format!("lost connection to daemon: {e}"))?;
+        match Answer::receive(&msg)? {
+            Answer::Event(event) if json => println!("{}", event.to_json()),
+            Answer::Event(event) => println!("{event}"),
+            Answer::Err(e) => return Err(e),
+            _ => return Err("Daemon sent something other than an event".to_string()),
+        }
+    }
+}
+
+fn make_request(args: &Swww, config: &Config) -> Result<Option<Request>, String> {
     match args {
         Swww::Clear(c) => {
             let (format, _, _) = get_format_dims_and_outputs(&[])?;
@@ -134,43 +286,210 @@ fn make_request(args: &Swww) -> Result<Option<Request>, String> {
             Ok(None)
         }
         Swww::ClearCache => unreachable!("there is no request for clear-cache"),
-        Swww::Img(img) => {
-            let requested_outputs = split_cmdline_outputs(&img.outputs);
-            let (format, dims, outputs) = get_format_dims_and_outputs(&requested_outputs)?;
-            let imgbuf = ImgBuf::new(&img.path)?;
-            if imgbuf.is_animated() {
-                let animations = {
-                    let first_frame = imgbuf.decode(format)?;
-                    let img_request = make_img_request(img, first_frame, &dims, &outputs)?;
-                    let animations = make_animation_request(img, &imgbuf, &dims, format, &outputs);
-
-                    let socket = connect_to_socket(5, 100)?;
-                    Request::Img(img_request).send(&socket)?;
-                    let bytes = read_socket(&socket)?;
-                    drop(socket);
-                    if let Answer::Err(e) = Answer::receive(&bytes) {
-                        return Err(format!("daemon error when sending image: {e}"));
-                    }
-                    animations
+        Swww::Cache(_) => unreachable!("there is no request for cache"),
+        Swww::Img(img) if img.daemon_decode => {
+            send_img_by_path(img)?;
+            Ok(None)
+        }
+        Swww::Img(img) => make_img_request_from(img, &ImgBuf::new(&img.path)?, config),
+        // the daemon restores the cache by itself
+        Swww::Init { .. } => Ok(None),
+        Swww::Kill => Ok(Some(Request::Kill)),
+        Swww::Query { .. } => Ok(Some(Request::Query)),
+        Swww::Subscribe { .. } => unreachable!("subscriptions are handled in `subscribe`"),
+        Swww::Playlist(playlist) => {
+            send_playlist_command(playlist)?;
+            Ok(None)
+        }
+    }
+}
+
+/// Makes the request for `swww img`, out of the already read image
+fn make_img_request_from(
+    img: &cli::Img,
+    imgbuf: &ImgBuf,
+    config: &Config,
+) -> Result<Option<Request>, String> {
+    if img.span {
+        let requested_outputs = split_cmdline_outputs(&img.outputs);
+        let (format, outputs) = get_format_and_span_outputs(&requested_outputs)?;
+        if imgbuf.is_animated() {
+            return Err("cannot span animated images across outputs".to_string());
+        }
+        let img_raw = imgbuf.decode(format)?;
+        Ok(Some(Request::Img(make_span_img_request(
+            img, img_raw, &outputs,
+        )?)))
+    } else {
+        let requested_outputs = split_cmdline_outputs(&img.outputs);
+        let (format, dims, outputs) = get_format_dims_and_outputs(&requested_outputs)?;
+        if imgbuf.is_animated() {
+            let animations = {
+                let first_frame = imgbuf.decode(format)?;
+                let img_request = make_img_request(img, first_frame, &dims, &outputs)?;
+                let animations =
+                    make_animation_request(img, imgbuf, &dims, format, &outputs, config);
+
+                let socket = connect_to_socket(5, 100)?;
+                Request::Img(img_request).send(&socket)?;
+                let msg = read_socket(&socket)?;
+                drop(socket);
+                if let Answer::Err(e) = Answer::receive(&msg)? {
+                    return Err(format!("daemon error when sending image: {e}"));
                 }
-                .map_err(|e| format!("failed to create animated request: {e}"))?;
+                animations
+            }
+            .map_err(|e| format!("failed to create animated request: {e}"))?;
 
-                Ok(Some(Request::Animation(animations)))
-            } else {
-                let img_raw = imgbuf.decode(format)?;
-                Ok(Some(Request::Img(make_img_request(
-                    img, img_raw, &dims, &outputs,
-                )?)))
+            Ok(Some(Request::Animation(animations)))
+        } else {
+            let img_raw = imgbuf.decode(format)?;
+            Ok(Some(Request::Img(make_img_request(
+                img, img_raw, &dims, &outputs,
+            )?)))
+        }
+    }
+}
+
+fn cache_command(command: &cli::Cache, config: &Config) -> Result<(), String> {
+    match command {
+        cli::Cache::List => {
+            let now = SystemTime::now();
+            for entry in cache::entries()? {
+                let name = entry.path.file_name().unwrap_or_default().to_string_lossy();
+                let age = now.duration_since(entry.last_used).unwrap_or_default();
+                println!(
+                    "{:<10} {:>10} {:>9} ago  {name}",
+                    entry.kind.to_string(),
+                    human_size(entry.size),
+                    human_duration(age)
+                );
             }
         }
-        Swww::Init { no_cache, .. } => {
-            if !*no_cache {
-                restore_from_cache(&[])?;
+        cli::Cache::Prune { max_size } => {
+            let max_size = match max_size {
+                Some(mib) => mib.saturating_mul(1024 * 1024),
+                None => cache::max_size(config)?,
+            };
+            let removed = cache::prune(max_size)?;
+            let freed = removed.iter().map(|entry| entry.size).sum();
+            println!(
+                "removed {} files, freeing {}",
+                removed.len(),
+                human_size(freed)
+            );
+        }
+        cli::Cache::Stats => {
+            let entries = cache::entries()?;
+            let total: u64 = entries.iter().map(|entry| entry.size).sum();
+            println!(
+                "{} files, {} of {}",
+                entries.len(),
+                human_size(total),
+                human_size(cache::max_size(config)?)
+            );
+            for kind in [
+                cache::EntryKind::Output,
+                cache::EntryKind::Animation,
+                cache::EntryKind::Playlists,
+                cache::EntryKind::Outdated,
+            ] {
+                let entries: Vec<_> = entries.iter().filter(|entry| entry.kind == kind).collect();
+                let size = entries.iter().map(|entry| entry.size).sum();
+                println!("  {kind}: {} files, {}", entries.len(), human_size(size));
             }
-            Ok(None)
         }
-        Swww::Kill => Ok(Some(Request::Kill)),
-        Swww::Query => Ok(Some(Request::Query)),
+    }
+    Ok(())
+}
+
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+fn human_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..=59 => format!("{secs}s"),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+fn send_playlist_command(playlist: &cli::Playlist) -> Result<(), String> {
+    let command = match playlist {
+        cli::Playlist::Start(start) => ipc::PlaylistCommand::Start(make_playlist(start)?),
+        cli::Playlist::Next(p) => ipc::PlaylistCommand::Next(split_cmdline_outputs(&p.outputs)),
+        cli::Playlist::Prev(p) => ipc::PlaylistCommand::Prev(split_cmdline_outputs(&p.outputs)),
+        cli::Playlist::Pause(p) => ipc::PlaylistCommand::Pause(split_cmdline_outputs(&p.outputs)),
+        cli::Playlist::Resume(p) => ipc::PlaylistCommand::Resume(split_cmdline_outputs(&p.outputs)),
+        cli::Playlist::Stop(p) => ipc::PlaylistCommand::Stop(split_cmdline_outputs(&p.outputs)),
+    };
+
+    let socket = connect_to_socket(5, 100)?;
+    Request::Playlist(command).send(&socket)?;
+    let msg = read_socket(&socket)?;
+    match Answer::receive(&msg)? {
+        Answer::Err(e) => Err(format!("daemon error when handling playlist: {e}")),
+        _ => Ok(()),
+    }
+}
+
+fn make_playlist(start: &cli::PlaylistStart) -> Result<ipc::Playlist, String> {
+    let mut sources = Vec::with_capacity(start.paths.len());
+    for path in start.paths.iter() {
+        match path.canonicalize() {
+            Ok(p) => sources.push(p.to_string_lossy().to_string()),
+            Err(e) => return Err(format!("failed to canonicalize path {path:?}: {e}")),
+        }
+    }
+    Ok(ipc::Playlist {
+        outputs: split_cmdline_outputs(&start.outputs),
+        sources: sources.into_boxed_slice(),
+        interval: Duration::from_secs(start.interval),
+        order: make_playlist_order(&start.order),
+        resize: make_resize_strategy(&start.resize),
+        filter: make_filter(&start.filter),
+        fill_color: start.fill_color,
+        transition: make_transition(&start.transition),
+    })
+}
+
+fn send_img_by_path(img: &cli::Img) -> Result<(), String> {
+    if let Some("-") = img.path.to_str() {
+        return Err("cannot use --daemon-decode when reading the image from stdin".to_string());
+    }
+    let path = match img.path.canonicalize() {
+        Ok(p) => p.to_string_lossy().to_string(),
+        Err(e) => return Err(format!("failed no canonicalize image path: {e}")),
+    };
+    let request = Request::ImgByPath(ipc::ImgByPath {
+        path,
+        outputs: split_cmdline_outputs(&img.outputs),
+        resize: make_resize_strategy(&img.resize),
+        filter: make_filter(&img.filter),
+        fill_color: img.fill_color,
+        transition: make_transition(&img.transition),
+    });
+
+    let socket = connect_to_socket(5, 100)?;
+    request.send(&socket)?;
+    let msg = read_socket(&socket)?;
+    match Answer::receive(&msg)? {
+        Answer::Err(e) => Err(format!("daemon error when decoding image: {e}")),
+        _ => Ok(()),
     }
 }
 
@@ -180,30 +499,29 @@ fn make_img_request(
     dims: &[(u32, u32)],
     outputs: &[Vec<String>],
 ) -> Result<ipc::ImageRequest, String> {
-    let transition = make_transition(img);
+    let transition = make_transition(&img.transition);
     let mut unique_requests = Vec::with_capacity(dims.len());
     for (dim, outputs) in dims.iter().zip(outputs) {
-        let path = match img.path.canonicalize() {
-            Ok(p) => p.to_string_lossy().to_string(),
-            Err(e) => {
-                if let Some("-") = img.path.to_str() {
-                    "STDIN".to_string()
-                } else {
-                    return Err(format!("failed no canonicalize image path: {e}"));
-                }
-            }
-        };
-
-        let img = match img.resize {
-            ResizeStrategy::No => img_pad(&img_raw, *dim, &img.fill_color)?,
-            ResizeStrategy::Crop => img_resize_crop(&img_raw, *dim, make_filter(&img.filter))?,
-            ResizeStrategy::Fit => {
-                img_resize_fit(&img_raw, *dim, make_filter(&img.filter), &img.fill_color)?
-            }
-        };
+        let path = img_path(img)?;
+        let resize = make_resize_strategy(&img.resize);
+        let filter = make_filter(&img.filter);
+        let img_mmap = img_resize_mmap(
+            &img_raw,
+            *dim,
+            resize,
+            utils::imgproc::make_filter(&filter),
+            &img.fill_color,
+        )?;
 
         unique_requests.push((
-            ipc::Img { img, path },
+            ipc::Img {
+                img: img_mmap,
+                path,
+                resize,
+                filter,
+                fill_color: img.fill_color,
+                span: false,
+            },
             outputs.to_owned().into_boxed_slice(),
         ));
     }
@@ -211,6 +529,56 @@ fn make_img_request(
     Ok((transition, unique_requests.into_boxed_slice()))
 }
 
+/// Like `make_img_request`, but every output gets its own piece of the image
+fn make_span_img_request(
+    img: &cli::Img,
+    img_raw: Image,
+    outputs: &[(String, SpanOutput)],
+) -> Result<ipc::ImageRequest, String> {
+    let transition = make_transition(&img.transition);
+    let path = img_path(img)?;
+    let resize = make_resize_strategy(&img.resize);
+    let filter = make_filter(&img.filter);
+    let geometry: Vec<SpanOutput> = outputs.iter().map(|(_, output)| *output).collect();
+    let pieces = img_span(
+        &img_raw,
+        &geometry,
+        resize,
+        utils::imgproc::make_filter(&filter),
+        &img.fill_color,
+    )?;
+
+    let mut requests = Vec::with_capacity(outputs.len());
+    for ((name, _), piece) in outputs.iter().zip(pieces) {
+        requests.push((
+            ipc::Img {
+                img: piece,
+                path: path.clone(),
+                resize,
+                filter,
+                fill_color: img.fill_color,
+                span: true,
+            },
+            vec![name.clone()].into_boxed_slice(),
+        ));
+    }
+
+    Ok((transition, requests.into_boxed_slice()))
+}
+
+fn img_path(img: &cli::Img) -> Result<String, String> {
+    match img.path.canonicalize() {
+        Ok(p) => Ok(p.to_string_lossy().to_string()),
+        Err(e) => {
+            if let Some("-") = img.path.to_str() {
+                Ok("STDIN".to_string())
+            } else {
+                Err(format!("failed no canonicalize image path: {e}"))
+            }
+        }
+    }
+}
+
 #[allow(clippy::type_complexity)]
 fn get_format_dims_and_outputs(
     requested_outputs: &[String],
@@ -219,44 +587,74 @@ fn get_format_dims_and_outputs(
     let mut dims: Vec<(u32, u32)> = Vec::new();
     let mut imgs: Vec<ipc::BgImg> = Vec::new();
 
+    let infos = query_outputs()?;
+    let format = infos
+        .first()
+        .map_or(ipc::PixelFormat::Xrgb, |info| info.pixel_format);
+    for info in select_outputs(&infos, requested_outputs)? {
+        let info_img = &info.img;
+        let name = info.name.to_string();
+        let real_dim = info.real_dim();
+        if let Some((_, output)) = dims
+            .iter_mut()
+            .zip(&imgs)
+            .zip(&mut outputs)
+            .find(|((dim, img), _)| real_dim == **dim && info_img == *img)
+        {
+            output.push(name);
+        } else {
+            outputs.push(vec![name]);
+            dims.push(real_dim);
+            imgs.push(info_img.clone());
+        }
+    }
+    if outputs.is_empty() {
+        Err("none of the requested outputs are valid".to_owned())
+    } else {
+        Ok((format, dims, outputs))
+    }
+}
+
+fn get_format_and_span_outputs(
+    requested_outputs: &[String],
+) -> Result<(ipc::PixelFormat, Vec<(String, SpanOutput)>), String> {
+    let infos = query_outputs()?;
+    let format = infos
+        .first()
+        .map_or(ipc::PixelFormat::Xrgb, |info| info.pixel_format);
+    let mut outputs = Vec::new();
+    for info in select_outputs(&infos, requested_outputs)? {
+        let output = SpanOutput {
+            position: info.position,
+            dim: info.dim,
+            real_dim: info.real_dim(),
+        };
+        outputs.push((info.name.clone(), output));
+    }
+    if outputs.is_empty() {
+        Err("none of the requested outputs are valid".to_owned())
+    } else {
+        Ok((format, outputs))
+    }
+}
+
+/// The outputs `selectors` select, in the order the daemon listed them. See `utils::selector`
+fn select_outputs<'a>(
+    infos: &'a [ipc::BgInfo],
+    selectors: &[String],
+) -> Result<Vec<&'a ipc::BgInfo>, String> {
+    let ids: Vec<_> = infos.iter().map(ipc::BgInfo::output_id).collect();
+    let selected = Selection::parse(selectors)?.select(&ids)?;
+    Ok(selected.into_iter().map(|i| &infos[i]).collect())
+}
+
+fn query_outputs() -> Result<Box<[ipc::BgInfo]>, String> {
     let socket = connect_to_socket(5, 100)?;
     Request::Query.send(&socket)?;
-    let bytes = read_socket(&socket)?;
+    let msg = read_socket(&socket)?;
     drop(socket);
-    let answer = Answer::receive(&bytes);
-    match answer {
-        Answer::Info(infos) => {
-            let mut format = ipc::PixelFormat::Xrgb;
-            for info in infos.iter() {
-                format = info.pixel_format;
-                let info_img = &info.img;
-                let name = info.name.to_string();
-                if !requested_outputs.is_empty() && !requested_outputs.contains(&name) {
-                    continue;
-                }
-                let real_dim = (
-                    info.dim.0 * info.scale_factor as u32,
-                    info.dim.1 * info.scale_factor as u32,
-                );
-                if let Some((_, output)) = dims
-                    .iter_mut()
-                    .zip(&imgs)
-                    .zip(&mut outputs)
-                    .find(|((dim, img), _)| real_dim == **dim && info_img == *img)
-                {
-                    output.push(name);
-                } else {
-                    outputs.push(vec![name]);
-                    dims.push(real_dim);
-                    imgs.push(info_img.clone());
-                }
-            }
-            if outputs.is_empty() {
-                Err("none of the requested outputs are valid".to_owned())
-            } else {
-                Ok((format, dims, outputs))
-            }
-        }
+    match Answer::receive(&msg)? {
+        Answer::Info(infos) => Ok(infos),
         Answer::Err(e) => Err(format!("daemon error when sending query: {e}")),
         _ => unreachable!(),
     }
@@ -268,22 +666,22 @@ fn make_animation_request(
     dims: &[(u32, u32)],
     pixel_format: ipc::PixelFormat,
     outputs: &[Vec<String>],
+    config: &Config,
 ) -> Result<AnimationRequest, String> {
-    let filter = make_filter(&img.filter);
+    let filter = utils::imgproc::make_filter(&make_filter(&img.filter));
+    //TODO: make cache work for all resize strategies
+    let key =
+        (img.resize == ResizeStrategy::Crop).then(|| cache::AnimationKey::new(imgbuf.bytes()));
     let mut animations = Vec::with_capacity(dims.len());
     for (dim, outputs) in dims.iter().zip(outputs) {
-        // do not load cache if we are reading from stdin
-        if let Some("-") = img.path.to_str() {
-            //TODO: make cache work for all resize strategies
-            if img.resize == ResizeStrategy::Crop {
-                match cache::load_animation_frames(&img.path, *dim, pixel_format) {
-                    Ok(Some(animation)) => {
-                        animations.push((animation, outputs.to_owned().into_boxed_slice()));
-                        continue;
-                    }
-                    Ok(None) => (),
-                    Err(e) => eprintln!("Error loading cache for {:?}: {e}", img.path),
+        if let Some(key) = key {
+            match cache::load_animation_frames(key, *dim, pixel_format) {
+                Ok(Some(animation)) => {
+                    animations.push((animation, outputs.to_owned().into_boxed_slice()));
+                    continue;
                 }
+                Ok(None) => (),
+                Err(e) => eprintln!("Error loading cache for {:?}: {e}", img.path),
             }
         }
 
@@ -295,12 +693,19 @@ fn make_animation_request(
                 *dim,
                 pixel_format,
                 filter,
-                img.resize,
+                make_resize_strategy(&img.resize),
                 &img.fill_color,
             )?
             .into_boxed_slice(),
             pixel_format,
         };
+        if let Some(key) = key {
+            let stored = cache::max_size(config)
+                .and_then(|max_size| cache::store_animation_frames(key, &animation, max_size));
+            if let Err(e) = stored {
+                eprintln!("Error storing cache for {:?}: {e}", img.path);
+            }
+        }
         animations.push((animation, outputs.to_owned().into_boxed_slice()));
     }
     Ok(animations.into_boxed_slice())
@@ -314,9 +719,17 @@ fn split_cmdline_outputs(outputs: &str) -> Box<[String]> {
         .collect()
 }
 
-fn spawn_daemon(no_daemon: bool, format: &Option<cli::PixelFormat>) -> Result<(), String> {
+fn spawn_daemon(
+    no_daemon: bool,
+    no_cache: bool,
+    format: &Option<cli::PixelFormat>,
+) -> Result<(), String> {
     let mut cmd = std::process::Command::new("swww-daemon");
 
+    if no_cache {
+        cmd.arg("--no-cache");
+    }
+
     if let Some(format) = format {
         cmd.arg("--format");
         cmd.arg(match format {
@@ -372,69 +785,48 @@ fn connect_to_socket(tries: u8, interval: u64) -> Result<UnixStream, String> {
     }
     let error = error.unwrap();
     if error.kind() == std::io::ErrorKind::NotFound {
-        return Err("Socket file not found. Are you sure swww-daemon is running?".to_string());
+        return Err(format!(
+            "Socket file {} not found. Are you sure swww-daemon is running?",
+            path.to_string_lossy()
+        ));
     }
 
     Err(format!("Failed to connect to socket: {error}"))
 }
 
-fn is_daemon_running() -> Result<bool, String> {
-    let proc = PathBuf::from("/proc");
-
-    let entries = match proc.read_dir() {
-        Ok(e) => e,
-        Err(e) => return Err(e.to_string()),
-    };
-
-    for entry in entries.flatten() {
-        let dirname = entry.file_name();
-        if let Ok(pid) = dirname.to_string_lossy().parse::<u32>() {
-            if std::process::id() == pid {
+fn restore_from_cache(requested_outputs: &[String]) -> Result<(), String> {
+    let infos = query_outputs()?;
+    for info in select_outputs(&infos, requested_outputs)? {
+        let img = match cache::get_previous_image(&info.output_id()) {
+            Ok(Some(img)) => img,
+            Ok(None) => continue,
+            Err(e) => {
+                eprintln!(
+                    "WARNING: failed to read cache for output {}: {e}",
+                    info.name
+                );
                 continue;
             }
-            let mut entry_path = entry.path();
-            entry_path.push("cmdline");
-            if let Ok(cmd) = std::fs::read_to_string(entry_path) {
-                let mut args = cmd.split(&[' ', '\0']);
-                if let Some(arg0) = args.next() {
-                    if arg0.ends_with("swww-daemon") {
-                        return Ok(true);
-                    }
-                }
-            }
-        }
-    }
-
-    Ok(false)
-}
-
-fn restore_from_cache(requested_outputs: &[String]) -> Result<(), String> {
-    let (_, _, outputs) = get_format_dims_and_outputs(requested_outputs)?;
-
-    for output in outputs.iter().flatten() {
-        let img_path = utils::cache::get_previous_image_path(output)?;
-        #[allow(deprecated)]
-        if let Err(e) = process_swww_args(&Swww::Img(cli::Img {
-            path: PathBuf::from(img_path),
-            outputs: output.to_string(),
-            no_resize: false,
-            resize: ResizeStrategy::Crop,
-            fill_color: [0, 0, 0],
-            filter: cli::Filter::Lanczos3,
-            transition_type: cli::TransitionType::None,
-            transition_step: u8::MAX,
-            transition_duration: 0.0,
-            transition_fps: 30,
-            transition_angle: 0.0,
-            transition_pos: cli::CliPosition {
-                x: cli::CliCoord::Pixel(0.0),
-                y: cli::CliCoord::Pixel(0.0),
+        };
+        let request = Request::ImgByPath(ipc::ImgByPath {
+            path: img.path,
+            outputs: Box::new([info.name.clone()]),
+            resize: img.resize,
+            filter: img.filter,
+            fill_color: img.fill_color,
+            transition: ipc::Transition {
+                step: u8::MAX,
+                ..ipc::Transition::default()
             },
-            invert_y: false,
-            transition_bezier: (0.0, 0.0, 0.0, 0.0),
-            transition_wave: (0.0, 0.0),
-        })) {
-            eprintln!("WARNING: failed to load cache for output {output}: {e}");
+        });
+        let socket = connect_to_socket(5, 100)?;
+        request.send(&socket)?;
+        let msg = read_socket(&socket)?;
+        if let Answer::Err(e) = Answer::receive(&msg)? {
+            eprintln!(
+                "WARNING: failed to load cache for output {}: {e}",
+                info.name
+            );
         }
     }
 
@@ -1,89 +1,257 @@
 //! Implements basic cache functionality.
 //!
 //! The idea is:
-//!   1. the client registers the last image sent for each output in a file
-//!   2. the daemon spawns a client that reloads that image when an output is created
+//!   1. the daemon registers the last image displayed at each output in a file, along with how it
+//!      was resized
+//!   2. the daemon displays that image again when the output appears
+//!
+//! Those files are named after the monitor rather than after the output, so that a monitor gets
+//! its image back even when it is plugged into another port. See `output_key`.
+//!
+//! We also keep the compressed frames of animations, named after a hash of the animation's file
+//! contents, so that moving or renaming the file does not make us process it again. Since those
+//! can get big, we evict the least recently used ones once the cache grows over its maximum size
+//! (the `[cache]` table's `max_size`, in MiB). See `prune`.
+//!
+//! Files are written to a temporary file first, and then renamed over the old one, so that
+//! readers never see half written files.
 
 use std::{
+    fmt,
     fs::File,
     io::{BufReader, BufWriter, Read, Write},
     path::{Path, PathBuf},
+    time::SystemTime,
 };
 
-use crate::ipc::{Animation, PixelFormat};
+use bitcode::{Decode, Encode};
+use serde_json::{json, Value};
 
-pub fn store(output_name: &str, img_path: &str) -> Result<(), String> {
-    let mut filepath = cache_dir()?;
-    filepath.push(output_name);
-    let file = File::create(filepath).map_err(|e| e.to_string())?;
+use crate::{
+    config::Config,
+    ipc::{Animation, Filter, PixelFormat, Playlist, ResizeStrategy},
+    selector::OutputId,
+};
 
-    let mut writer = BufWriter::new(file);
-    writer
-        .write_all(img_path.as_bytes())
-        .map_err(|e| format!("failed to write cache: {e}"))
+/// The cache's maximum size, in MiB, when the configuration file does not say otherwise
+pub const DEFAULT_MAX_SIZE: u64 = 512;
+
+/// What a file in the cache holds
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    /// The last image displayed at a monitor
+    Output,
+    /// An animation's compressed frames
+    Animation,
+    Playlists,
+    /// Anything stored by another version of swww, which we cannot read
+    Outdated,
 }
 
-pub fn store_animation_frames(animation: &Animation) -> Result<(), String> {
-    let filename = animation_filename(
-        &PathBuf::from(&animation.path),
-        animation.dimensions,
-        animation.pixel_format,
-    );
-    let mut filepath = cache_dir()?;
-    filepath.push(&filename);
+impl fmt::Display for EntryKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Output => "output",
+            Self::Animation => "animation",
+            Self::Playlists => "playlists",
+            Self::Outdated => "outdated",
+        };
+        write!(f, "{s}")
+    }
+}
 
-    let bytes = bitcode::encode(animation);
+/// A file in the cache
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub path: PathBuf,
+    pub kind: EntryKind,
+    /// In bytes
+    pub size: u64,
+    /// When we last wrote or read the file
+    pub last_used: SystemTime,
+}
 
-    if !filepath.is_file() {
-        let file = File::create(filepath).map_err(|e| e.to_string())?;
+/// What the daemon needs to resume a playlist after restarting
+#[derive(Decode, Encode)]
+pub struct PlaylistState {
+    pub playlist: Playlist,
+    /// The image currently being displayed
+    pub current: Option<String>,
+    pub paused: bool,
+}
 
-        let mut writer = BufWriter::new(file);
-        writer
-            .write_all(&bytes)
-            .map_err(|e| format!("failed to write cache: {e}"))
-    } else {
-        Ok(())
+/// The image an output displayed last, and how it was resized, so that we can display it exactly
+/// the same way again
+#[derive(Clone, Debug, PartialEq)]
+pub struct CachedImg {
+    pub path: String,
+    pub resize: ResizeStrategy,
+    pub filter: Filter,
+    pub fill_color: [u8; 3],
+}
+
+impl CachedImg {
+    /// We store these as json, with the same fields as the daemon's json "img" command, so that
+    /// they survive upgrades
+    fn to_json(&self) -> Value {
+        let [r, g, b] = self.fill_color;
+        json!({
+            "path": self.path,
+            "resize": self.resize.to_string(),
+            "filter": self.filter.to_string(),
+            "fill_color": format!("{r:02x}{g:02x}{b:02x}"),
+        })
+    }
+
+    fn parse(contents: &str) -> Result<Self, String> {
+        // older versions only stored the path
+        if !contents.starts_with('{') {
+            return Ok(Self {
+                path: contents.to_string(),
+                resize: ResizeStrategy::Crop,
+                filter: Filter::Lanczos3,
+                fill_color: [0, 0, 0],
+            });
+        }
+        let value: Value = serde_json::from_str(contents).map_err(|e| e.to_string())?;
+        let field = |name: &str| -> Result<&str, String> {
+            value
+                .get(name)
+                .and_then(Value::as_str)
+                .ok_or_else(|| format!("missing \"{name}\" string"))
+        };
+        let fill_color = field("fill_color")?;
+        let fill_color = match u32::from_str_radix(fill_color, 16) {
+            Ok(rgb) if fill_color.len() == 6 => {
+                let [_, r, g, b] = rgb.to_be_bytes();
+                [r, g, b]
+            }
+            _ => return Err(format!("invalid fill color '{fill_color}'")),
+        };
+        Ok(Self {
+            path: field("path")?.to_string(),
+            resize: field("resize")?.parse()?,
+            filter: field("filter")?.parse()?,
+            fill_color,
+        })
+    }
+}
+
+/// Identifies an animation by its file's contents. Computing it means hashing the whole file, so
+/// compute it once, and use it for every dimension the animation is needed at
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AnimationKey(u64);
+
+impl AnimationKey {
+    /// A 64-bit FNV-1a hash of `contents`. It is not cryptographic, but it is stable across
+    /// builds, which is all we need to recognize a file that was moved
+    #[must_use]
+    pub fn new(contents: &[u8]) -> Self {
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for byte in contents {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+        Self(hash)
+    }
+}
+
+/// Remembers `img` as the last image displayed at `output`
+pub fn store(output: &OutputId, img: &CachedImg) -> Result<(), String> {
+    let mut filepath = cache_dir()?;
+    filepath.push(output_key(output));
+    write_file(&filepath, img.to_json().to_string().as_bytes())
+}
+
+/// Stores the frames of the animation identified by `key`, then prunes the cache down to
+/// `max_size` bytes
+pub fn store_animation_frames(
+    key: AnimationKey,
+    animation: &Animation,
+    max_size: u64,
+) -> Result<(), String> {
+    let filename = animation_filename(key, animation.dimensions, animation.pixel_format);
+    let mut filepath = cache_dir()?;
+    filepath.push(&filename);
+
+    if filepath.is_file() {
+        return Ok(());
     }
+    write_file(&filepath, &bitcode::encode(animation))?;
+    prune(max_size).map(|_| ())
 }
 
 pub fn load_animation_frames(
-    path: &Path,
+    key: AnimationKey,
     dimensions: (u32, u32),
     pixel_format: PixelFormat,
 ) -> Result<Option<Animation>, String> {
-    let filename = animation_filename(path, dimensions, pixel_format);
-    let cache_dir = cache_dir()?;
-    let mut filepath = cache_dir.clone();
+    let filename = animation_filename(key, dimensions, pixel_format);
+    let mut filepath = cache_dir()?;
     filepath.push(filename);
+    if !filepath.is_file() {
+        return Ok(None);
+    }
 
-    let read_dir = cache_dir
-        .read_dir()
-        .map_err(|e| format!("failed to read cache directory ({cache_dir:?}): {e}"))?;
+    let file = File::open(&filepath).map_err(|e| e.to_string())?;
+    // this is what makes the cache evict the least recently *used* animations
+    let now = rustix::fs::Timespec {
+        tv_sec: 0,
+        tv_nsec: rustix::fs::UTIME_NOW,
+    };
+    let times = rustix::fs::Timestamps {
+        last_access: now,
+        last_modification: now,
+    };
+    // if this fails, the animation only gets evicted sooner than it should
+    let _ = rustix::fs::futimens(&file, &times);
+    let mut buf_reader = BufReader::new(file);
+    let mut buf = Vec::new();
+    buf_reader
+        .read_to_end(&mut buf)
+        .map_err(|e| format!("failed to read file `{filepath:?}`: {e}"))?;
 
-    for entry in read_dir.into_iter().flatten() {
-        if entry.path() == filepath {
-            let file = File::open(&filepath).map_err(|e| e.to_string())?;
-            let mut buf_reader = BufReader::new(file);
-            let mut buf = Vec::new();
-            buf_reader
-                .read_to_end(&mut buf)
-                .map_err(|e| format!("failed to read file `{filepath:?}`: {e}"))?;
-
-            let frames: Animation =
-                bitcode::decode(&buf).expect("failed to decode cached animations");
-            return Ok(Some(frames));
+    bitcode::decode(&buf)
+        .map(Some)
+        .map_err(|e| format!("failed to decode cached animation {filepath:?}: {e}"))
+}
+
+/// Overwrites the stored playlists with `playlists`
+pub fn store_playlists(playlists: &[PlaylistState]) -> Result<(), String> {
+    let mut filepath = cache_dir()?;
+    filepath.push(playlists_filename());
+    if playlists.is_empty() {
+        if filepath.is_file() {
+            std::fs::remove_file(&filepath)
+                .map_err(|e| format!("failed to remove {filepath:?}: {e}"))?;
         }
+        return Ok(());
     }
-    Ok(None)
+
+    write_file(&filepath, &bitcode::encode(playlists))
 }
 
-pub fn get_previous_image_path(output_name: &str) -> Result<String, String> {
+pub fn load_playlists() -> Result<Vec<PlaylistState>, String> {
     let mut filepath = cache_dir()?;
-    clean_previous_verions(&filepath);
+    filepath.push(playlists_filename());
+    if !filepath.is_file() {
+        return Ok(Vec::new());
+    }
+    let bytes =
+        std::fs::read(&filepath).map_err(|e| format!("failed to read file `{filepath:?}`: {e}"))?;
+    bitcode::decode(&bytes).map_err(|e| format!("failed to decode cached playlists: {e}"))
+}
 
-    filepath.push(output_name);
+/// The last image displayed at `output`, if any
+pub fn get_previous_image(output: &OutputId) -> Result<Option<CachedImg>, String> {
+    let cache_dir = cache_dir()?;
+    let mut filepath = cache_dir.join(output_key(output));
     if !filepath.is_file() {
-        return Ok("".to_string());
+        // older versions named the file after the output
+        filepath = cache_dir.join(output.name);
+        if output.name.is_empty() || !filepath.is_file() {
+            return Ok(None);
+        }
     }
     let file = std::fs::File::open(filepath).map_err(|e| format!("failed to open file: {e}"))?;
     let mut reader = BufReader::new(file);
@@ -92,38 +260,11 @@ pub fn get_previous_image_path(output_name: &str) -> Result<String, String> {
         .read_to_end(&mut buf)
         .map_err(|e| format!("failed to read file: {e}"))?;
 
-    String::from_utf8(buf).map_err(|e| format!("failed to decode bytes: {e}"))
-}
-
-pub fn load(output_name: &str) -> Result<(), String> {
-    let img_path = get_previous_image_path(output_name)?;
-    if img_path.is_empty() {
-        return Ok(());
-    }
-
-    if let Ok(mut child) = std::process::Command::new("pidof").arg("swww").spawn() {
-        if let Ok(status) = child.wait() {
-            if status.success() {
-                return Err("there is already another swww process running".to_string());
-            }
-        }
-    }
-
-    match std::process::Command::new("swww")
-        .arg("img")
-        .args([
-            &format!("--outputs={output_name}"),
-            "--transition-type=none",
-            &img_path,
-        ])
-        .spawn()
-    {
-        Ok(mut child) => match child.wait() {
-            Ok(_) => Ok(()),
-            Err(e) => Err(format!("child process failed: {e}")),
-        },
-        Err(e) => Err(format!("failed to spawn child process: {e}")),
+    let contents = String::from_utf8(buf).map_err(|e| format!("failed to decode bytes: {e}"))?;
+    if contents.is_empty() {
+        return Ok(None);
     }
+    CachedImg::parse(&contents).map(Some)
 }
 
 pub fn clean() -> Result<(), String> {
@@ -131,39 +272,115 @@ pub fn clean() -> Result<(), String> {
         .map_err(|e| format!("failed to remove cache directory: {e}"))
 }
 
-fn clean_previous_verions(cache_dir: &Path) {
-    let mut read_dir = match std::fs::read_dir(cache_dir) {
-        Ok(read_dir) => read_dir,
-        Err(_) => {
-            eprintln!("WARNING: failed to read cache dir {:?} entries", cache_dir);
-            return;
-        }
+/// The cache's maximum size, in bytes, according to `config`
+pub fn max_size(config: &Config) -> Result<u64, String> {
+    let mib = match config.cache().get("max_size") {
+        Some(size) => match size.as_f64() {
+            Some(size) if size >= 0.0 => size as u64,
+            _ => return Err("the cache's \"max_size\" must be a non-negative number".to_string()),
+        },
+        None => DEFAULT_MAX_SIZE,
     };
+    Ok(mib.saturating_mul(1024 * 1024))
+}
 
-    let current_version = env!("CARGO_PKG_VERSION");
+/// Every file in the cache, least recently used first
+pub fn entries() -> Result<Vec<Entry>, String> {
+    entries_in(&cache_dir()?)
+}
 
-    while let Some(Ok(entry)) = read_dir.next() {
+fn entries_in(cache_dir: &Path) -> Result<Vec<Entry>, String> {
+    let read_dir = cache_dir
+        .read_dir()
+        .map_err(|e| format!("failed to read cache directory ({cache_dir:?}): {e}"))?;
+
+    let mut entries = Vec::new();
+    for entry in read_dir.flatten() {
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if !metadata.is_file() {
+            continue;
+        }
         let filename = entry.file_name();
-        let filename = match filename.to_str() {
-            Some(filename) => filename,
-            None => {
-                eprintln!("WARNING: failed to read filename of {:?}", filename);
-                continue;
-            }
+        // we only ever create files with utf-8 names
+        let Some(filename) = filename.to_str() else {
+            continue;
         };
+        entries.push(Entry {
+            path: entry.path(),
+            kind: entry_kind(filename),
+            size: metadata.len(),
+            last_used: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+        });
+    }
+    entries.sort_by_key(|entry| entry.last_used);
+    Ok(entries)
+}
 
-        // only the images we've cached will have a _v token, indicating their version
-        if let Some(i) = filename.rfind("_v") {
-            if &filename[i + 2..] != current_version {
-                if let Err(e) = std::fs::remove_file(entry.path()) {
-                    eprintln!(
-                        "WARNING: failed to remove cache file {} of old swww version {:?}",
-                        filename, e
-                    );
-                }
-            }
+fn entry_kind(filename: &str) -> EntryKind {
+    // temporary files are only left behind by writes that were interrupted
+    if filename.starts_with('.') {
+        return EntryKind::Outdated;
+    }
+    // only the files holding frames and playlists have a _v token, indicating their version
+    match filename.rfind("_v") {
+        Some(i) if &filename[i + 2..] != env!("CARGO_PKG_VERSION") => EntryKind::Outdated,
+        Some(_) if filename.starts_with("playlists_v") => EntryKind::Playlists,
+        Some(_) => EntryKind::Animation,
+        None => EntryKind::Output,
+    }
+}
+
+/// Removes everything stored by other versions of swww, then the least recently used animations,
+/// until the cache takes at most `max_size` bytes. Returns what we removed
+pub fn prune(max_size: u64) -> Result<Vec<Entry>, String> {
+    prune_dir(&cache_dir()?, max_size)
+}
+
+fn prune_dir(cache_dir: &Path, max_size: u64) -> Result<Vec<Entry>, String> {
+    let mut entries = entries_in(cache_dir)?;
+    // outdated files go first, whenever they were used
+    entries.sort_by_key(|entry| entry.kind != EntryKind::Outdated);
+    let mut total: u64 = entries.iter().map(|entry| entry.size).sum();
+    let mut removed = Vec::new();
+    for entry in entries {
+        let evict = match entry.kind {
+            EntryKind::Outdated => true,
+            EntryKind::Animation => total > max_size,
+            // these are tiny, and we cannot get them back
+            EntryKind::Output | EntryKind::Playlists => false,
+        };
+        if !evict {
+            continue;
         }
+        std::fs::remove_file(&entry.path)
+            .map_err(|e| format!("failed to remove cache file {:?}: {e}", entry.path))?;
+        total -= entry.size;
+        removed.push(entry);
     }
+    Ok(removed)
+}
+
+/// Replaces the contents of `filepath` with `bytes`, through a temporary file, so that nobody ever
+/// reads a half written file
+fn write_file(filepath: &Path, bytes: &[u8]) -> Result<(), String> {
+    let filename = filepath.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = filepath.with_file_name(format!(".{filename}.{}", std::process::id()));
+    let file = File::create(&tmp).map_err(|e| format!("failed to create {tmp:?}: {e}"))?;
+    let mut writer = BufWriter::new(file);
+    let written = writer
+        .write_all(bytes)
+        .and_then(|()| writer.flush())
+        .map_err(|e| format!("failed to write cache: {e}"))
+        .and_then(|()| {
+            std::fs::rename(&tmp, filepath)
+                .map_err(|e| format!("failed to rename {tmp:?} to {filepath:?}: {e}"))
+        });
+    if written.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    written
 }
 
 fn create_dir(p: &Path) -> Result<(), String> {
@@ -192,11 +409,52 @@ fn cache_dir() -> Result<PathBuf, String> {
     }
 }
 
+/// Names the file holding the last image displayed at `output` after the monitor, rather than
+/// after the port it is plugged into.
+///
+/// The wayland protocol does not tell us the monitor's serial number on its own, but
+/// compositors usually include it in the description, along with the make and model. Some also
+/// append the output's name, which we remove. Without a description, we use the make and model,
+/// and without those, the name. Monitors of the same model without serial numbers share the file.
+fn output_key(output: &OutputId) -> PathBuf {
+    let description = output
+        .description
+        .strip_suffix(&format!(" ({})", output.name))
+        .unwrap_or(output.description)
+        .trim();
+    let make_and_model = format!("{} {}", output.make, output.model);
+    let key = if !description.is_empty() {
+        description
+    } else if !make_and_model.trim().is_empty() {
+        make_and_model.trim()
+    } else {
+        output.name
+    };
+    // keep the name safe to use as a file name, and away from the `_v` versioning
+    let key: String = key
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '.' | ' ' => c,
+            _ => '-',
+        })
+        .collect();
+    format!("output {key}").into()
+}
+
+/// Versioned, so that we do not try to decode playlists stored by other versions of swww
 #[must_use]
-fn animation_filename(path: &Path, dimensions: (u32, u32), pixel_format: PixelFormat) -> PathBuf {
+fn playlists_filename() -> PathBuf {
+    format!("playlists_v{}", env!("CARGO_PKG_VERSION")).into()
+}
+
+fn animation_filename(
+    key: AnimationKey,
+    dimensions: (u32, u32),
+    pixel_format: PixelFormat,
+) -> PathBuf {
     format!(
-        "{}__{}x{}_{:?}_v{}",
-        path.to_string_lossy().replace('/', "_"),
+        "animation_{:016x}__{}x{}_{:?}_v{}",
+        key.0,
         dimensions.0,
         dimensions.1,
         pixel_format,
@@ -204,3 +462,134 @@ fn animation_filename(path: &Path, dimensions: (u32, u32), pixel_format: PixelFo
     )
     .into()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output<'a>(name: &'a str, description: &'a str) -> OutputId<'a> {
+        OutputId {
+            name,
+            description,
+            make: "Dell Inc.",
+            model: "DELL U2720Q",
+        }
+    }
+
+    #[test]
+    fn should_key_outputs_by_monitor() {
+        let key = output_key(&output("DP-1", "Dell Inc. DELL U2720Q 7X5D9F3 (DP-1)"));
+        assert_eq!(key, PathBuf::from("output Dell Inc. DELL U2720Q 7X5D9F3"));
+        assert_eq!(
+            output_key(&output("DP-2", "Dell Inc. DELL U2720Q 7X5D9F3 (DP-2)")),
+            key
+        );
+        assert_eq!(
+            output_key(&output("DP-1", "")),
+            PathBuf::from("output Dell Inc. DELL U2720Q")
+        );
+        let unknown = OutputId {
+            name: "WL-1",
+            description: "",
+            make: "",
+            model: "",
+        };
+        assert_eq!(output_key(&unknown), PathBuf::from("output WL-1"));
+        assert_eq!(
+            output_key(&output("DP-1", "some/odd_v1 name")),
+            PathBuf::from("output some-odd-v1 name")
+        );
+    }
+
+    #[test]
+    fn cached_images_round_trip() {
+        let img = CachedImg {
+            path: "/home/user/a \"quoted\" image.png".to_string(),
+            resize: ResizeStrategy::Fit,
+            filter: Filter::CatmullRom,
+            fill_color: [0x1e, 0x1e, 0x2e],
+        };
+        assert_eq!(CachedImg::parse(&img.to_json().to_string()), Ok(img));
+
+        let legacy = CachedImg::parse("/home/user/old.png").unwrap();
+        assert_eq!(legacy.path, "/home/user/old.png");
+        assert_eq!(legacy.resize, ResizeStrategy::Crop);
+        assert!(CachedImg::parse(r#"{"path": "/a.png"}"#).is_err());
+    }
+
+    /// A fresh directory, so that we never touch the user's cache
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("swww-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn create(dir: &Path, name: &str, size: usize, last_used: i64) {
+        let path = dir.join(name);
+        std::fs::write(&path, vec![0u8; size]).unwrap();
+        let time = rustix::fs::Timespec {
+            tv_sec: last_used,
+            tv_nsec: 0,
+        };
+        let times = rustix::fs::Timestamps {
+            last_access: time,
+            last_modification: time,
+        };
+        rustix::fs::futimens(File::open(path).unwrap(), &times).unwrap();
+    }
+
+    #[test]
+    fn should_evict_the_least_recently_used_animations() {
+        let dir = test_dir("prune");
+        let version = env!("CARGO_PKG_VERSION");
+        create(&dir, &format!("animation_1__10x10_Xrgb_v{version}"), 100, 1);
+        create(&dir, &format!("animation_2__10x10_Xrgb_v{version}"), 100, 3);
+        create(&dir, &format!("animation_3__10x10_Xrgb_v{version}"), 100, 2);
+        create(&dir, "animation_4__10x10_Xrgb_v0.1.0", 10, 4);
+        create(&dir, "output Dell Inc. DELL U2720Q", 10, 0);
+        create(&dir, &format!("playlists_v{version}"), 10, 0);
+
+        let kinds: Vec<EntryKind> = entries_in(&dir).unwrap().iter().map(|e| e.kind).collect();
+        assert_eq!(kinds.len(), 6);
+        assert_eq!(kinds[2..5], [EntryKind::Animation; 3]);
+        assert_eq!(kinds[5], EntryKind::Outdated);
+
+        let removed = prune_dir(&dir, 150).unwrap();
+        let removed: Vec<_> = removed
+            .iter()
+            .map(|e| e.path.file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(
+            removed,
+            [
+                "animation_4__10x10_Xrgb_v0.1.0",
+                format!("animation_1__10x10_Xrgb_v{version}").as_str(),
+                format!("animation_3__10x10_Xrgb_v{version}").as_str(),
+            ]
+        );
+        assert_eq!(entries_in(&dir).unwrap().len(), 3);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn should_name_animations_after_their_contents() {
+        let name = |contents: &[u8]| {
+            animation_filename(AnimationKey::new(contents), (10, 10), PixelFormat::Xrgb)
+        };
+        assert_eq!(name(b"GIF89a"), name(b"GIF89a"));
+        assert_ne!(name(b"GIF89a"), name(b"GIF89b"));
+    }
+
+    #[test]
+    fn should_replace_files_without_leaving_temporary_ones() {
+        let dir = test_dir("write");
+        let path = dir.join("output DP-1");
+        write_file(&path, b"old").unwrap();
+        write_file(&path, b"new").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+        assert_eq!(entries_in(&dir).unwrap().len(), 1);
+        assert_eq!(entry_kind(".output DP-1.1234"), EntryKind::Outdated);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
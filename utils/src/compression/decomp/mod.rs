@@ -4,6 +4,41 @@
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 pub(super) mod ssse3;
 
+/// The pixels unpacking `diff` changes, from the first one to the last one, or `None` if it
+/// changes none. We only read the run lengths, skipping over the pixel data
+///
+/// diff must be a slice produced by a BitPack
+pub(super) fn changed_pixels(diff: &[u8]) -> Option<std::ops::Range<usize>> {
+    // The final bytes are just padding to prevent us from going out of bounds
+    let len = diff.len().saturating_sub(3);
+    let mut first = None;
+    let mut end = 0;
+
+    let mut diff_idx = 0;
+    let mut pix_idx = 0;
+    let read_count = |diff_idx: &mut usize| {
+        let mut count = 0;
+        while diff[*diff_idx] == u8::MAX {
+            count += u8::MAX as usize;
+            *diff_idx += 1;
+        }
+        count += diff[*diff_idx] as usize;
+        *diff_idx += 1;
+        count
+    };
+    while diff_idx < len {
+        pix_idx += read_count(&mut diff_idx);
+        let to_cpy = read_count(&mut diff_idx);
+        if to_cpy > 0 {
+            first.get_or_insert(pix_idx);
+            end = pix_idx + to_cpy;
+        }
+        diff_idx += to_cpy * 3;
+        pix_idx += to_cpy + 1;
+    }
+    first.map(|first| first..end)
+}
+
 /// diff must be a slice produced by a BitPack
 /// buf must have the EXACT expected size by the BitPack
 #[inline(always)]
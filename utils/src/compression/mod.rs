@@ -3,7 +3,7 @@
 //! Our compression strategy is documented in `comp/mod.rs`
 
 use comp::pack_bytes;
-use decomp::{unpack_bytes_3channels, unpack_bytes_4channels};
+use decomp::{changed_pixels, unpack_bytes_3channels, unpack_bytes_4channels};
 use std::ffi::{c_char, c_int};
 
 use bitcode::{Decode, Encode};
@@ -193,13 +193,16 @@ impl Decompressor {
 
     ///returns whether unpacking was successful. Note it can only fail if `buf.len() !=
     ///expected_buf_size`
+    ///
+    ///On success, returns the range of pixels (not bytes) that may have changed, so that we only
+    ///have to tell the compositor about those
     #[inline]
     pub fn decompress(
         &mut self,
         bitpack: &BitPack,
         buf: &mut [u8],
         pixel_format: PixelFormat,
-    ) -> Result<(), String> {
+    ) -> Result<Option<std::ops::Range<usize>>, String> {
         if buf.len() != bitpack.expected_buf_size {
             return Err(format!(
                 "buf has len {}, but expected len is {}",
@@ -237,7 +240,7 @@ impl Decompressor {
             unpack_bytes_4channels(buf, v);
         }
 
-        Ok(())
+        Ok(changed_pixels(v))
     }
 }
 
@@ -286,6 +289,24 @@ mod tests {
         }
     }
 
+    #[test]
+    fn should_report_the_changed_pixels() {
+        let frame1 = [0u8; 30];
+        let mut frame2 = frame1;
+        frame2[7] = 1; // pixel 2
+        frame2[19] = 1; // pixel 6
+        for format in FORMATS {
+            let compressed = Compressor::new()
+                .compress(&frame1, &frame2, format)
+                .unwrap();
+            let mut buf = buf_from(&frame1, format.channels().into());
+            let changed = Decompressor::new()
+                .decompress(&compressed, &mut buf, format)
+                .unwrap();
+            assert_eq!(changed, Some(2..7));
+        }
+    }
+
     #[test]
     fn total_random() {
         for format in FORMATS.into_iter() {
@@ -0,0 +1,280 @@
+//! The user's configuration file, `$XDG_CONFIG_HOME/swww/config.toml`.
+//!
+//! It holds default settings for displaying images, both for every output and for specific ones.
+//! Settings use the same names as `swww img`'s flags (with underscores instead of dashes), which
+//! are also the names of the fields in the daemon's json protocol:
+//!
+//! ```toml
+//! resize = "fit"
+//! transition_type = "fade"
+//! image = "~/Pictures/default.png"
+//!
+//! [outputs.DP-1]
+//! resize = "crop"
+//!
+//! [daemon]
+//! log_level = "info"
+//!
+//! [cache]
+//! max_size = 256
+//! ```
+//!
+//! The `[daemon]` table holds the daemon's own options, and `[cache]` those of the cache. We keep
+//! everything as `serde_json::Value`s, since each reader has its own way of parsing them.
+
+use std::path::PathBuf;
+
+use serde_json::Value;
+
+use crate::toml;
+
+/// Every setting we accept, in each table
+pub const KEYS: &[&str] = &[
+    "resize",
+    "filter",
+    "fill_color",
+    "transition_type",
+    "transition_step",
+    "transition_duration",
+    "transition_fps",
+    "transition_angle",
+    "transition_pos",
+    "transition_bezier",
+    "transition_wave",
+    "transition_slats",
+    "image",
+];
+
+/// Every option we accept in the `[daemon]` table
+pub const DAEMON_KEYS: &[&str] = &["log_level"];
+
+/// Every option we accept in the `[cache]` table
+pub const CACHE_KEYS: &[&str] = &["max_size"];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    /// Settings for every output, as a `Value::Object`, or `Value::Null` if there are none
+    defaults: Value,
+    /// Settings for specific outputs. These take precedence over the defaults
+    outputs: Vec<(String, Value)>,
+    /// The daemon's own options, as a `Value::Object`, or `Value::Null` if there are none
+    daemon: Value,
+    /// The cache's options, as a `Value::Object`, or `Value::Null` if there are none
+    cache: Value,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Config {
+    /// A configuration without any settings. Empty maps cannot be made in a `const fn`, so every
+    /// table starts as `Value::Null`
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            defaults: Value::Null,
+            outputs: Vec::new(),
+            daemon: Value::Null,
+            cache: Value::Null,
+        }
+    }
+
+    /// Reads the configuration file. If there is none, we have no settings
+    pub fn load() -> Result<Self, String> {
+        let Some(path) = config_file("config.toml") else {
+            return Ok(Self::new());
+        };
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => Self::parse(&contents).map_err(|e| format!("{path:?}: {e}")),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::new()),
+            Err(e) => Err(format!("failed to read {path:?}: {e}")),
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let Value::Object(fields) = toml::parse(s)? else {
+            unreachable!("toml documents are always tables");
+        };
+        let mut config = Self::new();
+        let mut defaults = serde_json::Map::new();
+        for (key, value) in fields {
+            if key == "daemon" || key == "cache" {
+                let Value::Object(options) = &value else {
+                    return Err(format!("\"{key}\" must be a table, such as [{key}]"));
+                };
+                let valid = if key == "daemon" {
+                    DAEMON_KEYS
+                } else {
+                    CACHE_KEYS
+                };
+                for option in options.keys() {
+                    check_key(option, valid).map_err(|e| format!("{key}: {e}"))?;
+                }
+                match key.as_str() {
+                    "daemon" => config.daemon = value,
+                    _ => config.cache = value,
+                }
+                continue;
+            }
+            if key != "outputs" {
+                check_key(&key, KEYS)?;
+                defaults.insert(key, value);
+                continue;
+            }
+            let Value::Object(outputs) = value else {
+                return Err("\"outputs\" must be a table, such as [outputs.DP-1]".to_string());
+            };
+            for (output, settings) in outputs {
+                let Value::Object(fields) = &settings else {
+                    return Err(format!("\"outputs.{output}\" must be a table"));
+                };
+                for key in fields.keys() {
+                    check_key(key, KEYS).map_err(|e| format!("outputs.{output}: {e}"))?;
+                }
+                config.outputs.push((output, settings));
+            }
+        }
+        if !defaults.is_empty() {
+            config.defaults = Value::Object(defaults);
+        }
+        Ok(config)
+    }
+
+    /// The settings for every output, as a `Value::Object`, or `Value::Null` if there are none
+    #[must_use]
+    pub fn defaults(&self) -> &Value {
+        &self.defaults
+    }
+
+    /// The daemon's own options, as a `Value::Object`, or `Value::Null` if there are none
+    #[must_use]
+    pub fn daemon(&self) -> &Value {
+        &self.daemon
+    }
+
+    /// The cache's options, as a `Value::Object`, or `Value::Null` if there are none
+    #[must_use]
+    pub fn cache(&self) -> &Value {
+        &self.cache
+    }
+
+    /// The outputs that have settings of their own
+    pub fn outputs(&self) -> impl Iterator<Item = &str> {
+        self.outputs.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// The settings for `output`, falling back to the defaults for the ones it does not set
+    #[must_use]
+    pub fn for_output(&self, output: &str) -> Value {
+        match self.outputs.iter().find(|(name, _)| name == output) {
+            Some((_, settings)) => with_defaults(settings, &self.defaults),
+            None => self.defaults.clone(),
+        }
+    }
+}
+
+fn check_key(key: &str, valid: &[&str]) -> Result<(), String> {
+    if valid.contains(&key) {
+        Ok(())
+    } else {
+        Err(format!(
+            "unknown setting '{key}'. Valid settings are: {}",
+            valid.join(" | ")
+        ))
+    }
+}
+
+/// Adds the fields of `defaults` that `value` does not have. Both must be `Value::Object`s,
+/// otherwise we return `value` unchanged
+#[must_use]
+pub fn with_defaults(value: &Value, defaults: &Value) -> Value {
+    let (Value::Object(fields), Value::Object(defaults)) = (value, defaults) else {
+        return value.clone();
+    };
+    let mut fields = fields.clone();
+    for (key, default) in defaults {
+        if !fields.contains_key(key) {
+            fields.insert(key.clone(), default.clone());
+        }
+    }
+    Value::Object(fields)
+}
+
+/// The path to `name` inside our configuration directory, `$XDG_CONFIG_HOME/swww` (or
+/// `$HOME/.config/swww`)
+#[must_use]
+pub fn config_file(name: &str) -> Option<PathBuf> {
+    let mut path: PathBuf = match std::env::var("XDG_CONFIG_HOME") {
+        Ok(path) => path.into(),
+        Err(_) => PathBuf::from(std::env::var("HOME").ok()?).join(".config"),
+    };
+    path.push("swww");
+    path.push(name);
+    Some(path)
+}
+
+/// Turns a path written in a configuration file into an absolute one. A leading `~/` refers to
+/// the home directory
+pub fn resolve_path(path: &str) -> Result<String, String> {
+    let expanded = match path.strip_prefix("~/") {
+        Some(rest) => PathBuf::from(std::env::var("HOME").unwrap_or_default()).join(rest),
+        None => PathBuf::from(path),
+    };
+    match std::fs::canonicalize(expanded) {
+        Ok(p) => Ok(p.to_string_lossy().to_string()),
+        Err(e) => Err(format!("failed to canonicalize image path '{path}': {e}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_fall_back_to_the_defaults() {
+        let config = Config::parse(
+            r#"
+            resize = "fit"
+            transition_type = "fade"
+
+            [outputs.DP-1]
+            resize = "crop"
+            image = "/tmp/img.png"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.outputs().collect::<Vec<_>>(), ["DP-1"]);
+
+        let dp1 = config.for_output("DP-1");
+        assert_eq!(dp1.get("resize").and_then(Value::as_str), Some("crop"));
+        assert_eq!(
+            dp1.get("transition_type").and_then(Value::as_str),
+            Some("fade")
+        );
+        assert_eq!(
+            dp1.get("image").and_then(Value::as_str),
+            Some("/tmp/img.png")
+        );
+
+        let other = config.for_output("HDMI-A-1");
+        assert_eq!(other.get("resize").and_then(Value::as_str), Some("fit"));
+        assert!(other.get("image").is_none());
+    }
+
+    #[test]
+    fn should_reject_unknown_settings() {
+        assert!(Config::parse("resize = \"fit\"").is_ok());
+        assert!(Config::parse("rezise = \"fit\"").is_err());
+        assert!(Config::parse("[outputs.DP-1]\ntransition = \"fade\"").is_err());
+        assert!(Config::parse("outputs = \"DP-1\"").is_err());
+        assert!(Config::parse("[daemon]\nlog_level = \"info\"").is_ok());
+        assert!(Config::parse("[daemon]\nresize = \"fit\"").is_err());
+        assert!(Config::parse("log_level = \"info\"").is_err());
+        assert!(Config::parse("[cache]\nmax_size = 256").is_ok());
+        assert!(Config::parse("[cache]\nlog_level = \"info\"").is_err());
+        assert_eq!(Config::parse("").unwrap(), Config::new());
+    }
+}
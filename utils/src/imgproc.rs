@@ -0,0 +1,638 @@
+use fast_image_resize::{FilterType, PixelType, Resizer};
+use image::{
+    codecs::{gif::GifDecoder, png::PngDecoder, webp::WebPDecoder},
+    AnimationDecoder, DynamicImage, Frames, GenericImageView, ImageFormat,
+};
+use std::{
+    io::{stdin, Cursor, Read},
+    num::NonZeroU32,
+    path::Path,
+    time::Duration,
+};
+
+use crate::{
+    compression::{BitPack, Compressor},
+    ipc::{Filter, PixelFormat, ResizeStrategy},
+    mmap::Mmap,
+};
+
+pub struct ImgBuf {
+    bytes: Box<[u8]>,
+    format: ImageFormat,
+    is_animated: bool,
+}
+
+impl ImgBuf {
+    /// Create a new ImgBuf from a given path. Use - for Stdin
+    pub fn new(path: &Path) -> Result<Self, String> {
+        let bytes = if let Some("-") = path.to_str() {
+            let mut bytes = Vec::new();
+            stdin()
+                .read_to_end(&mut bytes)
+                .map_err(|e| format!("failed to read standard input: {e}"))?;
+            bytes
+        } else {
+            std::fs::read(path).map_err(|e| format!("failed to read file: {e}"))?
+        };
+
+        let reader = image::io::Reader::new(Cursor::new(&bytes))
+            .with_guessed_format()
+            .map_err(|e| format!("failed to detect the image's format: {e}"))?;
+
+        let format = reader.format();
+        let is_animated = match format {
+            Some(ImageFormat::Gif) => true,
+            Some(ImageFormat::WebP) => WebPDecoder::new(Cursor::new(&bytes))
+                .map_err(|e| format!("failed to decode Webp Image: {e}"))?
+                .has_animation(),
+            Some(ImageFormat::Png) => PngDecoder::new(Cursor::new(&bytes))
+                .map_err(|e| format!("failed to decode Png Image: {e}"))?
+                .is_apng()
+                .map_err(|e| format!("failed to detect if Png is animated: {e}"))?,
+            None => return Err("Unknown image format".to_string()),
+            _ => false,
+        };
+
+        Ok(Self {
+            format: format.unwrap(), // this is ok because we return err earlier if it is None
+            bytes: bytes.into_boxed_slice(),
+            is_animated,
+        })
+    }
+
+    /// The image file's contents, still encoded
+    #[inline]
+    #[must_use]
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    #[inline]
+    pub fn is_animated(&self) -> bool {
+        self.is_animated
+    }
+
+    /// Decode the ImgBuf into am RgbImage
+    pub fn decode(&self, format: PixelFormat) -> Result<Image, String> {
+        let mut reader = image::io::Reader::new(Cursor::new(&self.bytes));
+        reader.set_format(self.format);
+        let dynimage = reader
+            .decode()
+            .map_err(|e| format!("failed to decode image: {e}"))?;
+
+        let width = dynimage.width();
+        let height = dynimage.height();
+
+        let bytes = {
+            let mut img = if format.channels() == 3 {
+                dynimage.into_rgb8().into_raw().into_boxed_slice()
+            } else {
+                dynimage.into_rgba8().into_raw().into_boxed_slice()
+            };
+
+            if format.must_swap_r_and_b_channels() {
+                for pixel in img.chunks_exact_mut(format.channels() as usize) {
+                    pixel.swap(0, 2);
+                }
+            }
+            img
+        };
+
+        Ok(Image {
+            width,
+            height,
+            bytes,
+            format,
+        })
+    }
+
+    /// Convert this ImgBuf into Frames
+    pub fn as_frames(&self) -> Result<Frames, String> {
+        match self.format {
+            ImageFormat::Gif => Ok(GifDecoder::new(Cursor::new(&self.bytes))
+                .map_err(|e| format!("failed to decode gif during animation: {e}"))?
+                .into_frames()),
+            ImageFormat::WebP => Ok(WebPDecoder::new(Cursor::new(&self.bytes))
+                .map_err(|e| format!("failed to decode webp during animation: {e}"))?
+                .into_frames()),
+            ImageFormat::Png => Ok(PngDecoder::new(Cursor::new(&self.bytes))
+                .map_err(|e| format!("failed to decode png during animation: {e}"))?
+                .apng()
+                .unwrap() // we detected this earlier
+                .into_frames()),
+            _ => Err(format!(
+                "requested format has no decoder: {:#?}",
+                self.format
+            )),
+        }
+    }
+}
+
+/// Whether the file's extension is one of an image format we can decode. This does not look at the
+/// file's contents
+#[must_use]
+pub fn is_image_path(path: &Path) -> bool {
+    ImageFormat::from_path(path).is_ok()
+}
+
+/// Created by decoding an ImgBuf
+pub struct Image {
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    bytes: Box<[u8]>,
+}
+
+impl Image {
+    #[must_use]
+    fn crop(&self, x: u32, y: u32, width: u32, height: u32) -> Self {
+        // make sure we don't crop a region larger than the image
+        let x = x.min(self.width) as usize;
+        let y = y.min(self.height) as usize;
+        let width = (width as usize).min(self.width as usize - x);
+        let height = (height as usize).min(self.height as usize - y);
+
+        let mut bytes = Vec::with_capacity(width * height * self.format.channels() as usize);
+
+        let begin = ((y * self.width as usize) + x) * self.format.channels() as usize;
+        let stride = self.width as usize * self.format.channels() as usize;
+        let row_size = width * self.format.channels() as usize;
+
+        for row_index in 0..height {
+            let row = begin + row_index * stride;
+            bytes.extend_from_slice(&self.bytes[row..row + row_size]);
+        }
+
+        Self {
+            width: width as u32,
+            height: height as u32,
+            bytes: bytes.into_boxed_slice(),
+            format: self.format,
+        }
+    }
+
+    fn from_frame(frame: image::Frame, format: PixelFormat) -> Self {
+        let dynimage = DynamicImage::ImageRgba8(frame.into_buffer());
+        let (width, height) = dynimage.dimensions();
+
+        // NOTE: when animating frames, we ALWAYS use 3 channels
+
+        let format = match format {
+            PixelFormat::Bgr | PixelFormat::Xbgr => PixelFormat::Bgr,
+            PixelFormat::Rgb | PixelFormat::Xrgb => PixelFormat::Rgb,
+        };
+
+        let mut bytes = dynimage.into_rgb8().into_raw().into_boxed_slice();
+        if format.must_swap_r_and_b_channels() {
+            for pixel in bytes.chunks_exact_mut(3) {
+                pixel.swap(0, 2);
+            }
+        }
+
+        Self {
+            width,
+            height,
+            format,
+            bytes,
+        }
+    }
+}
+
+pub fn compress_frames(
+    mut frames: Frames,
+    dim: (u32, u32),
+    format: PixelFormat,
+    filter: FilterType,
+    resize: ResizeStrategy,
+    color: &[u8; 3],
+) -> Result<Vec<(BitPack, Duration)>, String> {
+    let mut compressor = Compressor::new();
+    let mut compressed_frames = Vec::new();
+
+    // The first frame should always exist
+    let first = frames.next().unwrap().unwrap();
+    let first_duration = first.delay().numer_denom_ms();
+    let mut first_duration = Duration::from_millis((first_duration.0 / first_duration.1).into());
+    let first_img = Image::from_frame(first, format);
+    let first_img = img_resize(&first_img, dim, resize, filter, color)?;
+
+    let mut canvas: Option<Box<[u8]>> = None;
+    while let Some(Ok(frame)) = frames.next() {
+        let (dur_num, dur_div) = frame.delay().numer_denom_ms();
+        let duration = Duration::from_millis((dur_num / dur_div).into());
+
+        let img = Image::from_frame(frame, format);
+        let img = img_resize(&img, dim, resize, filter, color)?;
+
+        if let Some(canvas) = canvas.as_ref() {
+            match compressor.compress(canvas, &img, format) {
+                Some(bytes) => compressed_frames.push((bytes, duration)),
+                None => match compressed_frames.last_mut() {
+                    Some(last) => last.1 += duration,
+                    None => first_duration += duration,
+                },
+            }
+        } else {
+            match compressor.compress(&first_img, &img, format) {
+                Some(bytes) => compressed_frames.push((bytes, duration)),
+                None => first_duration += duration,
+            }
+        }
+        canvas = Some(img);
+    }
+
+    //Add the first frame we got earlier:
+    if let Some(canvas) = canvas.as_ref() {
+        match compressor.compress(canvas, &first_img, format) {
+            Some(bytes) => compressed_frames.push((bytes, first_duration)),
+            None => match compressed_frames.last_mut() {
+                Some(last) => last.1 += first_duration,
+                None => first_duration += first_duration,
+            },
+        }
+    }
+
+    Ok(compressed_frames)
+}
+
+#[must_use]
+pub fn make_filter(filter: &Filter) -> FilterType {
+    match filter {
+        Filter::Nearest => FilterType::Box,
+        Filter::Bilinear => FilterType::Bilinear,
+        Filter::CatmullRom => FilterType::CatmullRom,
+        Filter::Mitchell => FilterType::Mitchell,
+        Filter::Lanczos3 => FilterType::Lanczos3,
+    }
+}
+
+/// Resizes the image according to `resize`, returning a buffer with exactly `dimensions` pixels
+pub fn img_resize(
+    img: &Image,
+    dimensions: (u32, u32),
+    resize: ResizeStrategy,
+    filter: FilterType,
+    fill_color: &[u8; 3],
+) -> Result<Box<[u8]>, String> {
+    let mut resized = vec![0; resized_len(img, dimensions)];
+    img_resize_into(img, dimensions, resize, filter, fill_color, &mut resized)?;
+    Ok(resized.into_boxed_slice())
+}
+
+/// Like `img_resize`, but resizes straight into a sealed memfd, ready to be sent through the socket
+pub fn img_resize_mmap(
+    img: &Image,
+    dimensions: (u32, u32),
+    resize: ResizeStrategy,
+    filter: FilterType,
+    fill_color: &[u8; 3],
+) -> Result<Mmap, String> {
+    let mut mmap = Mmap::create(resized_len(img, dimensions))?;
+    img_resize_into(
+        img,
+        dimensions,
+        resize,
+        filter,
+        fill_color,
+        mmap.slice_mut(),
+    )?;
+    mmap.seal()?;
+    Ok(mmap)
+}
+
+/// Like `img_resize`, but writes the result into `out`, which must hold exactly `dimensions`
+/// pixels
+pub fn img_resize_into(
+    img: &Image,
+    dimensions: (u32, u32),
+    resize: ResizeStrategy,
+    filter: FilterType,
+    fill_color: &[u8; 3],
+    out: &mut [u8],
+) -> Result<(), String> {
+    if out.len() != resized_len(img, dimensions) {
+        return Err(format!(
+            "cannot resize a {}x{} image into a buffer of {} bytes",
+            dimensions.0,
+            dimensions.1,
+            out.len()
+        ));
+    }
+    match resize {
+        ResizeStrategy::No => img_pad(img, dimensions, fill_color, out),
+        ResizeStrategy::Crop => img_resize_crop(img, dimensions, filter, out),
+        ResizeStrategy::Fit => img_resize_fit(img, dimensions, filter, fill_color, out),
+    }
+}
+
+fn resized_len(img: &Image, dimensions: (u32, u32)) -> usize {
+    dimensions.0 as usize * dimensions.1 as usize * img.format.channels() as usize
+}
+
+/// Centers the image in `out`, filling the rest with `color`. Images larger than `dimensions` get
+/// cropped around their center.
+pub fn img_pad(
+    img: &Image,
+    dimensions: (u32, u32),
+    color: &[u8; 3],
+    out: &mut [u8],
+) -> Result<(), String> {
+    let channels = img.format.channels() as usize;
+
+    let mut color3 = color.to_owned();
+    let mut color4 = [color[0], color[1], color[2], 255];
+    let color: &mut [u8] = if channels == 3 {
+        &mut color3
+    } else {
+        &mut color4
+    };
+
+    if img.format.must_swap_r_and_b_channels() {
+        color.swap(0, 2);
+    }
+    let (padded_w, padded_h) = dimensions;
+    let (padded_w, padded_h) = (padded_w as usize, padded_h as usize);
+
+    let img = if img.width > dimensions.0 || img.height > dimensions.1 {
+        let left = img.width.saturating_sub(dimensions.0) / 2;
+        let top = img.height.saturating_sub(dimensions.1) / 2;
+        img.crop(left, top, dimensions.0, dimensions.1)
+    } else {
+        img.crop(0, 0, dimensions.0, dimensions.1)
+    };
+
+    let (img_w, img_h) = (
+        (img.width as usize).min(padded_w),
+        (img.height as usize).min(padded_h),
+    );
+
+    for pixel in out.chunks_exact_mut(channels) {
+        pixel.copy_from_slice(color);
+    }
+
+    let top = (padded_h - img_h) / 2;
+    let left = (padded_w - img_w) / 2;
+    let row_len = img_w * channels;
+    for row in 0..img_h {
+        let start = ((top + row) * padded_w + left) * channels;
+        out[start..start + row_len].copy_from_slice(&img.bytes[row * row_len..(row + 1) * row_len]);
+    }
+
+    Ok(())
+}
+
+/// Resize an image to fit within the given dimensions, covering as much space as possible without
+/// cropping.
+pub fn img_resize_fit(
+    img: &Image,
+    dimensions: (u32, u32),
+    filter: FilterType,
+    padding_color: &[u8; 3],
+    out: &mut [u8],
+) -> Result<(), String> {
+    let (width, height) = dimensions;
+    if (img.width, img.height) != (width, height) {
+        // if our image is already scaled to fit, skip resizing it and just pad it directly
+        if img.width == width || img.height == height {
+            return img_pad(img, dimensions, padding_color, out);
+        }
+
+        let ratio = width as f32 / height as f32;
+        let img_r = img.width as f32 / img.height as f32;
+
+        let (trg_w, trg_h) = if ratio > img_r {
+            let scale = height as f32 / img.height as f32;
+            ((img.width as f32 * scale) as u32, height)
+        } else {
+            let scale = width as f32 / img.width as f32;
+            (width, (img.height as f32 * scale) as u32)
+        };
+
+        let pixel_type = if img.format.channels() == 3 {
+            PixelType::U8x3
+        } else {
+            PixelType::U8x4
+        };
+        let src = match fast_image_resize::Image::from_vec_u8(
+            // We unwrap below because we know the images's dimensions should never be 0
+            NonZeroU32::new(img.width).unwrap(),
+            NonZeroU32::new(img.height).unwrap(),
+            img.bytes.to_vec(),
+            pixel_type,
+        ) {
+            Ok(i) => i,
+            Err(e) => return Err(e.to_string()),
+        };
+
+        // We unwrap below because we know the outputs's dimensions should never be 0
+        let new_w = NonZeroU32::new(trg_w).unwrap();
+        let new_h = NonZeroU32::new(trg_h).unwrap();
+
+        let mut dst = fast_image_resize::Image::new(new_w, new_h, pixel_type);
+        let mut dst_view = dst.view_mut();
+
+        let mut resizer = Resizer::new(fast_image_resize::ResizeAlg::Convolution(filter));
+        if let Err(e) = resizer.resize(&src.view(), &mut dst_view) {
+            return Err(e.to_string());
+        }
+
+        let img = Image {
+            width: trg_w,
+            height: trg_h,
+            format: img.format,
+            bytes: dst.into_vec().into_boxed_slice(),
+        };
+        img_pad(&img, dimensions, padding_color, out)
+    } else {
+        out.copy_from_slice(&img.bytes);
+        Ok(())
+    }
+}
+
+pub fn img_resize_crop(
+    img: &Image,
+    dimensions: (u32, u32),
+    filter: FilterType,
+    out: &mut [u8],
+) -> Result<(), String> {
+    let (width, height) = dimensions;
+    if (img.width, img.height) != (width, height) {
+        let pixel_type = if img.format.channels() == 3 {
+            PixelType::U8x3
+        } else {
+            PixelType::U8x4
+        };
+        let src = match fast_image_resize::Image::from_vec_u8(
+            // We unwrap below because we know the images's dimensions should never be 0
+            NonZeroU32::new(img.width).unwrap(),
+            NonZeroU32::new(img.height).unwrap(),
+            img.bytes.to_vec(),
+            pixel_type,
+        ) {
+            Ok(i) => i,
+            Err(e) => return Err(e.to_string()),
+        };
+
+        // We unwrap below because we know the outputs's dimensions should never be 0
+        let new_w = NonZeroU32::new(width).unwrap();
+        let new_h = NonZeroU32::new(height).unwrap();
+        let mut src_view = src.view();
+        src_view.set_crop_box_to_fit_dst_size(new_w, new_h, Some((0.5, 0.5)));
+
+        let mut dst = match fast_image_resize::Image::from_slice_u8(new_w, new_h, out, pixel_type) {
+            Ok(i) => i,
+            Err(e) => return Err(e.to_string()),
+        };
+        let mut dst_view = dst.view_mut();
+
+        let mut resizer = Resizer::new(fast_image_resize::ResizeAlg::Convolution(filter));
+        if let Err(e) = resizer.resize(&src_view, &mut dst_view) {
+            return Err(e.to_string());
+        }
+    } else {
+        out.copy_from_slice(&img.bytes);
+    }
+
+    Ok(())
+}
+
+/// Where an output sits in the compositor's global space, for spanning one image across several
+/// outputs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SpanOutput {
+    /// Logical position
+    pub position: (i32, i32),
+    /// Logical dimensions
+    pub dim: (u32, u32),
+    /// Dimensions in physical pixels
+    pub real_dim: (u32, u32),
+}
+
+/// Cuts `img` into one sealed memfd per output, in the same order as `outputs`, so that the pieces line
+/// up with the outputs' physical layout. Any gaps between outputs swallow the corresponding parts
+/// of the image.
+///
+/// The image is first resized (according to `resize`) to the outputs' bounding box, at the highest
+/// pixel density among them. That way, outputs with mixed scales all get a crop covering the same
+/// logical area, and no output gets an upscaled one.
+pub fn img_span(
+    img: &Image,
+    outputs: &[SpanOutput],
+    resize: ResizeStrategy,
+    filter: FilterType,
+    fill_color: &[u8; 3],
+) -> Result<Vec<Mmap>, String> {
+    let (canvas_dim, crops) = span_crops(outputs)?;
+    let canvas = Image {
+        width: canvas_dim.0,
+        height: canvas_dim.1,
+        format: img.format,
+        bytes: img_resize(img, canvas_dim, resize, filter, fill_color)?,
+    };
+
+    let mut imgs = Vec::with_capacity(outputs.len());
+    for (output, (x, y, width, height)) in outputs.iter().zip(crops) {
+        let crop = canvas.crop(x, y, width, height);
+        imgs.push(img_resize_mmap(
+            &crop,
+            output.real_dim,
+            ResizeStrategy::Crop,
+            filter,
+            fill_color,
+        )?);
+    }
+    Ok(imgs)
+}
+
+type SpanCrops = ((u32, u32), Vec<(u32, u32, u32, u32)>);
+
+/// Returns the dimensions of the canvas covering all outputs, and each output's `(x, y, width,
+/// height)` rectangle in it
+fn span_crops(outputs: &[SpanOutput]) -> Result<SpanCrops, String> {
+    if outputs.is_empty() {
+        return Err("cannot span an image across zero outputs".to_string());
+    }
+    if outputs.iter().any(|o| o.dim.0 == 0 || o.dim.1 == 0) {
+        return Err("cannot span an image across outputs of unknown dimensions".to_string());
+    }
+
+    let left = outputs.iter().map(|o| o.position.0).min().unwrap_or(0);
+    let top = outputs.iter().map(|o| o.position.1).min().unwrap_or(0);
+    let right = outputs
+        .iter()
+        .map(|o| o.position.0 + o.dim.0 as i32)
+        .max()
+        .unwrap_or(0);
+    let bottom = outputs
+        .iter()
+        .map(|o| o.position.1 + o.dim.1 as i32)
+        .max()
+        .unwrap_or(0);
+
+    let density = outputs
+        .iter()
+        .map(|o| o.real_dim.0 as f64 / o.dim.0 as f64)
+        .fold(1.0, f64::max);
+    let scale = |logical: i32| (logical as f64 * density).round() as u32;
+
+    let canvas_dim = (scale(right - left), scale(bottom - top));
+    let crops = outputs
+        .iter()
+        .map(|o| {
+            (
+                scale(o.position.0 - left),
+                scale(o.position.1 - top),
+                scale(o.dim.0 as i32),
+                scale(o.dim.1 as i32),
+            )
+        })
+        .collect();
+    Ok((canvas_dim, crops))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(position: (i32, i32), dim: (u32, u32), scale: u32) -> SpanOutput {
+        SpanOutput {
+            position,
+            dim,
+            real_dim: (dim.0 * scale, dim.1 * scale),
+        }
+    }
+
+    #[test]
+    fn span_should_leave_gaps_between_outputs() {
+        let outputs = [
+            output((0, 0), (1920, 1080), 1),
+            output((2020, 0), (1920, 1080), 1),
+        ];
+        let (canvas, crops) = span_crops(&outputs).unwrap();
+        assert_eq!(canvas, (3940, 1080));
+        assert_eq!(crops, vec![(0, 0, 1920, 1080), (2020, 0, 1920, 1080)]);
+    }
+
+    #[test]
+    fn span_should_use_the_highest_density() {
+        // a 4k laptop screen at scale 2, below and to the right of a 1080p monitor
+        let outputs = [
+            output((0, 0), (1920, 1080), 1),
+            output((960, 1080), (1920, 1080), 2),
+        ];
+        let (canvas, crops) = span_crops(&outputs).unwrap();
+        assert_eq!(canvas, (5760, 4320));
+        assert_eq!(crops, vec![(0, 0, 3840, 2160), (1920, 2160, 3840, 2160)]);
+    }
+
+    #[test]
+    fn span_should_handle_negative_positions() {
+        let outputs = [
+            output((-1080, -500), (1080, 1920), 1),
+            output((0, 0), (1920, 1080), 1),
+        ];
+        let (canvas, crops) = span_crops(&outputs).unwrap();
+        assert_eq!(canvas, (3000, 1920));
+        assert_eq!(crops, vec![(0, 0, 1080, 1920), (1080, 500, 1920, 1080)]);
+    }
+}
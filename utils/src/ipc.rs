@@ -1,13 +1,23 @@
 use bitcode::{Decode, Encode};
 use std::{
     fmt,
-    io::{BufReader, BufWriter, Read, Write},
-    os::unix::net::UnixStream,
+    io::{IoSlice, IoSliceMut, Read, Write},
+    os::{
+        fd::{AsFd, BorrowedFd, OwnedFd},
+        unix::net::UnixStream,
+    },
     path::{Path, PathBuf},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use crate::{cache, compression::BitPack};
+use rustix::net::{
+    RecvAncillaryBuffer, RecvAncillaryMessage, RecvFlags, SendAncillaryBuffer,
+    SendAncillaryMessage, SendFlags,
+};
+
+use serde_json::{json, Value};
+
+use crate::{compression::BitPack, mmap::Mmap, selector::OutputId, transitions};
 
 #[derive(Clone, PartialEq, Decode, Encode)]
 pub enum Coord {
@@ -76,6 +86,19 @@ pub enum BgImg {
     Img(String),
 }
 
+impl BgImg {
+    /// Either `{"image": <path>}` or `{"color": "rrggbb"}`
+    #[must_use]
+    pub fn to_json(&self) -> Value {
+        match self {
+            BgImg::Color(color) => {
+                json!({ "color": format!("{:02x}{:02x}{:02x}", color[0], color[1], color[2]) })
+            }
+            BgImg::Img(path) => json!({ "image": path }),
+        }
+    }
+}
+
 impl fmt::Display for BgImg {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -134,23 +157,307 @@ impl PixelFormat {
     }
 }
 
+/// How to make an image fit an output's dimensions
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Decode, Encode)]
+pub enum ResizeStrategy {
+    /// Do not resize; center the image and pad it with the fill color
+    No,
+    /// Resize to fill the whole output, cropping out what doesn't fit
+    #[default]
+    Crop,
+    /// Resize to fit inside the output, preserving the aspect ratio and padding the rest
+    Fit,
+}
+
+impl std::str::FromStr for ResizeStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "no" => Ok(Self::No),
+            "crop" => Ok(Self::Crop),
+            "fit" => Ok(Self::Fit),
+            _ => Err(format!(
+                "unrecognized resize strategy '{s}'. Valid values are: no | crop | fit"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ResizeStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::No => "no",
+            Self::Crop => "crop",
+            Self::Fit => "fit",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// Filter used when scaling images
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Decode, Encode)]
+pub enum Filter {
+    Nearest,
+    Bilinear,
+    CatmullRom,
+    Mitchell,
+    #[default]
+    Lanczos3,
+}
+
+impl std::str::FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Nearest" => Ok(Self::Nearest),
+            "Bilinear" => Ok(Self::Bilinear),
+            "CatmullRom" => Ok(Self::CatmullRom),
+            "Mitchell" => Ok(Self::Mitchell),
+            "Lanczos3" => Ok(Self::Lanczos3),
+            _ => Err(format!(
+                "unrecognized filter '{s}'. Valid filters are: \
+                 Nearest | Bilinear | CatmullRom | Mitchell | Lanczos3"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+/// An output's scale factor
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Decode, Encode)]
+pub enum Scale {
+    /// The integer scale from `wl_output` or `wl_surface`
+    Whole(i32),
+    /// The preferred scale from `wp_fractional_scale_v1`, in 120ths (so `180` means 1.5)
+    Fractional(i32),
+}
+
+impl Scale {
+    /// Fractional scales are more precise, so once we receive one we ignore the whole ones
+    #[inline]
+    #[must_use]
+    pub const fn priority(&self) -> u8 {
+        match self {
+            Self::Whole(_) => 0,
+            Self::Fractional(_) => 1,
+        }
+    }
+
+    /// Turns logical dimensions into buffer dimensions, rounding halfway away from zero like the
+    /// fractional scale protocol asks us to
+    #[inline]
+    #[must_use]
+    pub const fn mul_dim(&self, width: u32, height: u32) -> (u32, u32) {
+        match self {
+            Self::Whole(s) => (width * *s as u32, height * *s as u32),
+            Self::Fractional(s) => {
+                let s = *s as u32;
+                ((width * s + 60) / 120, (height * s + 60) / 120)
+            }
+        }
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn as_f32(&self) -> f32 {
+        match self {
+            Self::Whole(s) => *s as f32,
+            Self::Fractional(s) => *s as f32 / 120.0,
+        }
+    }
+
+    /// An integer when the scale is whole, so that scripts get `2` rather than `2.0`
+    #[must_use]
+    pub fn to_json(&self) -> Value {
+        match self {
+            Self::Whole(s) => (*s).into(),
+            Self::Fractional(s) if s % 120 == 0 => (s / 120).into(),
+            Self::Fractional(s) => (*s as f64 / 120.0).into(),
+        }
+    }
+}
+
+impl fmt::Display for Scale {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Whole(s) => write!(f, "{s}"),
+            Self::Fractional(s) => write!(f, "{}", *s as f32 / 120.0),
+        }
+    }
+}
+
+/// An output's transform (mirrors `wl_output::Transform`). Rotations are counter-clockwise
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Decode, Encode)]
+pub enum Transform {
+    #[default]
+    Normal,
+    _90,
+    _180,
+    _270,
+    Flipped,
+    Flipped90,
+    Flipped180,
+    Flipped270,
+}
+
+impl Transform {
+    /// Whether the output's width and height are swapped by this transform
+    #[inline]
+    #[must_use]
+    pub const fn swaps_dimensions(&self) -> bool {
+        matches!(
+            self,
+            Self::_90 | Self::_270 | Self::Flipped90 | Self::Flipped270
+        )
+    }
+}
+
+impl fmt::Display for Transform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Normal => "normal",
+            Self::_90 => "90",
+            Self::_180 => "180",
+            Self::_270 => "270",
+            Self::Flipped => "flipped",
+            Self::Flipped90 => "flipped-90",
+            Self::Flipped180 => "flipped-180",
+            Self::Flipped270 => "flipped-270",
+        };
+        write!(f, "{s}")
+    }
+}
+
 #[derive(Clone, Decode, Encode)]
 pub struct BgInfo {
     pub name: String,
+    /// What the compositor advertises about the monitor. These let `utils::selector` find outputs
+    /// by something more stable than their names
+    pub description: String,
+    pub make: String,
+    pub model: String,
+    /// Logical position in the compositor's global space
+    pub position: (i32, i32),
+    /// Logical dimensions, with the transform already applied
     pub dim: (u32, u32),
-    pub scale_factor: i32,
+    pub scale_factor: Scale,
+    pub transform: Transform,
     pub img: BgImg,
     pub pixel_format: PixelFormat,
+    /// Whether an animated image is playing
+    pub animating: bool,
+    /// Whether a transition to a new image is running
+    pub transitioning: bool,
 }
 
 impl BgInfo {
     #[inline]
     #[must_use]
     pub fn real_dim(&self) -> (u32, u32) {
-        (
-            self.dim.0 * self.scale_factor as u32,
-            self.dim.1 * self.scale_factor as u32,
-        )
+        self.scale_factor.mul_dim(self.dim.0, self.dim.1)
+    }
+
+    #[must_use]
+    pub fn output_id(&self) -> OutputId<'_> {
+        OutputId {
+            name: &self.name,
+            description: &self.description,
+            make: &self.make,
+            model: &self.model,
+        }
+    }
+
+    /// Scripts rely on these fields (see `swww-query(1)`), so we must only ever add new ones
+    #[must_use]
+    pub fn to_json(&self) -> Value {
+        let (physical_width, physical_height) = self.real_dim();
+        json!({
+            "name": self.name,
+            "description": self.description,
+            "make": self.make,
+            "model": self.model,
+            "x": self.position.0,
+            "y": self.position.1,
+            "width": self.dim.0,
+            "height": self.dim.1,
+            "physical_width": physical_width,
+            "physical_height": physical_height,
+            "scale": self.scale_factor.to_json(),
+            "transform": self.transform.to_string(),
+            "displaying": self.img.to_json(),
+            "pixel_format": format!("{:?}", self.pixel_format).to_lowercase(),
+            "animating": self.animating,
+            "transitioning": self.transitioning,
+        })
+    }
+}
+
+/// Changes the daemon notifies its subscribers about
+#[derive(Clone, Decode, Encode)]
+pub enum Event {
+    OutputAdded(BgInfo),
+    /// The output's dimensions or scale factor changed
+    OutputChanged(BgInfo),
+    OutputRemoved(String),
+    ImageChanged {
+        output: String,
+        img: BgImg,
+    },
+    TransitionStarted(String),
+    TransitionFinished(String),
+    AnimationStopped(String),
+}
+
+impl Event {
+    #[must_use]
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::OutputAdded(_) => "output_added",
+            Self::OutputChanged(_) => "output_changed",
+            Self::OutputRemoved(_) => "output_removed",
+            Self::ImageChanged { .. } => "image_changed",
+            Self::TransitionStarted(_) => "transition_started",
+            Self::TransitionFinished(_) => "transition_finished",
+            Self::AnimationStopped(_) => "animation_stopped",
+        }
+    }
+
+    #[must_use]
+    pub fn to_json(&self) -> Value {
+        match self {
+            Self::OutputAdded(info) | Self::OutputChanged(info) => {
+                json!({ "event": self.name(), "output": info.to_json() })
+            }
+            Self::ImageChanged { output, img } => {
+                json!({ "event": self.name(), "output": output, "displaying": img.to_json() })
+            }
+            Self::OutputRemoved(output)
+            | Self::TransitionStarted(output)
+            | Self::TransitionFinished(output)
+            | Self::AnimationStopped(output) => json!({ "event": self.name(), "output": output }),
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutputAdded(info) | Self::OutputChanged(info) => {
+                write!(f, "{}: {info}", self.name())
+            }
+            Self::ImageChanged { output, img } => write!(f, "{}: {output}, {img}", self.name()),
+            Self::OutputRemoved(output)
+            | Self::TransitionStarted(output)
+            | Self::TransitionFinished(output)
+            | Self::AnimationStopped(output) => write!(f, "{}: {output}", self.name()),
+        }
     }
 }
 
@@ -158,45 +465,121 @@ impl fmt::Display for BgInfo {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "{}: {}x{}, scale: {}, currently displaying: {}",
-            self.name, self.dim.0, self.dim.1, self.scale_factor, self.img
-        )
+            "{}: {}x{}, scale: {}, ",
+            self.name, self.dim.0, self.dim.1, self.scale_factor
+        )?;
+        // keep the usual output unchanged for the vast majority of monitors
+        if self.transform != Transform::Normal {
+            write!(f, "transform: {}, ", self.transform)?;
+        }
+        write!(f, "currently displaying: {}", self.img)
     }
 }
 
-#[derive(Clone, Copy, Decode, Encode)]
-pub enum TransitionType {
-    Simple,
-    Fade,
-    Outer,
-    Wipe,
-    Grow,
-    Wave,
+/// The name of one of the effects in `transitions::TRANSITIONS`. Parse it from a string to make
+/// sure it is one
+#[derive(Clone, Debug, PartialEq, Eq, Decode, Encode)]
+pub struct TransitionType(String);
+
+impl TransitionType {
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+
+    /// The step `swww img` uses for this effect when none is given: 2 for 'simple', which only
+    /// ever steps, and 90 for the others, which only step to finish off
+    #[must_use]
+    pub fn default_step(&self) -> u8 {
+        if self.0 == "simple" {
+            2
+        } else {
+            90
+        }
+    }
 }
 
-#[derive(Decode, Encode)]
+impl Default for TransitionType {
+    fn default() -> Self {
+        Self("simple".to_string())
+    }
+}
+
+impl std::str::FromStr for TransitionType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match transitions::find(s) {
+            Some(t) => Ok(Self(t.name.to_string())),
+            None => Err(format!(
+                "unrecognized transition type '{s}'. Valid transitions are: {}",
+                transitions::names()
+            )),
+        }
+    }
+}
+
+impl fmt::Display for TransitionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Clone, Decode, Encode)]
 pub struct Transition {
     pub transition_type: TransitionType,
     pub duration: f32,
     pub step: u8,
+    /// The most frames per second to draw. `0` means no limit: we draw a frame whenever the
+    /// compositor asks for one
     pub fps: u16,
     pub angle: f64,
     pub pos: Position,
     pub bezier: (f32, f32, f32, f32),
     pub wave: (f32, f32),
+    /// How many slats the blinds transitions have
+    pub slats: u16,
     pub invert_y: bool,
 }
 
+impl Default for Transition {
+    /// The defaults `swww img` uses for the 'simple' transition. Changing `transition_type` means
+    /// changing `step` to its `default_step` too
+    fn default() -> Self {
+        let transition_type = TransitionType::default();
+        Self {
+            step: transition_type.default_step(),
+            transition_type,
+            duration: 3.0,
+            fps: 0,
+            angle: 45.0,
+            pos: Position::new(Coord::Percent(0.5), Coord::Percent(0.5)),
+            bezier: (0.54, 0.0, 0.34, 0.99),
+            wave: (20.0, 20.0),
+            slats: 8,
+            invert_y: false,
+        }
+    }
+}
+
 #[derive(Decode, Encode)]
 pub struct Clear {
     pub color: [u8; 3],
     pub outputs: Box<[String]>,
 }
 
-#[derive(Decode, Encode)]
+/// The image's contents live in a sealed memfd, which we send alongside the request as a file
+/// descriptor
 pub struct Img {
     pub path: String,
-    pub img: Box<[u8]>,
+    pub img: Mmap,
+    /// How the image was resized. The daemon uses these to redo it when the output changes
+    pub resize: ResizeStrategy,
+    pub filter: Filter,
+    pub fill_color: [u8; 3],
+    /// Whether this is one piece of an image spanned across several outputs. The daemon cannot
+    /// redo those by itself, and we do not cache them
+    pub span: bool,
 }
 
 #[derive(Encode, Decode)]
@@ -207,10 +590,55 @@ pub struct Animation {
     pub pixel_format: PixelFormat,
 }
 
+/// An image for the daemon to decode and resize by itself
+#[derive(Clone, Decode, Encode)]
+pub struct ImgByPath {
+    pub path: String,
+    pub outputs: Box<[String]>,
+    pub resize: ResizeStrategy,
+    pub filter: Filter,
+    pub fill_color: [u8; 3],
+    pub transition: Transition,
+}
+
+/// Images for the daemon to cycle through by itself
+#[derive(Clone, Decode, Encode)]
+pub struct Playlist {
+    pub outputs: Box<[String]>,
+    /// Absolute paths to images, or to directories whose images will all be included
+    pub sources: Box<[String]>,
+    pub interval: Duration,
+    pub order: PlaylistOrder,
+    pub resize: ResizeStrategy,
+    pub filter: Filter,
+    pub fill_color: [u8; 3],
+    pub transition: Transition,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Decode, Encode)]
+pub enum PlaylistOrder {
+    #[default]
+    Sequential,
+    Shuffle,
+    ShuffleNoRepeat,
+}
+
+/// Every command other than `Start` applies to the playlists running on the given outputs. An
+/// empty slice means all of them
+#[derive(Clone, Decode, Encode)]
+pub enum PlaylistCommand {
+    /// Replaces the playlists running on the same outputs
+    Start(Playlist),
+    Next(Box<[String]>),
+    Prev(Box<[String]>),
+    Pause(Box<[String]>),
+    Resume(Box<[String]>),
+    Stop(Box<[String]>),
+}
+
 pub type AnimationRequest = Box<[(Animation, Box<[String]>)]>;
 pub type ImageRequest = (Transition, Box<[(Img, Box<[String]>)]>);
 
-#[derive(Decode, Encode)]
 pub enum Request {
     Animation(AnimationRequest),
     Clear(Clear),
@@ -218,49 +646,144 @@ pub enum Request {
     Kill,
     Query,
     Img(ImageRequest),
+    /// Keeps the connection open, so that the daemon can send `Answer::Event`s through it
+    Subscribe,
+    /// The daemon answers once it has finished decoding the image
+    ImgByPath(ImgByPath),
+    Playlist(PlaylistCommand),
+}
+
+/// What actually goes through the socket. It is the same as `Request`, except images only carry
+/// their paths. Their contents are sent as file descriptors, in the same order
+#[derive(Decode, Encode)]
+enum WireRequest {
+    Animation(AnimationRequest),
+    Clear(Clear),
+    Ping,
+    Kill,
+    Query,
+    Img(WireImageRequest),
+    Subscribe,
+    ImgByPath(ImgByPath),
+    Playlist(PlaylistCommand),
+}
+
+/// Like `ImageRequest`, but without the images' contents
+type WireImageRequest = (Transition, Box<[(WireImg, Box<[String]>)]>);
+
+#[derive(Decode, Encode)]
+struct WireImg {
+    path: String,
+    resize: ResizeStrategy,
+    filter: Filter,
+    fill_color: [u8; 3],
+    span: bool,
 }
 
 impl Request {
-    pub fn send(&self, stream: &UnixStream) -> Result<(), String> {
-        let bytes = bitcode::encode(self);
-        std::thread::scope(|s| {
-            if let Self::Animation(animations) = self {
-                s.spawn(|| {
-                    for (animation, _) in animations.iter() {
-                        // only store the cache if we aren't reading from stdin
-                        if animation.path != "-" {
-                            if let Err(e) = cache::store_animation_frames(animation) {
-                                eprintln!("Error storing cache for {}: {e}", animation.path);
-                            }
-                        }
-                    }
-                });
-            }
-            let mut writer = BufWriter::new(stream);
-            if let Err(e) = writer.write_all(&bytes.len().to_ne_bytes()) {
-                return Err(format!("failed to write serialized request's length: {e}"));
+    fn into_wire(self) -> (WireRequest, Vec<Mmap>) {
+        let mut mmaps = Vec::new();
+        let wire = match self {
+            Self::Animation(animations) => WireRequest::Animation(animations),
+            Self::Clear(clear) => WireRequest::Clear(clear),
+            Self::Ping => WireRequest::Ping,
+            Self::Kill => WireRequest::Kill,
+            Self::Query => WireRequest::Query,
+            Self::Img((transition, imgs)) => {
+                let mut wire_imgs = Vec::with_capacity(imgs.len());
+                for (img, outputs) in imgs.into_vec() {
+                    let Img {
+                        path,
+                        img,
+                        resize,
+                        filter,
+                        fill_color,
+                        span,
+                    } = img;
+                    let wire_img = WireImg {
+                        path,
+                        resize,
+                        filter,
+                        fill_color,
+                        span,
+                    };
+                    wire_imgs.push((wire_img, outputs));
+                    mmaps.push(img);
+                }
+                WireRequest::Img((transition, wire_imgs.into_boxed_slice()))
             }
-            if let Err(e) = writer.write_all(&bytes) {
-                Err(format!("failed to write serialized request: {e}"))
-            } else {
-                if let Self::Img((_, imgs)) = self {
-                    for (Img { path, .. }, outputs) in imgs.iter() {
-                        for output in outputs.iter() {
-                            if let Err(e) = super::cache::store(output, path) {
-                                eprintln!("ERROR: failed to store cache: {e}");
-                            }
-                        }
-                    }
+            Self::Subscribe => WireRequest::Subscribe,
+            Self::ImgByPath(img) => WireRequest::ImgByPath(img),
+            Self::Playlist(command) => WireRequest::Playlist(command),
+        };
+        (wire, mmaps)
+    }
+
+    fn from_wire(wire: WireRequest, fds: Vec<OwnedFd>) -> Result<Self, String> {
+        let expected_fds = match &wire {
+            WireRequest::Img((_, imgs)) => imgs.len(),
+            _ => 0,
+        };
+        if fds.len() != expected_fds {
+            return Err(format!(
+                "expected {expected_fds} file descriptors with request, but received {}",
+                fds.len()
+            ));
+        }
+        Ok(match wire {
+            WireRequest::Animation(animations) => Self::Animation(animations),
+            WireRequest::Clear(clear) => Self::Clear(clear),
+            WireRequest::Ping => Self::Ping,
+            WireRequest::Kill => Self::Kill,
+            WireRequest::Query => Self::Query,
+            WireRequest::Img((transition, wire_imgs)) => {
+                let mut imgs = Vec::with_capacity(wire_imgs.len());
+                for ((wire_img, outputs), fd) in wire_imgs.into_vec().into_iter().zip(fds) {
+                    let img = Img {
+                        path: wire_img.path,
+                        img: Mmap::from_fd(fd)?,
+                        resize: wire_img.resize,
+                        filter: wire_img.filter,
+                        fill_color: wire_img.fill_color,
+                        span: wire_img.span,
+                    };
+                    imgs.push((img, outputs));
                 }
-                Ok(())
+                Self::Img((transition, imgs.into_boxed_slice()))
             }
+            WireRequest::Subscribe => Self::Subscribe,
+            WireRequest::ImgByPath(img) => Self::ImgByPath(img),
+            WireRequest::Playlist(command) => Self::Playlist(command),
         })
     }
 
-    #[must_use]
-    #[inline]
-    pub fn receive(bytes: &[u8]) -> Self {
-        bitcode::decode(bytes).expect("failed to decode request")
+    pub fn send(self, stream: &UnixStream) -> Result<(), String> {
+        let (wire, mut mmaps) = self.into_wire();
+        for mmap in mmaps.iter_mut() {
+            mmap.seal()?;
+        }
+        let fds: Vec<BorrowedFd> = mmaps.iter().map(AsFd::as_fd).collect();
+        let bytes = bitcode::encode(&wire);
+        write_message(stream, &bytes, &fds)
+            .map_err(|e| format!("failed to write serialized request: {e}"))
+    }
+
+    /// Decodes a request, failing if it was sent with a different protocol version or if its
+    /// payload is malformed. Images are mapped from the file descriptors sent with the message.
+    ///
+    /// Clients of any version learn ours through `handshake` before sending requests, so they can
+    /// tell the user what is wrong instead of getting an answer they cannot decode
+    pub fn receive(msg: Message) -> Result<Self, String> {
+        if msg.version != PROTOCOL_VERSION {
+            return Err(format!(
+                "client uses protocol version {}, but swww-daemon uses version {PROTOCOL_VERSION}. \
+                 Make sure swww and swww-daemon are the same version",
+                msg.version
+            ));
+        }
+        let wire =
+            bitcode::decode(&msg.payload).map_err(|e| format!("failed to decode request: {e}"))?;
+        Self::from_wire(wire, msg.fds)
     }
 }
 
@@ -270,59 +793,300 @@ pub enum Answer {
     Err(String),
     Info(Box<[BgInfo]>),
     Ping(bool),
+    Event(Event),
 }
 
 impl Answer {
     pub fn send(&self, stream: &UnixStream) -> Result<(), String> {
         let bytes = bitcode::encode(self);
-        let mut writer = BufWriter::new(stream);
-        if let Err(e) = writer.write_all(&bytes.len().to_ne_bytes()) {
-            return Err(format!("failed to write serialized answer's length: {e}"));
-        }
-        if let Err(e) = writer.write_all(&bytes) {
-            Err(format!("Failed to write serialized answer: {e}"))
-        } else {
-            Ok(())
+        write_message(stream, &bytes, &[])
+            .map_err(|e| format!("Failed to write serialized answer: {e}"))
+    }
+
+    /// Decodes an answer, failing if it was sent with a different protocol version or if its
+    /// payload is malformed
+    pub fn receive(msg: &Message) -> Result<Self, String> {
+        if msg.version != PROTOCOL_VERSION {
+            return Err(format!(
+                "swww-daemon uses protocol version {}, but this client uses version \
+                 {PROTOCOL_VERSION}. Make sure swww and swww-daemon are the same version",
+                msg.version
+            ));
         }
+        bitcode::decode(&msg.payload).map_err(|e| format!("failed to decode answer: {e}"))
+    }
+}
+
+/// Every message sent through the socket starts with these bytes, so that we can reject
+/// anything that wasn't sent by `swww` or `swww-daemon` before trying to decode it
+pub const MAGIC: [u8; 4] = *b"swww";
+
+/// Version of the wire format of `Request` and `Answer`. It **must** be bumped every time their
+/// encoding changes, since bitcode makes no effort to be compatible across different layouts.
+/// `should_bump_the_protocol_version_when_the_encoding_changes` fails until it is
+pub const PROTOCOL_VERSION: u16 = 4;
+
+/// magic + version + capabilities + payload length
+const HEADER_LEN: usize = MAGIC.len() + 2 + 4 + 8;
+
+/// Optional features supported by whoever sent a message.
+///
+/// The protocol version tells whether we can decode a message at all. The capabilities tell
+/// what the other side is able to do once we know we can talk to it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// The daemon can push `Event`s after receiving a `Request::Subscribe`
+    pub const SUBSCRIBE: Self = Self(1 << 0);
+
+    /// The daemon can decode images by itself, through `Request::ImgByPath`
+    pub const DAEMON_DECODE: Self = Self(1 << 1);
+
+    /// The daemon can cycle through images by itself, through `Request::Playlist`
+    pub const PLAYLIST: Self = Self(1 << 2);
+
+    /// Everything this build of swww knows how to handle
+    pub const SUPPORTED: Self = Self(Self::SUBSCRIBE.0 | Self::DAEMON_DECODE.0 | Self::PLAYLIST.0);
+
+    #[inline]
+    #[must_use]
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
     }
 
+    #[inline]
     #[must_use]
+    pub const fn bits(&self) -> u32 {
+        self.0
+    }
+
     #[inline]
-    pub fn receive(bytes: &[u8]) -> Self {
-        bitcode::decode(bytes).expect("failed to decode answer")
+    #[must_use]
+    pub const fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+/// What the other side of the socket told us about itself
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Peer {
+    pub version: u16,
+    pub capabilities: Capabilities,
+}
+
+/// Sends a handshake: a message with an empty payload, which only carries our header. The header's
+/// layout never changes, so whoever reads it learns our version and capabilities, whatever
+/// version it is
+pub fn send_handshake(stream: &UnixStream) -> Result<(), String> {
+    write_message(stream, &[], &[]).map_err(|e| format!("failed to write handshake: {e}"))
+}
+
+/// Tells the daemon our version and capabilities, and returns its own. This works with any daemon
+/// that uses the magic header: older ones answer with an error we cannot decode, but its header
+/// still tells us who they are
+pub fn handshake(stream: &UnixStream) -> Result<Peer, String> {
+    send_handshake(stream)?;
+    let msg = read_socket(stream)?;
+    Ok(Peer {
+        version: msg.version,
+        capabilities: msg.capabilities,
+    })
+}
+
+/// A message read from the socket whose header has already been validated
+pub struct Message {
+    /// Protocol version of whoever sent the message
+    pub version: u16,
+    /// Capabilities of whoever sent the message
+    pub capabilities: Capabilities,
+    pub payload: Vec<u8>,
+    /// File descriptors sent alongside the message (for example, images in shared memory)
+    pub fds: Vec<OwnedFd>,
+}
+
+impl Message {
+    /// Whether this is a handshake rather than a `Request` or an `Answer`, which are never empty
+    #[must_use]
+    pub fn is_handshake(&self) -> bool {
+        self.payload.is_empty() && self.fds.is_empty()
+    }
+}
+
+/// Maximum number of file descriptors we send with a single message. Since we send one per
+/// unique output dimension, this should be plenty
+const MAX_FDS: usize = 64;
+
+/// Largest payload we accept. Images travel through memfds, so the only big payloads are the
+/// compressed frames of animations. This keeps a bogus header from making us allocate gigabytes
+const MAX_PAYLOAD_LEN: u64 = 1 << 30;
+
+fn write_message(stream: &UnixStream, payload: &[u8], fds: &[BorrowedFd]) -> std::io::Result<()> {
+    if fds.len() > MAX_FDS {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("cannot send more than {MAX_FDS} images at once"),
+        ));
+    }
+    if payload.len() as u64 > MAX_PAYLOAD_LEN {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("message is too large: {} bytes", payload.len()),
+        ));
+    }
+    let mut header = [0; HEADER_LEN];
+    header[0..4].copy_from_slice(&MAGIC);
+    header[4..6].copy_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+    header[6..10].copy_from_slice(&Capabilities::SUPPORTED.bits().to_le_bytes());
+    header[10..18].copy_from_slice(&(payload.len() as u64).to_le_bytes());
+
+    // the file descriptors go together with the header's first bytes
+    let mut space = [0; rustix::cmsg_space!(ScmRights(MAX_FDS))];
+    let mut control = SendAncillaryBuffer::new(&mut space);
+    if !fds.is_empty() {
+        control.push(SendAncillaryMessage::ScmRights(fds));
     }
+    let sent = loop {
+        match rustix::net::sendmsg(
+            stream,
+            &[IoSlice::new(&header)],
+            &mut control,
+            SendFlags::empty(),
+        ) {
+            Ok(sent) => break sent,
+            Err(rustix::io::Errno::INTR) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    };
+
+    let mut writer = stream;
+    writer.write_all(&header[sent..])?;
+    writer.write_all(payload)?;
+    writer.flush()
+}
+
+/// Reads from a socket, giving up once `deadline` passes. A read timeout only bounds each read, so
+/// it does nothing against someone sending us one byte at a time
+pub struct DeadlineReader<'a> {
+    stream: &'a UnixStream,
+    deadline: Instant,
 }
 
-pub fn read_socket(stream: &UnixStream) -> Result<Vec<u8>, String> {
-    let mut reader = BufReader::new(stream);
-    let mut buf = vec![0; 8];
+impl<'a> DeadlineReader<'a> {
+    #[must_use]
+    pub fn new(stream: &'a UnixStream, deadline: Instant) -> Self {
+        Self { stream, deadline }
+    }
+}
 
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        set_time_left(self.stream, self.deadline)?;
+        let mut stream = self.stream;
+        stream.read(buf)
+    }
+}
+
+/// Sets `stream`'s read timeout to whatever is left until `deadline`
+fn set_time_left(stream: &UnixStream, deadline: Instant) -> std::io::Result<()> {
+    let left = deadline.saturating_duration_since(Instant::now());
+    if left.is_zero() {
+        return Err(std::io::ErrorKind::TimedOut.into());
+    }
+    stream.set_read_timeout(Some(left))
+}
+
+pub fn read_socket(stream: &UnixStream) -> Result<Message, String> {
+    read_message(stream, None)
+}
+
+/// Like `read_socket`, but fails if the whole message has not arrived by `deadline`
+pub fn read_socket_before(stream: &UnixStream, deadline: Instant) -> Result<Message, String> {
+    read_message(stream, Some(deadline))
+}
+
+fn read_message(stream: &UnixStream, deadline: Option<Instant>) -> Result<Message, String> {
+    let mut header = [0; HEADER_LEN];
+    let mut fds = Vec::new();
+    let mut space = [0; rustix::cmsg_space!(ScmRights(MAX_FDS))];
+
+    let mut read = 0;
     let mut tries = 0;
-    loop {
-        match reader.read_exact(&mut buf[0..std::mem::size_of::<usize>()]) {
-            Ok(()) => break,
-            Err(e) => {
-                if e.kind() == std::io::ErrorKind::WouldBlock && tries < 5 {
-                    std::thread::sleep(Duration::from_millis(1));
-                } else {
-                    return Err(format!("failed to read serialized length: {e}"));
-                }
+    while read < HEADER_LEN {
+        if let Some(deadline) = deadline {
+            if let Err(e) = set_time_left(stream, deadline) {
+                return Err(format!("failed to read message header: {e}"));
+            }
+        }
+        let mut control = RecvAncillaryBuffer::new(&mut space);
+        match rustix::net::recvmsg(
+            stream,
+            &mut [IoSliceMut::new(&mut header[read..])],
+            &mut control,
+            RecvFlags::CMSG_CLOEXEC,
+        ) {
+            Ok(ret) if ret.bytes == 0 => {
+                return Err("failed to read message header: connection closed".to_string())
+            }
+            Ok(ret) => read += ret.bytes,
+            Err(rustix::io::Errno::INTR) => continue,
+            Err(rustix::io::Errno::AGAIN) if tries < 5 => {
+                std::thread::sleep(Duration::from_millis(1));
+                tries += 1;
             }
+            Err(e) => return Err(format!("failed to read message header: {e}")),
         }
-        tries += 1;
+        for msg in control.drain() {
+            if let RecvAncillaryMessage::ScmRights(received) = msg {
+                fds.extend(received);
+            }
+        }
+    }
+
+    if header[0..4] != MAGIC {
+        return Err("message does not start with the swww magic bytes. \
+             Was it sent by an incompatible version of swww?"
+            .to_string());
+    }
+    let version = u16::from_le_bytes(header[4..6].try_into().unwrap());
+    let capabilities =
+        Capabilities::from_bits(u32::from_le_bytes(header[6..10].try_into().unwrap()));
+    let len = u64::from_le_bytes(header[10..18].try_into().unwrap());
+    if len > MAX_PAYLOAD_LEN {
+        return Err(format!("message is too large: {len} bytes"));
     }
-    let len = usize::from_ne_bytes(buf[0..std::mem::size_of::<usize>()].try_into().unwrap());
-    buf.clear();
-    buf.resize(len, 0);
 
-    if let Err(e) = reader.read_exact(&mut buf) {
-        return Err(format!("Failed to read request: {e}"));
+    // grow the buffer as the bytes arrive, rather than trusting the header's length up front
+    let mut payload = Vec::new();
+    let read = match deadline {
+        Some(deadline) => DeadlineReader::new(stream, deadline)
+            .take(len)
+            .read_to_end(&mut payload),
+        None => stream.take(len).read_to_end(&mut payload),
+    };
+    if let Err(e) = read {
+        return Err(format!("Failed to read message payload: {e}"));
+    }
+    if payload.len() as u64 != len {
+        return Err("Failed to read message payload: connection closed".to_string());
     }
-    Ok(buf)
+    Ok(Message {
+        version,
+        capabilities,
+        payload,
+        fds,
+    })
 }
 
+/// The environment variable that overrides where the socket is
+pub const SOCKET_ENV: &str = "SWWW_SOCKET";
+
+/// `$SWWW_SOCKET` if it is set, `$XDG_RUNTIME_DIR/swww.socket` otherwise
 #[must_use]
 pub fn get_socket_path() -> PathBuf {
+    if let Some(path) = std::env::var_os(SOCKET_ENV).filter(|path| !path.is_empty()) {
+        return PathBuf::from(path);
+    }
     let runtime_dir = if let Ok(dir) = std::env::var("XDG_RUNTIME_DIR") {
         dir
     } else {
@@ -332,6 +1096,13 @@ pub fn get_socket_path() -> PathBuf {
     runtime_dir.join("swww.socket")
 }
 
+/// Whether a daemon is listening at `path`. A socket file nobody listens at was left behind by a
+/// daemon that did not exit cleanly
+#[must_use]
+pub fn is_daemon_listening(path: &Path) -> bool {
+    UnixStream::connect(path).is_ok()
+}
+
 pub fn get_cache_path() -> Result<PathBuf, String> {
     let cache_path = match std::env::var("XDG_CACHE_HOME") {
         Ok(dir) => {
@@ -360,3 +1131,339 @@ pub fn get_cache_path() -> Result<PathBuf, String> {
 
     Ok(cache_path)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answer_round_trip() {
+        let (a, b) = UnixStream::pair().unwrap();
+        Answer::Err("hello".to_string()).send(&a).unwrap();
+        let msg = read_socket(&b).unwrap();
+        assert_eq!(msg.version, PROTOCOL_VERSION);
+        assert_eq!(msg.capabilities, Capabilities::SUPPORTED);
+        match Answer::receive(&msg).unwrap() {
+            Answer::Err(e) => assert_eq!(e, "hello"),
+            _ => panic!("decoded the wrong answer"),
+        }
+    }
+
+    #[test]
+    fn event_round_trip() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let event = Event::ImageChanged {
+            output: "DP-1".to_string(),
+            img: BgImg::Color([0xff, 0, 0x10]),
+        };
+        Answer::Event(event).send(&a).unwrap();
+        let msg = read_socket(&b).unwrap();
+        assert!(msg.capabilities.contains(Capabilities::SUBSCRIBE));
+        match Answer::receive(&msg).unwrap() {
+            Answer::Event(event) => {
+                assert_eq!(event.to_string(), "image_changed: DP-1, color: FF0010");
+                assert_eq!(
+                    event.to_json().to_string(),
+                    r#"{"event":"image_changed","output":"DP-1","displaying":{"color":"ff0010"}}"#
+                );
+            }
+            _ => panic!("decoded the wrong answer"),
+        }
+    }
+
+    #[test]
+    fn should_round_fractional_scales_halfway_up() {
+        assert_eq!(Scale::Whole(2).mul_dim(1920, 1080), (3840, 2160));
+        assert_eq!(Scale::Fractional(150).mul_dim(1920, 1080), (2400, 1350));
+        assert_eq!(Scale::Fractional(180).mul_dim(1707, 960), (2561, 1440));
+        assert_eq!(Scale::Fractional(180).to_string(), "1.5");
+    }
+
+    #[test]
+    fn should_only_display_unusual_transforms() {
+        let mut info = BgInfo {
+            name: "DP-1".to_string(),
+            description: String::new(),
+            make: String::new(),
+            model: String::new(),
+            position: (0, 0),
+            dim: (1080, 1920),
+            scale_factor: Scale::Whole(1),
+            transform: Transform::Normal,
+            img: BgImg::Color([0, 0, 0]),
+            pixel_format: PixelFormat::Xrgb,
+            animating: false,
+            transitioning: false,
+        };
+        assert_eq!(
+            info.to_string(),
+            "DP-1: 1080x1920, scale: 1, currently displaying: color: 000000"
+        );
+        info.transform = Transform::_90;
+        assert_eq!(
+            info.to_string(),
+            "DP-1: 1080x1920, scale: 1, transform: 90, currently displaying: color: 000000"
+        );
+    }
+
+    /// Fails whenever the encoding of our messages changes, as a reminder to bump
+    /// `PROTOCOL_VERSION`. Once it is bumped, update the fingerprint below to the new one
+    #[test]
+    fn should_bump_the_protocol_version_when_the_encoding_changes() {
+        let info = BgInfo {
+            name: "DP-1".to_string(),
+            description: "a monitor".to_string(),
+            make: "make".to_string(),
+            model: "model".to_string(),
+            position: (1, 2),
+            dim: (3, 4),
+            scale_factor: Scale::Fractional(150),
+            transform: Transform::Flipped90,
+            img: BgImg::Img("img".to_string()),
+            pixel_format: PixelFormat::Xrgb,
+            animating: true,
+            transitioning: false,
+        };
+        let img_by_path = ImgByPath {
+            path: "img".to_string(),
+            outputs: vec!["DP-1".to_string()].into_boxed_slice(),
+            resize: ResizeStrategy::Fit,
+            filter: Filter::Nearest,
+            fill_color: [1, 2, 3],
+            transition: Transition::default(),
+        };
+        let wire_img = WireImg {
+            path: "img".to_string(),
+            resize: ResizeStrategy::Crop,
+            filter: Filter::Lanczos3,
+            fill_color: [4, 5, 6],
+            span: true,
+        };
+        let messages = [
+            bitcode::encode(&WireRequest::Img((
+                Transition::default(),
+                vec![(wire_img, Box::default())].into_boxed_slice(),
+            ))),
+            bitcode::encode(&WireRequest::ImgByPath(img_by_path.clone())),
+            bitcode::encode(&WireRequest::Playlist(PlaylistCommand::Start(Playlist {
+                outputs: Box::default(),
+                sources: vec!["dir".to_string()].into_boxed_slice(),
+                interval: Duration::from_secs(60),
+                order: PlaylistOrder::Shuffle,
+                resize: ResizeStrategy::No,
+                filter: Filter::Bilinear,
+                fill_color: [7, 8, 9],
+                transition: img_by_path.transition,
+            }))),
+            bitcode::encode(&WireRequest::Clear(Clear {
+                color: [1, 2, 3],
+                outputs: Box::default(),
+            })),
+            bitcode::encode(&Answer::Info(vec![info.clone()].into_boxed_slice())),
+            bitcode::encode(&Answer::Event(Event::OutputChanged(info))),
+        ];
+        let mut fingerprint: u64 = 0xcbf2_9ce4_8422_2325;
+        for byte in messages.concat() {
+            fingerprint ^= byte as u64;
+            fingerprint = fingerprint.wrapping_mul(0x0100_0000_01b3);
+        }
+        assert_eq!(
+            (PROTOCOL_VERSION, fingerprint),
+            (4, 0xb1df_ec5a_4505_5b8d),
+            "the encoding changed: bump PROTOCOL_VERSION"
+        );
+    }
+
+    #[test]
+    fn should_describe_outputs_in_json() {
+        let info = BgInfo {
+            name: "eDP-1".to_string(),
+            description: "BOE 0x095F (eDP-1)".to_string(),
+            make: "BOE".to_string(),
+            model: "0x095F".to_string(),
+            position: (1920, 0),
+            dim: (1536, 864),
+            scale_factor: Scale::Fractional(150),
+            transform: Transform::Normal,
+            img: BgImg::Img("/tmp/a.png".to_string()),
+            pixel_format: PixelFormat::Xrgb,
+            animating: true,
+            transitioning: false,
+        };
+        assert_eq!(
+            info.to_json().to_string(),
+            r#"{"name":"eDP-1","description":"BOE 0x095F (eDP-1)","make":"BOE","model":"0x095F","x":1920,"y":0,"width":1536,"height":864,"physical_width":1920,"physical_height":1080,"scale":1.25,"transform":"normal","displaying":{"image":"/tmp/a.png"},"pixel_format":"xrgb","animating":true,"transitioning":false}"#
+        );
+    }
+
+    #[test]
+    fn img_request_round_trip() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let imgs = [(1920 * 1080 * 3, "DP-1"), (2560 * 1440 * 3, "HDMI-A-1")];
+        let request = Request::Img((
+            Transition::default(),
+            imgs.iter()
+                .map(|(len, output)| {
+                    let mut img = Mmap::create(*len).unwrap();
+                    img.slice_mut().fill(*len as u8);
+                    let img = Img {
+                        path: "STDIN".to_string(),
+                        img,
+                        resize: ResizeStrategy::Fit,
+                        filter: Filter::Nearest,
+                        fill_color: [1, 2, 3],
+                        span: false,
+                    };
+                    (img, vec![output.to_string()].into_boxed_slice())
+                })
+                .collect(),
+        ));
+        request.send(&a).unwrap();
+
+        let msg = read_socket(&b).unwrap();
+        assert_eq!(msg.fds.len(), imgs.len());
+        match Request::receive(msg).unwrap() {
+            Request::Img((_, received)) => {
+                for ((img, outputs), (len, output)) in received.iter().zip(imgs) {
+                    assert_eq!(img.img.len(), len);
+                    assert_eq!(img.resize, ResizeStrategy::Fit);
+                    assert_eq!(img.filter, Filter::Nearest);
+                    assert_eq!(img.fill_color, [1, 2, 3]);
+                    assert!(img.img.slice().iter().all(|b| *b == len as u8));
+                    assert_eq!(outputs[0], output);
+                }
+            }
+            _ => panic!("decoded the wrong request"),
+        }
+    }
+
+    #[test]
+    fn should_reject_requests_missing_fds() {
+        let wire = WireRequest::Img((
+            Transition::default(),
+            vec![(
+                WireImg {
+                    path: "STDIN".to_string(),
+                    resize: ResizeStrategy::default(),
+                    filter: Filter::default(),
+                    fill_color: [0, 0, 0],
+                    span: false,
+                },
+                Box::default(),
+            )]
+            .into_boxed_slice(),
+        ));
+        let msg = Message {
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities::SUPPORTED,
+            payload: bitcode::encode(&wire),
+            fds: Vec::new(),
+        };
+        assert!(Request::receive(msg).is_err());
+    }
+
+    #[test]
+    fn should_reject_missing_magic() {
+        let (a, b) = UnixStream::pair().unwrap();
+        // this is what the older, length-prefixed protocol looked like
+        (&a).write_all(&8usize.to_ne_bytes()).unwrap();
+        (&a).write_all(&[0; 16]).unwrap();
+        assert!(read_socket(&b).is_err());
+    }
+
+    #[test]
+    fn should_reject_huge_payloads_without_allocating() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut header = [0; HEADER_LEN];
+        header[0..4].copy_from_slice(&MAGIC);
+        header[4..6].copy_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+        header[10..18].copy_from_slice(&u64::MAX.to_le_bytes());
+        (&a).write_all(&header).unwrap();
+        assert!(read_socket(&b).is_err());
+
+        // a length we accept, but the connection closes before the payload arrives
+        header[10..18].copy_from_slice(&MAX_PAYLOAD_LEN.to_le_bytes());
+        (&a).write_all(&header).unwrap();
+        (&a).write_all(&[0; 8]).unwrap();
+        drop(a);
+        assert!(read_socket(&b).is_err());
+    }
+
+    #[test]
+    fn should_handshake_across_versions() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let daemon = std::thread::spawn(move || {
+            let msg = read_socket(&b).unwrap();
+            assert!(msg.is_handshake());
+            // pretend to be a newer daemon, with capabilities we do not know about
+            let mut header = [0; HEADER_LEN];
+            header[0..4].copy_from_slice(&MAGIC);
+            header[4..6].copy_from_slice(&(PROTOCOL_VERSION + 1).to_le_bytes());
+            header[6..10]
+                .copy_from_slice(&(1u32 << 31 | Capabilities::PLAYLIST.bits()).to_le_bytes());
+            (&b).write_all(&header).unwrap();
+        });
+        let peer = handshake(&a).unwrap();
+        daemon.join().unwrap();
+        assert_eq!(peer.version, PROTOCOL_VERSION + 1);
+        assert!(peer.capabilities.contains(Capabilities::PLAYLIST));
+        assert!(!peer.capabilities.contains(Capabilities::SUBSCRIBE));
+    }
+
+    #[test]
+    fn should_never_mistake_messages_for_handshakes() {
+        let (a, b) = UnixStream::pair().unwrap();
+        Request::Ping.send(&a).unwrap();
+        assert!(!read_socket(&b).unwrap().is_handshake());
+        Answer::Ok.send(&a).unwrap();
+        assert!(!read_socket(&b).unwrap().is_handshake());
+    }
+
+    #[test]
+    fn should_give_up_on_slow_messages() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let writer = std::thread::spawn(move || {
+            let mut header = [0; HEADER_LEN];
+            header[0..4].copy_from_slice(&MAGIC);
+            header[4..6].copy_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+            header[10..18].copy_from_slice(&64u64.to_le_bytes());
+            (&a).write_all(&header).unwrap();
+            // every byte comes in well within a read timeout, but they never all come in time
+            for _ in 0..64 {
+                std::thread::sleep(Duration::from_millis(10));
+                if (&a).write_all(&[0]).is_err() {
+                    return;
+                }
+            }
+        });
+        assert!(read_socket_before(&b, deadline).is_err());
+        assert!(Instant::now() < deadline + Duration::from_millis(100));
+        drop(b);
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn should_reject_other_versions_without_decoding() {
+        let msg = Message {
+            version: PROTOCOL_VERSION + 1,
+            capabilities: Capabilities::SUPPORTED,
+            payload: bitcode::encode(&WireRequest::Ping),
+            fds: Vec::new(),
+        };
+        assert!(Answer::receive(&msg).is_err());
+        assert!(Request::receive(msg).is_err());
+    }
+
+    #[test]
+    fn should_not_panic_on_garbage() {
+        let msg = Message {
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities::SUPPORTED,
+            payload: vec![u8::MAX; 7],
+            fds: Vec::new(),
+        };
+        assert!(Answer::receive(&msg).is_err());
+        assert!(Request::receive(msg).is_err());
+    }
+}
@@ -1,3 +1,10 @@
 pub mod cache;
 pub mod compression;
+pub mod config;
+pub mod imgproc;
 pub mod ipc;
+pub mod mmap;
+pub mod schedule;
+pub mod selector;
+pub mod toml;
+pub mod transitions;
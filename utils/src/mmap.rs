@@ -0,0 +1,222 @@
+//! Images backed by sealed memfds.
+//!
+//! Sending full resolution images through the socket means copying them several times (into the
+//! serialized buffer, through the kernel, and out of it again). Instead, we write them into a
+//! memfd, seal it, and send only the file descriptor. The receiving side maps it straight into
+//! its own address space.
+
+use std::{
+    ffi::c_void,
+    os::fd::{AsFd, BorrowedFd, OwnedFd},
+    ptr::NonNull,
+};
+
+use rustix::{
+    fs::{MemfdFlags, SealFlags},
+    mm::{MapFlags, ProtFlags},
+};
+
+/// Once these are set, nobody can change the memfd's contents or size anymore, so we can safely
+/// map it without worrying about `SIGBUS`
+const SEALS: SealFlags = SealFlags::SHRINK
+    .union(SealFlags::GROW)
+    .union(SealFlags::WRITE)
+    .union(SealFlags::SEAL);
+
+pub struct Mmap {
+    fd: OwnedFd,
+    ptr: NonNull<c_void>,
+    len: usize,
+    sealed: bool,
+    /// Whether `ptr` is a writable mapping. Only true between `create` and `seal`
+    writable: bool,
+}
+
+// SAFETY: we own the mapping, and only hand out references to it tied to our own lifetime, so
+// this has the same semantics as a `Box<[u8]>`
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    /// Creates a new, writable, zero-filled memfd mapping of `len` bytes
+    pub fn create(len: usize) -> Result<Self, String> {
+        if len == 0 {
+            return Err("cannot create an empty memory map".to_string());
+        }
+        let fd =
+            rustix::fs::memfd_create("swww-img", MemfdFlags::CLOEXEC | MemfdFlags::ALLOW_SEALING)
+                .map_err(|e| format!("failed to create memfd: {e}"))?;
+        rustix::fs::ftruncate(&fd, len as u64)
+            .map_err(|e| format!("failed to set memfd size: {e}"))?;
+        let ptr = map(
+            &fd,
+            len,
+            ProtFlags::READ | ProtFlags::WRITE,
+            MapFlags::SHARED,
+        )?;
+        Ok(Self {
+            fd,
+            ptr,
+            len,
+            sealed: false,
+            writable: true,
+        })
+    }
+
+    /// Copies `bytes` into a new memfd, and seals it
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let mut mmap = Self::create(bytes.len())?;
+        mmap.slice_mut().copy_from_slice(bytes);
+        mmap.seal()?;
+        Ok(mmap)
+    }
+
+    /// Maps a memfd we received from someone else. It must have been sealed with `Mmap::seal`
+    pub fn from_fd(fd: OwnedFd) -> Result<Self, String> {
+        let seals = rustix::fs::fcntl_get_seals(&fd)
+            .map_err(|e| format!("failed to get memfd seals: {e}"))?;
+        if !seals.contains(SEALS) {
+            return Err("received memfd is not properly sealed".to_string());
+        }
+        let stat = rustix::fs::fstat(&fd).map_err(|e| format!("failed to stat memfd: {e}"))?;
+        let len = usize::try_from(stat.st_size)
+            .map_err(|_| format!("invalid memfd size: {}", stat.st_size))?;
+        if len == 0 {
+            return Err("received an empty memfd".to_string());
+        }
+        let ptr = map(&fd, len, ProtFlags::READ, MapFlags::SHARED)?;
+        Ok(Self {
+            fd,
+            ptr,
+            len,
+            sealed: true,
+            writable: false,
+        })
+    }
+
+    /// Forbids any further changes to the memfd, remapping it as read only.
+    ///
+    /// This must be called before sending it through the socket.
+    pub fn seal(&mut self) -> Result<(), String> {
+        if self.sealed {
+            return Ok(());
+        }
+        if self.writable {
+            // the kernel refuses to seal writes while there are writable shared mappings, so we
+            // swap ours for a read only one. We map the new one first, so that `self.ptr` always
+            // points to a valid mapping, whatever fails. It must be private: the kernel counts
+            // every shared mapping of a writable fd as writable. Since nobody writes to it, it
+            // still shares its pages with the memfd.
+            let read_only = map(&self.fd, self.len, ProtFlags::READ, MapFlags::PRIVATE)?;
+            // SAFETY: we created this mapping in `Mmap::create`
+            if let Err(e) = unsafe { rustix::mm::munmap(self.ptr.as_ptr(), self.len) } {
+                // SAFETY: we just created this mapping, and nobody has seen it yet
+                let _ = unsafe { rustix::mm::munmap(read_only.as_ptr(), self.len) };
+                return Err(format!("failed to unmap memfd: {e}"));
+            }
+            self.ptr = read_only;
+            self.writable = false;
+        }
+        rustix::fs::fcntl_add_seals(&self.fd, SEALS)
+            .map_err(|e| format!("failed to seal memfd: {e}"))?;
+        self.sealed = true;
+        Ok(())
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn slice(&self) -> &[u8] {
+        // SAFETY: the mapping is valid for `self.len` bytes for as long as we live, and nobody
+        // else can write to it (it is either sealed, or it is private to us)
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr().cast(), self.len) }
+    }
+
+    /// # Panics
+    ///
+    /// If the memfd has already been sealed (or we tried to)
+    #[inline]
+    #[must_use]
+    pub fn slice_mut(&mut self) -> &mut [u8] {
+        assert!(self.writable, "tried writing into a sealed memfd");
+        // SAFETY: same as above, and `self.writable` means we are mapped as writable
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr().cast(), self.len) }
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn is_sealed(&self) -> bool {
+        self.sealed
+    }
+}
+
+impl AsFd for Mmap {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        // SAFETY: the mapping was created by us with this exact length
+        if let Err(e) = unsafe { rustix::mm::munmap(self.ptr.as_ptr(), self.len) } {
+            eprintln!("failed to unmap memfd: {e}");
+        }
+    }
+}
+
+fn map(
+    fd: &OwnedFd,
+    len: usize,
+    prot: ProtFlags,
+    flags: MapFlags,
+) -> Result<NonNull<c_void>, String> {
+    // SAFETY: we map a new region (the kernel chooses the address), so we cannot be invalidating
+    // any existing references
+    let ptr = unsafe { rustix::mm::mmap(std::ptr::null_mut(), len, prot, flags, fd, 0) }
+        .map_err(|e| format!("failed to mmap memfd: {e}"))?;
+    NonNull::new(ptr).ok_or_else(|| "mmap returned a null pointer".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_share_contents_through_fd() {
+        let bytes: Vec<u8> = (0..4096u32).map(|i| i as u8).collect();
+        let mmap = Mmap::from_bytes(&bytes).unwrap();
+        let fd = mmap.as_fd().try_clone_to_owned().unwrap();
+        let received = Mmap::from_fd(fd).unwrap();
+        assert_eq!(received.slice(), &bytes[..]);
+        assert!(received.is_sealed());
+    }
+
+    #[test]
+    fn should_reject_unsealed_fds() {
+        let mmap = Mmap::create(16).unwrap();
+        let fd = mmap.as_fd().try_clone_to_owned().unwrap();
+        assert!(Mmap::from_fd(fd).is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "sealed")]
+    fn should_not_write_after_sealing() {
+        let mut mmap = Mmap::create(16).unwrap();
+        mmap.slice_mut()[0] = 1;
+        mmap.seal().unwrap();
+        assert_eq!(mmap.slice()[0], 1);
+        let _ = mmap.slice_mut();
+    }
+}
@@ -0,0 +1,258 @@
+//! Times of the day at which the daemon changes wallpapers by itself.
+//!
+//! Besides fixed clock times, we can follow the sun. Sunrise and sunset are computed offline from
+//! a latitude and a longitude, using the sunrise equation. It is accurate to within a couple of
+//! minutes, which is more than enough to change wallpapers.
+
+use std::{fmt, str::FromStr};
+
+const SECS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// A moment that happens once every day
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimeOfDay {
+    /// Minutes since midnight, in local time
+    Clock(u32),
+    /// Minutes relative to sunrise
+    Sunrise(i32),
+    /// Minutes relative to sunset
+    Sunset(i32),
+}
+
+impl TimeOfDay {
+    #[must_use]
+    pub fn needs_location(&self) -> bool {
+        !matches!(self, Self::Clock(_))
+    }
+
+    /// When this happens on the given `day` (counted in days since the unix epoch, in local time),
+    /// as seconds since the unix epoch.
+    ///
+    /// `utc_offset` is the local time zone's offset in seconds. Returns `None` for sunrises and
+    /// sunsets that do not happen that day, or when we do not know where we are.
+    #[must_use]
+    pub fn on_day(&self, day: i64, utc_offset: i64, location: Option<&Location>) -> Option<i64> {
+        match self {
+            Self::Clock(minutes) => Some(day * SECS_PER_DAY + *minutes as i64 * 60 - utc_offset),
+            Self::Sunrise(offset) => {
+                let (sunrise, _) = location?.sun_times(day)?;
+                Some(sunrise + *offset as i64 * 60)
+            }
+            Self::Sunset(offset) => {
+                let (_, sunset) = location?.sun_times(day)?;
+                Some(sunset + *offset as i64 * 60)
+            }
+        }
+    }
+}
+
+impl FromStr for TimeOfDay {
+    type Err = String;
+
+    /// Parses "HH:MM", "sunrise" or "sunset", optionally followed by an offset such as
+    /// "sunset-00:30"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || {
+            format!("invalid time of day '{s}'. Expected 'HH:MM', 'sunrise[(+|-)HH:MM]' or 'sunset[(+|-)HH:MM]'")
+        };
+        for (name, make) in [
+            ("sunrise", Self::Sunrise as fn(i32) -> Self),
+            ("sunset", Self::Sunset as fn(i32) -> Self),
+        ] {
+            if let Some(offset) = s.strip_prefix(name) {
+                let offset = if offset.is_empty() {
+                    0
+                } else if let Some(offset) = offset.strip_prefix('+') {
+                    hours_and_minutes(offset).ok_or_else(err)? as i32
+                } else if let Some(offset) = offset.strip_prefix('-') {
+                    -(hours_and_minutes(offset).ok_or_else(err)? as i32)
+                } else {
+                    return Err(err());
+                };
+                return Ok(make(offset));
+            }
+        }
+        match hours_and_minutes(s) {
+            Some(minutes) if minutes < 24 * 60 => Ok(Self::Clock(minutes)),
+            _ => Err(err()),
+        }
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (name, offset) = match self {
+            Self::Clock(minutes) => return write!(f, "{:02}:{:02}", minutes / 60, minutes % 60),
+            Self::Sunrise(offset) => ("sunrise", *offset),
+            Self::Sunset(offset) => ("sunset", *offset),
+        };
+        write!(f, "{name}")?;
+        if offset != 0 {
+            let sign = if offset < 0 { '-' } else { '+' };
+            let offset = offset.unsigned_abs();
+            write!(f, "{sign}{:02}:{:02}", offset / 60, offset % 60)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses "HH:MM" into minutes
+fn hours_and_minutes(s: &str) -> Option<u32> {
+    let (hours, minutes) = s.split_once(':')?;
+    if hours.is_empty() || hours.len() > 2 || minutes.len() != 2 {
+        return None;
+    }
+    // `parse` would also take a leading '+'
+    if !hours
+        .bytes()
+        .chain(minutes.bytes())
+        .all(|b| b.is_ascii_digit())
+    {
+        return None;
+    }
+    let hours: u32 = hours.parse().ok()?;
+    let minutes: u32 = minutes.parse().ok()?;
+    if minutes >= 60 {
+        return None;
+    }
+    Some(hours * 60 + minutes)
+}
+
+/// Where we are on Earth, in degrees. Positive latitudes are north, positive longitudes are east
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl Location {
+    pub fn new(latitude: f64, longitude: f64) -> Result<Self, String> {
+        if !(-90.0..=90.0).contains(&latitude) {
+            return Err(format!(
+                "latitude must be between -90 and 90, got {latitude}"
+            ));
+        }
+        if !(-180.0..=180.0).contains(&longitude) {
+            return Err(format!(
+                "longitude must be between -180 and 180, got {longitude}"
+            ));
+        }
+        Ok(Self {
+            latitude,
+            longitude,
+        })
+    }
+
+    /// Sunrise and sunset on the given `day` (counted in days since the unix epoch), as seconds
+    /// since the unix epoch. Returns `None` during polar days and nights.
+    #[must_use]
+    pub fn sun_times(&self, day: i64) -> Option<(i64, i64)> {
+        // days since the J2000 epoch, at noon
+        let n = (day - 10957) as f64 + 0.0008;
+        let mean_noon = n - self.longitude / 360.0;
+        let anomaly = (357.5291 + 0.985_600_28 * mean_noon).rem_euclid(360.0);
+        let m = anomaly.to_radians();
+        let center = 1.9148 * m.sin() + 0.02 * (2.0 * m).sin() + 0.0003 * (3.0 * m).sin();
+        let ecliptic_longitude = (anomaly + center + 180.0 + 102.9372)
+            .rem_euclid(360.0)
+            .to_radians();
+        let transit =
+            mean_noon + 0.0053 * m.sin() - 0.0069 * (2.0 * ecliptic_longitude).sin() + 0.5;
+        let declination = (ecliptic_longitude.sin() * 23.4397f64.to_radians().sin()).asin();
+
+        let latitude = self.latitude.to_radians();
+        // -0.833 degrees accounts for atmospheric refraction and the sun's radius
+        let cos_hour_angle = ((-0.833f64).to_radians().sin() - latitude.sin() * declination.sin())
+            / (latitude.cos() * declination.cos());
+        if !(-1.0..=1.0).contains(&cos_hour_angle) {
+            return None;
+        }
+        let hour_angle = cos_hour_angle.acos().to_degrees() / 360.0;
+
+        let to_unix = |j2000: f64| ((j2000 + 10957.0) * SECS_PER_DAY as f64).round() as i64;
+        Some((to_unix(transit - hour_angle), to_unix(transit + hour_angle)))
+    }
+}
+
+/// The day (counted since the unix epoch) `time` falls in, in local time
+#[must_use]
+pub fn local_day(time: i64, utc_offset: i64) -> i64 {
+    (time + utc_offset).div_euclid(SECS_PER_DAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_times_of_day() {
+        assert_eq!("07:30".parse(), Ok(TimeOfDay::Clock(7 * 60 + 30)));
+        assert_eq!("0:05".parse(), Ok(TimeOfDay::Clock(5)));
+        assert_eq!("sunrise".parse(), Ok(TimeOfDay::Sunrise(0)));
+        assert_eq!("sunset-01:15".parse(), Ok(TimeOfDay::Sunset(-75)));
+        assert_eq!("sunrise+00:30".parse(), Ok(TimeOfDay::Sunrise(30)));
+        for s in [
+            "24:00",
+            "7:60",
+            "7",
+            "7:5",
+            "noon",
+            "sunset01:00",
+            "sunrise+1",
+            "+7:30",
+            "sunset++7:30",
+            "sunrise--0:30",
+        ] {
+            assert!(s.parse::<TimeOfDay>().is_err(), "accepted {s:?}");
+        }
+        for s in ["07:30", "sunrise", "sunset-01:15"] {
+            assert_eq!(s.parse::<TimeOfDay>().unwrap().to_string(), s);
+        }
+    }
+
+    #[test]
+    fn should_place_clock_times_in_local_time() {
+        // 2024-06-21, in UTC+02:00
+        let day = 19895;
+        let time = TimeOfDay::Clock(7 * 60).on_day(day, 7200, None).unwrap();
+        assert_eq!(time, day * SECS_PER_DAY + 5 * 3600);
+        assert_eq!(local_day(time, 7200), day);
+        // just before midnight in UTC-05:00 is already the next day in UTC
+        let time = TimeOfDay::Clock(23 * 60 + 59)
+            .on_day(day, -18000, None)
+            .unwrap();
+        assert_eq!(local_day(time, -18000), day);
+        assert_eq!(local_day(time, 0), day + 1);
+        assert_eq!(TimeOfDay::Sunset(0).on_day(day, 0, None), None);
+    }
+
+    #[test]
+    fn should_compute_sunrise_and_sunset() {
+        // London, 2024-06-21: sunrise at 03:43 UTC, sunset at 20:21 UTC
+        let london = Location::new(51.5074, -0.1278).unwrap();
+        let day = 19895;
+        let (sunrise, sunset) = london.sun_times(day).unwrap();
+        let minutes = |day: i64, t: i64| (t - day * SECS_PER_DAY) / 60;
+        assert!((minutes(day, sunrise) - (3 * 60 + 43)).abs() <= 3);
+        assert!((minutes(day, sunset) - (20 * 60 + 21)).abs() <= 3);
+
+        // Sydney, 2024-12-21: sunrise at 18:41 UTC on the previous day, sunset at 09:05 UTC
+        let sydney = Location::new(-33.8688, 151.2093).unwrap();
+        let day = 20078;
+        let (sunrise, sunset) = sydney.sun_times(day).unwrap();
+        assert!((minutes(day, sunrise) - (-5 * 60 - 19)).abs() <= 3);
+        assert!((minutes(day, sunset) - (9 * 60 + 5)).abs() <= 3);
+        assert_eq!(
+            TimeOfDay::Sunset(-30).on_day(day, 39600, Some(&sydney)),
+            Some(sunset - 30 * 60)
+        );
+    }
+
+    #[test]
+    fn should_have_no_sunset_during_polar_days() {
+        let svalbard = Location::new(78.22, 15.65).unwrap();
+        assert_eq!(svalbard.sun_times(19895), None);
+        assert!(Location::new(91.0, 0.0).is_err());
+        assert!(Location::new(0.0, -181.0).is_err());
+    }
+}
@@ -0,0 +1,251 @@
+//! Choosing outputs by more than their names.
+//!
+//! Names such as `DP-1` depend on the port (and the GPU) a monitor is plugged into, so wherever
+//! we take a list of outputs, each entry may also be a selector:
+//!
+//! * `DP-*`: a glob over the output's name. `*` matches any run of characters, and `?` any single
+//!   character. A name without those is just matched exactly;
+//! * `desc:<glob>`, `make:<glob>` and `model:<glob>`: a glob over the description, make or model
+//!   the compositor advertises for the output. Descriptions usually include the serial number;
+//! * `index:<n>`: the n-th output, counting from 0, in the order `swww query` lists them;
+//! * `!<selector>`: excludes the outputs the selector matches. A list with only exclusions selects
+//!   every other output.
+//!
+//! Selectors that match nothing are fine (the monitor may simply be unplugged), as long as the
+//! list as a whole selects something.
+
+use std::fmt;
+
+/// Everything a selector can look at
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutputId<'a> {
+    pub name: &'a str,
+    pub description: &'a str,
+    pub make: &'a str,
+    pub model: &'a str,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Selector {
+    Name(String),
+    Description(String),
+    Make(String),
+    Model(String),
+    Index(usize),
+}
+
+impl Selector {
+    fn parse(s: &str) -> Result<Self, String> {
+        let Some((kind, value)) = s.split_once(':') else {
+            return Ok(Self::Name(s.to_string()));
+        };
+        match kind {
+            "desc" => Ok(Self::Description(value.to_string())),
+            "make" => Ok(Self::Make(value.to_string())),
+            "model" => Ok(Self::Model(value.to_string())),
+            "index" => match value.parse() {
+                Ok(index) => Ok(Self::Index(index)),
+                Err(_) => Err(format!(
+                    "invalid output selector '{s}': the index must be a non-negative integer"
+                )),
+            },
+            // output names may contain colons, too
+            _ => Ok(Self::Name(s.to_string())),
+        }
+    }
+
+    fn matches(&self, index: usize, output: &OutputId) -> bool {
+        match self {
+            Self::Name(pattern) => glob(pattern, output.name),
+            Self::Description(pattern) => glob(pattern, output.description),
+            Self::Make(pattern) => glob(pattern, output.make),
+            Self::Model(pattern) => glob(pattern, output.model),
+            Self::Index(i) => *i == index,
+        }
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Name(pattern) => write!(f, "{pattern}"),
+            Self::Description(pattern) => write!(f, "desc:{pattern}"),
+            Self::Make(pattern) => write!(f, "make:{pattern}"),
+            Self::Model(pattern) => write!(f, "model:{pattern}"),
+            Self::Index(i) => write!(f, "index:{i}"),
+        }
+    }
+}
+
+/// A list of selectors, as given to `--outputs`. An empty list selects every output
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Selection {
+    include: Vec<Selector>,
+    exclude: Vec<Selector>,
+}
+
+impl Selection {
+    pub fn parse<S: AsRef<str>>(selectors: &[S]) -> Result<Self, String> {
+        let mut selection = Self::default();
+        for selector in selectors {
+            let selector = selector.as_ref();
+            match selector.strip_prefix('!') {
+                Some(selector) => selection.exclude.push(Selector::parse(selector)?),
+                None => selection.include.push(Selector::parse(selector)?),
+            }
+        }
+        Ok(selection)
+    }
+
+    /// Whether this selects every output
+    #[must_use]
+    pub fn is_all(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Whether the output at `index` (in the order `swww query` lists them) is selected
+    #[must_use]
+    pub fn matches(&self, index: usize, output: &OutputId) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|s| s.matches(index, output)))
+            && !self.exclude.iter().any(|s| s.matches(index, output))
+    }
+
+    /// The indices of the selected `outputs`. Fails, listing the available outputs, if we select
+    /// none of them
+    pub fn select(&self, outputs: &[OutputId]) -> Result<Vec<usize>, String> {
+        let selected: Vec<usize> = outputs
+            .iter()
+            .enumerate()
+            .filter(|(i, output)| self.matches(*i, output))
+            .map(|(i, _)| i)
+            .collect();
+        if selected.is_empty() && !self.is_all() {
+            return Err(format!(
+                "'{self}' does not select any output. {}",
+                describe(outputs)
+            ));
+        }
+        Ok(selected)
+    }
+}
+
+impl fmt::Display for Selection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let include = self.include.iter().map(|s| s.to_string());
+        let exclude = self.exclude.iter().map(|s| format!("!{s}"));
+        let selectors: Vec<String> = include.chain(exclude).collect();
+        write!(f, "{}", selectors.join(","))
+    }
+}
+
+/// Lists the outputs we have, for error messages
+fn describe(outputs: &[OutputId]) -> String {
+    if outputs.is_empty() {
+        return "There are no outputs".to_string();
+    }
+    let outputs: Vec<String> = outputs
+        .iter()
+        .enumerate()
+        .map(|(i, output)| format!("{i}: {} ({})", output.name, output.description))
+        .collect();
+    format!("Available outputs are: {}", outputs.join(", "))
+}
+
+/// Matches `s` against `pattern`, where `*` matches any run of characters and `?` any single one
+fn glob(pattern: &str, s: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+    let (mut p, mut i) = (0, 0);
+    // where we go back to when a mismatch happens after a `*`
+    let mut star = None;
+    while i < s.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, i));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == s[i] => {
+                p += 1;
+                i += 1;
+            }
+            _ => match star {
+                Some((star_p, star_i)) => {
+                    p = star_p + 1;
+                    i = star_i + 1;
+                    star = Some((star_p, star_i + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OUTPUTS: [OutputId; 3] = [
+        OutputId {
+            name: "eDP-1",
+            description: "BOE 0x095F (eDP-1)",
+            make: "BOE",
+            model: "0x095F",
+        },
+        OutputId {
+            name: "DP-3",
+            description: "Dell Inc. DELL U2720Q 7X5D9F3 (DP-3)",
+            make: "Dell Inc.",
+            model: "DELL U2720Q",
+        },
+        OutputId {
+            name: "HDMI-A-1",
+            description: "LG Electronics LG HDR 4K 0x0000C5A3 (HDMI-A-1)",
+            make: "LG Electronics",
+            model: "LG HDR 4K",
+        },
+    ];
+
+    fn select(selectors: &[&str]) -> Result<Vec<usize>, String> {
+        Selection::parse(selectors)?.select(&OUTPUTS)
+    }
+
+    #[test]
+    fn should_match_globs() {
+        assert!(glob("DP-1", "DP-1"));
+        assert!(!glob("DP-1", "DP-10"));
+        assert!(glob("DP-*", "DP-10"));
+        assert!(glob("*", ""));
+        assert!(glob("D?-*", "DP-1"));
+        assert!(glob("*U2720Q*", "Dell Inc. DELL U2720Q 7X5D9F3"));
+        assert!(glob("a*b*c", "abbbc"));
+        assert!(!glob("a*b*c", "abbb"));
+        assert!(!glob("?", ""));
+    }
+
+    #[test]
+    fn should_select_outputs() {
+        assert_eq!(select(&[]), Ok(vec![0, 1, 2]));
+        assert_eq!(select(&["DP-3"]), Ok(vec![1]));
+        assert_eq!(select(&["*DP-*"]), Ok(vec![0, 1]));
+        assert_eq!(select(&["desc:*7X5D9F3*"]), Ok(vec![1]));
+        assert_eq!(select(&["make:LG*", "model:0x095F"]), Ok(vec![0, 2]));
+        assert_eq!(select(&["index:2"]), Ok(vec![2]));
+        assert_eq!(select(&["!eDP-1"]), Ok(vec![1, 2]));
+        assert_eq!(select(&["*", "!index:1"]), Ok(vec![0, 2]));
+        // unplugged monitors are fine
+        assert_eq!(select(&["DP-3", "DP-4"]), Ok(vec![1]));
+    }
+
+    #[test]
+    fn should_list_the_outputs_when_nothing_is_selected() {
+        let e = select(&["DP-4"]).unwrap_err();
+        assert!(e.starts_with("'DP-4' does not select any output."), "{e}");
+        assert!(
+            e.contains("1: DP-3 (Dell Inc. DELL U2720Q 7X5D9F3 (DP-3))"),
+            "{e}"
+        );
+        assert!(select(&["!*"]).is_err());
+        assert!(select(&["index:-1"]).is_err());
+    }
+}
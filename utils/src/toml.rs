@@ -0,0 +1,126 @@
+//! Reads the toml files in the user's configuration directory.
+//!
+//! Documents are converted into a `serde_json::Value`, so that everything that reads json can read
+//! toml as well. Since json has no dates, infinities or NaN, toml's are rejected.
+
+use serde_json::{Number, Value};
+
+/// Parses a whole toml document into a `Value::Object`
+pub fn parse(s: &str) -> Result<Value, String> {
+    let table: ::toml::Table = ::toml::from_str(s).map_err(|e| e.to_string())?;
+    convert(::toml::Value::Table(table))
+}
+
+fn convert(value: ::toml::Value) -> Result<Value, String> {
+    Ok(match value {
+        ::toml::Value::String(s) => Value::String(s),
+        ::toml::Value::Integer(i) => Value::Number(i.into()),
+        ::toml::Value::Float(f) => match Number::from_f64(f) {
+            Some(f) => Value::Number(f),
+            None => return Err(format!("json cannot represent '{f}'")),
+        },
+        ::toml::Value::Boolean(b) => Value::Bool(b),
+        ::toml::Value::Datetime(d) => return Err(format!("dates are not supported, got '{d}'")),
+        ::toml::Value::Array(array) => {
+            Value::Array(array.into_iter().map(convert).collect::<Result<_, _>>()?)
+        }
+        ::toml::Value::Table(table) => Value::Object(
+            table
+                .into_iter()
+                .map(|(k, v)| Ok((k, convert(v)?)))
+                .collect::<Result<_, String>>()?,
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parses_tables_and_arrays_of_tables() {
+        let v = parse(
+            r#"
+            # where we are
+            latitude = 48.85
+            longitude = -2_000
+
+            [defaults]
+            resize = "crop" # trailing comment
+            transition.type = 'fade'
+
+            [[entry]]
+            at = "07:00"
+            outputs = [
+                "DP-1",
+                "HDMI-A-1", # trailing comma
+            ]
+
+            [[entry]]
+            at = "sunset"
+            enabled = false
+            "#,
+        )
+        .unwrap();
+        assert_eq!(v.get("latitude"), Some(&json!(48.85)));
+        assert_eq!(v.get("longitude"), Some(&json!(-2000)));
+        let defaults = v.get("defaults").unwrap();
+        assert_eq!(defaults.get("resize").and_then(Value::as_str), Some("crop"));
+        let transition = defaults.get("transition").unwrap();
+        assert_eq!(transition.get("type").and_then(Value::as_str), Some("fade"));
+
+        let entries = v.get("entry").and_then(Value::as_array).unwrap();
+        assert_eq!(entries.len(), 2);
+        let outputs = entries[0].get("outputs").and_then(Value::as_array).unwrap();
+        assert_eq!(outputs[1].as_str(), Some("HDMI-A-1"));
+        assert_eq!(entries[1].get("enabled"), Some(&Value::Bool(false)));
+    }
+
+    #[test]
+    fn parses_scalars() {
+        let v = parse(
+            r#"
+            a = "tab\tquote\"\u00e9"
+            b = 'C:\no\escapes'
+            c = { x = 0x10, y = 1e3 }
+            "quoted key" = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(v.get("a").and_then(Value::as_str), Some("tab\tquote\"é"));
+        assert_eq!(v.get("b").and_then(Value::as_str), Some("C:\\no\\escapes"));
+        let c = v.get("c").unwrap();
+        assert_eq!(c.get("x"), Some(&json!(16)));
+        assert_eq!(c.get("y"), Some(&json!(1000.0)));
+        assert_eq!(v.get("quoted key"), Some(&Value::Bool(true)));
+    }
+
+    #[test]
+    fn should_reject_malformed_input() {
+        for s in [
+            "a",
+            "a = ",
+            "a = 1 b = 2",
+            "a = 1\na = 2",
+            "a = 1\n[a]",
+            "a = [1 2]",
+            "a = \"unterminated",
+            "a = 1979-05-27",
+            "a = 07:32:00",
+            "a = +inf",
+            "a = nan",
+            "[a",
+            "a = 1\n[[a]]",
+            &format!("a = {}", "[".repeat(1000)),
+        ] {
+            assert!(parse(s).is_err(), "accepted {s:?}");
+        }
+    }
+
+    #[test]
+    fn should_report_the_line_of_errors() {
+        let err = parse("a = 1\n\nb = nope").unwrap_err();
+        assert!(err.contains("line 3"), "{err}");
+    }
+}
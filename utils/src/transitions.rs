@@ -0,0 +1,116 @@
+// The transition effects swww-daemon implements.
+//
+// `swww img`, the json protocol and the daemon all take their effects from this list: the client
+// builds `--transition-type`'s possible values (and shell completions) out of it, and the daemon
+// looks up each name in its own registry of implementations (`daemon/src/animations/effects.rs`).
+// So adding an effect only takes an entry here and its implementation there.
+//
+// Note `build.rs` includes this file to generate the completions, so it must not depend on
+// anything else in this crate, nor have inner attributes.
+
+/// An effect `swww img --transition-type` can select
+pub struct TransitionInfo {
+    pub name: &'static str,
+    /// A one line description, for the shell completions
+    pub help: &'static str,
+}
+
+pub const TRANSITIONS: &[TransitionInfo] = &[
+    TransitionInfo {
+        name: "simple",
+        help: "fade every pixel into the new image by --transition-step each frame",
+    },
+    TransitionInfo {
+        name: "fade",
+        help: "like simple, but timed by --transition-duration and --transition-bezier",
+    },
+    TransitionInfo {
+        name: "wipe",
+        help: "a line sweeping the screen along --transition-angle",
+    },
+    TransitionInfo {
+        name: "wave",
+        help: "like wipe, but the line is wavy (see --transition-wave)",
+    },
+    TransitionInfo {
+        name: "grow",
+        help: "a circle growing from --transition-pos",
+    },
+    TransitionInfo {
+        name: "outer",
+        help: "a circle shrinking into --transition-pos",
+    },
+    TransitionInfo {
+        name: "horizontal-blinds",
+        help: "horizontal slats opening from top to bottom (see --transition-slats)",
+    },
+    TransitionInfo {
+        name: "vertical-blinds",
+        help: "vertical slats opening from left to right (see --transition-slats)",
+    },
+    TransitionInfo {
+        name: "dissolve",
+        help: "every pixel switches to the new image at its own time, random on every run",
+    },
+    TransitionInfo {
+        name: "pixelate-out",
+        help: "the old image breaks into ever bigger blocks, which shrink back into the new one",
+    },
+    TransitionInfo {
+        name: "pixelate-in",
+        help: "the new image comes in as big blocks, which get smaller until it is sharp",
+    },
+    TransitionInfo {
+        name: "iris-rectangle",
+        help: "a rectangle growing from --transition-pos",
+    },
+    TransitionInfo {
+        name: "iris-diamond",
+        help: "a diamond growing from --transition-pos",
+    },
+    TransitionInfo {
+        name: "iris-star",
+        help: "a five pointed star growing from --transition-pos",
+    },
+    TransitionInfo {
+        name: "slide",
+        help: "the new image slides in over the old one, along --transition-angle",
+    },
+    TransitionInfo {
+        name: "push",
+        help: "the new image pushes the old one out, along --transition-angle",
+    },
+];
+
+/// Finds the effect called `name`
+#[must_use]
+pub fn find(name: &str) -> Option<&'static TransitionInfo> {
+    TRANSITIONS.iter().find(|t| t.name == name)
+}
+
+/// The names of every effect, separated by " | ", for error messages
+#[must_use]
+pub fn names() -> String {
+    let names: Vec<&str> = TRANSITIONS.iter().map(|t| t.name).collect();
+    names.join(" | ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn effect_names_should_be_unique() {
+        for (i, t) in TRANSITIONS.iter().enumerate() {
+            assert!(
+                TRANSITIONS[i + 1..]
+                    .iter()
+                    .all(|other| other.name != t.name),
+                "{} is registered twice",
+                t.name
+            );
+            assert_eq!(find(t.name).map(|found| found.help), Some(t.help));
+        }
+        assert!(find("none").is_none());
+    }
+}